@@ -15,15 +15,19 @@ use simics::api::{
     CpuInstructionQueryInterface, CpuInstrumentationSubscribeInterface, CycleInterface,
     IntRegisterInterface, ProcessorInfoV2Interface,
 };
-use std::{ffi::CStr, mem::size_of, slice::from_raw_parts};
+use std::{ffi::CStr, mem::size_of, slice::from_raw_parts, sync::Arc};
 use yaxpeax_arch::{Decoder, U8Reader};
 use yaxpeax_arm::armv8::a64::{InstDecoder, Instruction, Opcode, Operand, ShiftStyle, SizeCode};
 
-use super::ArchitectureOperations;
+use super::{
+    decode::{DecodeCache, DecodedInstruction},
+    ArchitectureOperations,
+};
 
 pub(crate) struct AArch64ArchitectureOperations {
     cpu: *mut ConfObject,
     disassembler: Disassembler,
+    decode_cache: DecodeCache,
     int_register: IntRegisterInterface,
     processor_info_v2: ProcessorInfoV2Interface,
     cpu_instruction_query: CpuInstructionQueryInterface,
@@ -39,6 +43,20 @@ impl ArchitectureOperations for AArch64ArchitectureOperations {
     const ARGUMENT_REGISTER_1: &'static str = "x8";
 
     const ARGUMENT_REGISTER_2: &'static str = "x7";
+    const PAGE_TABLE_ROOT_REGISTER: &'static str = "ttbr0_el1";
+    /// TTBR1_EL1 maps the kernel half shared by every process, so TTBR0_EL1 identifies the
+    /// process. The address space identifier in the top bits is excluded.
+    const PAGE_TABLE_ROOT_MASK: u64 = 0x0000_ffff_ffff_fffe;
+    const TENET_REGISTERS: &'static [&'static str] = &[
+        "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+        "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26",
+        "x27", "x28", "x29", "x30", "sp",
+    ];
+    const TENET_PC_REGISTER: &'static str = "pc";
+    const CALL_ARGUMENT_REGISTERS: &'static [&'static str] =
+        &["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"];
+    const CALL_RETURN_REGISTER: &'static str = "x0";
+    const CALL_LINK_REGISTER: &'static str = "x30";
 
     fn new(cpu: *mut ConfObject) -> Result<Self> {
         let mut processor_info_v2: ProcessorInfoV2Interface = get_interface(cpu)?;
@@ -56,6 +74,7 @@ impl ArchitectureOperations for AArch64ArchitectureOperations {
             Ok(Self {
                 cpu,
                 disassembler: Disassembler::new(),
+                decode_cache: DecodeCache::default(),
                 int_register: get_interface(cpu)?,
                 processor_info_v2,
                 cpu_instruction_query: get_interface(cpu)?,
@@ -74,6 +93,7 @@ impl ArchitectureOperations for AArch64ArchitectureOperations {
         Ok(Self {
             cpu,
             disassembler: Disassembler::new(),
+            decode_cache: DecodeCache::default(),
             int_register: get_interface(cpu)?,
             processor_info_v2: get_interface(cpu)?,
             cpu_instruction_query: get_interface(cpu)?,
@@ -110,21 +130,29 @@ impl ArchitectureOperations for AArch64ArchitectureOperations {
         &mut self.cycle
     }
 
-    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+    fn decode(
+        &mut self,
+        instruction_query: *mut instruction_handle_t,
+    ) -> Result<Arc<DecodedInstruction>> {
         let instruction_bytes = self
             .cpu_instruction_query
             .get_instruction_bytes(instruction_query)?;
+        let physical_address = self
+            .cpu_instruction_query
+            .physical_address(instruction_query)?;
+        self.decode_cache
+            .decode(&mut self.disassembler, physical_address, unsafe {
+                from_raw_parts(instruction_bytes.data, instruction_bytes.size)
+            })
+    }
 
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
+    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+        let decoded = self.decode(instruction_query)?;
 
-        if self.disassembler.last_was_call()
-            || self.disassembler.last_was_control_flow()
-            || self.disassembler.last_was_ret()
-        {
+        if decoded.control_flow {
             Ok(TraceEntry::builder()
                 .edge(self.processor_info_v2.get_program_counter()?)
+                .branch(decoded.branch)
                 .build())
         } else {
             Ok(TraceEntry::default())
@@ -132,19 +160,14 @@ impl ArchitectureOperations for AArch64ArchitectureOperations {
     }
 
     fn trace_cmp(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
-        let instruction_bytes = self
-            .cpu_instruction_query
-            .get_instruction_bytes(instruction_query)?;
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
+        let decoded = self.decode(instruction_query)?;
 
         let pc = self.processor_info_v2.get_program_counter()?;
 
         let mut cmp_values = Vec::new();
 
-        for expr in self.disassembler.cmp() {
-            if let Ok(value) = self.simplify(&expr) {
+        for expr in &decoded.cmp_exprs {
+            if let Ok(value) = self.simplify(expr) {
                 cmp_values.push(value);
             }
         }
@@ -181,7 +204,7 @@ impl ArchitectureOperations for AArch64ArchitectureOperations {
         Ok(TraceEntry::builder()
             .cmp((
                 pc,
-                self.disassembler.cmp_type(),
+                decoded.cmp_types.clone(),
                 cmp_value.ok_or_else(|| anyhow!("No cmp value available"))?,
             ))
             .build())
@@ -11,7 +11,7 @@ use simics::api::{
     CpuInstructionQueryInterface, CpuInstrumentationSubscribeInterface, CycleInterface,
     IntRegisterInterface, ProcessorInfoV2Interface,
 };
-use std::{ffi::CStr, mem::size_of, slice::from_raw_parts};
+use std::{ffi::CStr, mem::size_of, slice::from_raw_parts, sync::Arc};
 use yaxpeax_arch::{Decoder, U8Reader};
 use yaxpeax_arm::armv7::{InstDecoder, Instruction, Opcode, Operand, RegShiftStyle, ShiftStyle};
 
@@ -20,11 +20,15 @@ use crate::{
     traits::TracerDisassembler,
 };
 
-use super::ArchitectureOperations;
+use super::{
+    decode::{DecodeCache, DecodedInstruction},
+    ArchitectureOperations,
+};
 
 pub(crate) struct ARMArchitectureOperations {
     cpu: *mut ConfObject,
     disassembler: Disassembler,
+    decode_cache: DecodeCache,
     int_register: IntRegisterInterface,
     processor_info_v2: ProcessorInfoV2Interface,
     cpu_instruction_query: CpuInstructionQueryInterface,
@@ -40,6 +44,12 @@ impl ArchitectureOperations for ARMArchitectureOperations {
     const ARGUMENT_REGISTER_1: &'static str = "r8";
 
     const ARGUMENT_REGISTER_2: &'static str = "r7";
+    const PAGE_TABLE_ROOT_REGISTER: &'static str = "ttbr0";
+    /// TTBR1 maps the kernel half shared by every process, so TTBR0 identifies the process
+    const PAGE_TABLE_ROOT_MASK: u64 = 0x0000_ffff_ffff_ff80;
+    const CALL_ARGUMENT_REGISTERS: &'static [&'static str] = &["r0", "r1", "r2", "r3"];
+    const CALL_RETURN_REGISTER: &'static str = "r0";
+    const CALL_LINK_REGISTER: &'static str = "r14";
 
     fn new(cpu: *mut ConfObject) -> Result<Self> {
         let mut processor_info_v2: ProcessorInfoV2Interface = get_interface(cpu)?;
@@ -53,6 +63,7 @@ impl ArchitectureOperations for ARMArchitectureOperations {
             Ok(Self {
                 cpu,
                 disassembler: Disassembler::new(),
+                decode_cache: DecodeCache::default(),
                 int_register: get_interface(cpu)?,
                 processor_info_v2,
                 cpu_instruction_query: get_interface(cpu)?,
@@ -71,6 +82,7 @@ impl ArchitectureOperations for ARMArchitectureOperations {
         Ok(Self {
             cpu,
             disassembler: Disassembler::new(),
+            decode_cache: DecodeCache::default(),
             int_register: get_interface(cpu)?,
             processor_info_v2: get_interface(cpu)?,
             cpu_instruction_query: get_interface(cpu)?,
@@ -107,21 +119,29 @@ impl ArchitectureOperations for ARMArchitectureOperations {
         &mut self.cycle
     }
 
-    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+    fn decode(
+        &mut self,
+        instruction_query: *mut instruction_handle_t,
+    ) -> Result<Arc<DecodedInstruction>> {
         let instruction_bytes = self
             .cpu_instruction_query
             .get_instruction_bytes(instruction_query)?;
+        let physical_address = self
+            .cpu_instruction_query
+            .physical_address(instruction_query)?;
+        self.decode_cache
+            .decode(&mut self.disassembler, physical_address, unsafe {
+                from_raw_parts(instruction_bytes.data, instruction_bytes.size)
+            })
+    }
 
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
+    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+        let decoded = self.decode(instruction_query)?;
 
-        if self.disassembler.last_was_call()
-            || self.disassembler.last_was_control_flow()
-            || self.disassembler.last_was_ret()
-        {
+        if decoded.control_flow {
             Ok(TraceEntry::builder()
                 .edge(self.processor_info_v2.get_program_counter()?)
+                .branch(decoded.branch)
                 .build())
         } else {
             Ok(TraceEntry::default())
@@ -129,19 +149,14 @@ impl ArchitectureOperations for ARMArchitectureOperations {
     }
 
     fn trace_cmp(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
-        let instruction_bytes = self
-            .cpu_instruction_query
-            .get_instruction_bytes(instruction_query)?;
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
+        let decoded = self.decode(instruction_query)?;
 
         let pc = self.processor_info_v2.get_program_counter()?;
 
         let mut cmp_values = Vec::new();
 
-        for expr in self.disassembler.cmp() {
-            if let Ok(value) = self.simplify(&expr) {
+        for expr in &decoded.cmp_exprs {
+            if let Ok(value) = self.simplify(expr) {
                 cmp_values.push(value);
             }
         }
@@ -178,7 +193,7 @@ impl ArchitectureOperations for ARMArchitectureOperations {
         Ok(TraceEntry::builder()
             .cmp((
                 pc,
-                self.disassembler.cmp_type(),
+                decoded.cmp_types.clone(),
                 cmp_value.ok_or_else(|| anyhow!("No cmp value available"))?,
             ))
             .build())
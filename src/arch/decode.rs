@@ -0,0 +1,99 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-processor cache of decoded instructions
+//!
+//! Tracing decodes every executed instruction, often several times per execution, to decide
+//! whether it is a branch or a comparison. The classification of an instruction only depends
+//! on its bytes, so it is cached by the physical address of the instruction along with the
+//! bytes it was decoded from. When the bytes at an address change, for example because the
+//! target modified its own code or a different page was mapped at that address, the entry no
+//! longer matches and the instruction is decoded again.
+
+use crate::{
+    tracer::{BranchKind, CmpExpr, CmpType},
+    traits::TracerDisassembler,
+};
+use anyhow::Result;
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Debug)]
+/// The classification of a decoded instruction used by tracing
+pub(crate) struct DecodedInstruction {
+    /// Whether the instruction is a branch of any kind, including calls and returns
+    pub control_flow: bool,
+    /// The kind of branch the instruction is, if it is a branch
+    pub branch: BranchKind,
+    /// Whether the instruction is a comparison
+    pub cmp: bool,
+    /// The operands of the comparison, to be evaluated each time the instruction executes
+    pub cmp_exprs: Vec<CmpExpr>,
+    /// The types of the comparison
+    pub cmp_types: Vec<CmpType>,
+}
+
+impl DecodedInstruction {
+    /// Read the classification of the last instruction decoded by a disassembler
+    fn from_disassembler(disassembler: &dyn TracerDisassembler) -> Self {
+        Self {
+            control_flow: disassembler.last_was_call()
+                || disassembler.last_was_control_flow()
+                || disassembler.last_was_ret(),
+            branch: disassembler.last_branch_kind(),
+            cmp: disassembler.last_was_cmp(),
+            cmp_exprs: disassembler.cmp(),
+            cmp_types: disassembler.cmp_type(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DecodeCacheEntry {
+    bytes: Vec<u8>,
+    decoded: Arc<DecodedInstruction>,
+}
+
+#[derive(Debug, Default)]
+/// Decoded instructions of one processor by physical address
+pub(crate) struct DecodeCache {
+    entries: HashMap<u64, DecodeCacheEntry>,
+}
+
+impl DecodeCache {
+    /// The number of entries after which the cache is cleared, bounding its memory use on
+    /// targets which execute a very large amount of distinct code
+    const MAX_ENTRIES: usize = 1 << 20;
+
+    /// Return the classification of the instruction with `bytes` at `physical_address`,
+    /// decoding it with `disassembler` if it is not cached or its bytes have changed
+    pub fn decode(
+        &mut self,
+        disassembler: &mut dyn TracerDisassembler,
+        physical_address: u64,
+        bytes: &[u8],
+    ) -> Result<Arc<DecodedInstruction>> {
+        if let Some(entry) = self.entries.get(&physical_address) {
+            if entry.bytes == bytes {
+                return Ok(entry.decoded.clone());
+            }
+        }
+
+        disassembler.disassemble(bytes)?;
+
+        let decoded = Arc::new(DecodedInstruction::from_disassembler(disassembler));
+
+        if self.entries.len() >= Self::MAX_ENTRIES {
+            self.entries.clear();
+        }
+
+        self.entries.insert(
+            physical_address,
+            DecodeCacheEntry {
+                bytes: bytes.to_vec(),
+                decoded: decoded.clone(),
+            },
+        );
+
+        Ok(decoded)
+    }
+}
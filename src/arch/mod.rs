@@ -5,8 +5,8 @@
 
 use self::{
     aarch64::AArch64ArchitectureOperations, arm::ARMArchitectureOperations,
-    risc_v::RISCVArchitectureOperations, x86::X86ArchitectureOperations,
-    x86_64::X86_64ArchitectureOperations,
+    decode::DecodedInstruction, risc_v::RISCVArchitectureOperations,
+    x86::X86ArchitectureOperations, x86_64::X86_64ArchitectureOperations,
 };
 use crate::{
     tracer::TraceEntry, traits::TracerDisassembler, ManualStartAddress, ManualStartInfo, StartInfo,
@@ -17,20 +17,33 @@ use anyhow::{bail, ensure, Error, Result};
 use raw_cstr::AsRawCstr;
 use simics::{
     api::{
-        read_phys_memory, sys::instruction_handle_t, write_byte, Access, AttrValueType, ConfObject,
-        CpuInstructionQueryInterface, CpuInstrumentationSubscribeInterface, CycleInterface,
-        IntRegisterInterface, ProcessorInfoV2Interface,
+        read_phys_memory,
+        sys::{instruction_handle_t, processor_mode_t},
+        write_byte, Access, AttrValue, AttrValueType, ConfObject, CpuInstructionQueryInterface,
+        CpuInstrumentationSubscribeInterface, CycleInterface, IntRegisterInterface,
+        ProcessorInfoV2Interface,
     },
     read_byte,
 };
-use std::{fmt::Debug, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    mem::size_of,
+    str::FromStr,
+    sync::Arc,
+};
 
 pub mod aarch64;
 pub mod arm;
+pub mod decode;
 pub mod risc_v;
 pub mod x86;
 pub mod x86_64;
 
+/// The smallest page size of the supported architectures. Reads of logical memory never
+/// cross a boundary of a page of this size.
+const PAGE_SIZE: u64 = 0x1000;
+
 #[derive(Debug, Clone)]
 /// An architecture hint that can be parsed from a string
 pub(crate) enum ArchitectureHint {
@@ -97,6 +110,78 @@ impl ArchitectureHint {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// The calling convention used to find the arguments of calls to functions in the target
+pub(crate) enum CallingConvention {
+    #[default]
+    /// The standard convention of the architecture: System V on x86-64, cdecl on i386, and
+    /// the procedure call standard on RISC-V, ARM, and AArch64
+    Standard,
+    /// The Microsoft x64 convention used by UEFI and Windows on x86-64. On i386, where
+    /// Microsoft compilers also pass arguments on the stack, it is the same as the standard
+    /// convention. Other architectures do not support it.
+    Microsoft,
+}
+
+impl CallingConvention {
+    const AS_STRING: &'static [(&'static str, Self)] =
+        &[("standard", Self::Standard), ("microsoft", Self::Microsoft)];
+}
+
+impl FromStr for CallingConvention {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let as_string = Self::AS_STRING.iter().cloned().collect::<HashMap<_, _>>();
+
+        as_string.get(s).cloned().ok_or_else(|| {
+            anyhow!(
+                "Invalid calling convention {}. Expected one of {}",
+                s,
+                Self::AS_STRING
+                    .iter()
+                    .map(|i| i.0)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+}
+
+impl Display for CallingConvention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let to_string = Self::AS_STRING
+            .iter()
+            .map(|(k, v)| (v, k))
+            .collect::<HashMap<_, _>>();
+        if let Some(name) = to_string.get(self) {
+            write!(f, "{}", name)
+        } else {
+            panic!("Invalid state for enum");
+        }
+    }
+}
+
+impl TryFrom<AttrValue> for CallingConvention {
+    type Error = Error;
+
+    fn try_from(value: AttrValue) -> Result<Self> {
+        String::try_from(value)?.parse()
+    }
+}
+
+impl From<CallingConvention> for AttrValueType {
+    fn from(value: CallingConvention) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl From<CallingConvention> for AttrValue {
+    fn from(value: CallingConvention) -> Self {
+        value.to_string().into()
+    }
+}
+
 pub(crate) enum Architecture {
     /// The x86_64 architecture
     X86_64(X86_64ArchitectureOperations),
@@ -132,6 +217,26 @@ pub trait ArchitectureOperations {
     const ARGUMENT_REGISTER_1: &'static str;
     const ARGUMENT_REGISTER_2: &'static str;
     const POINTER_WIDTH_OVERRIDE: Option<i32> = None;
+    /// The register holding the root of the page tables of the current address space
+    const PAGE_TABLE_ROOT_REGISTER: &'static str;
+    /// The bits of the page table root register which identify the page tables, excluding
+    /// flags and address space identifiers
+    const PAGE_TABLE_ROOT_MASK: u64 = u64::MAX;
+    /// The general purpose registers recorded in Tenet execution traces, which are named the
+    /// same by SIMICS and Tenet. Empty if Tenet does not support the architecture.
+    const TENET_REGISTERS: &'static [&'static str] = &[];
+    /// The name Tenet uses for the program counter
+    const TENET_PC_REGISTER: &'static str = "";
+    /// The registers holding the first integer arguments of a call on entry to the callee,
+    /// in the standard calling convention
+    const CALL_ARGUMENT_REGISTERS: &'static [&'static str] = &[];
+    /// The register holding the integer return value of a call on exit from the callee
+    const CALL_RETURN_REGISTER: &'static str;
+    /// The register holding the return address on entry to the callee, if it is not pushed
+    /// on the stack
+    const CALL_LINK_REGISTER: &'static str = "";
+    /// The calling conventions the arguments of calls can be read in
+    const CALLING_CONVENTIONS: &'static [CallingConvention] = &[CallingConvention::Standard];
 
     /// Create a new instance of the architecture operations
     fn new(cpu: *mut ConfObject) -> Result<Self>
@@ -176,6 +281,114 @@ pub trait ArchitectureOperations {
             .and_then(|n| self.int_register().read(n))?)
     }
 
+    /// Return the root of the page tables of the current address space, which identifies the
+    /// process the processor is currently executing on behalf of
+    fn get_page_table_root(&mut self) -> Result<u64> {
+        Ok(self
+            .int_register()
+            .get_number(Self::PAGE_TABLE_ROOT_REGISTER.as_raw_cstr()?)
+            .and_then(|n| self.int_register().read(n))?
+            & Self::PAGE_TABLE_ROOT_MASK)
+    }
+
+    /// Return the names and values of the registers recorded in Tenet execution traces,
+    /// followed by the program counter
+    fn get_tenet_registers(&mut self) -> Result<Vec<(&'static str, u64)>> {
+        ensure!(
+            !Self::TENET_REGISTERS.is_empty(),
+            "Tenet execution traces are not supported on this architecture"
+        );
+
+        let mut registers = Vec::with_capacity(Self::TENET_REGISTERS.len() + 1);
+
+        for name in Self::TENET_REGISTERS {
+            let number = self.int_register().get_number(name.as_raw_cstr()?)?;
+            registers.push((*name, self.int_register().read(number)?));
+        }
+
+        registers.push((
+            Self::TENET_PC_REGISTER,
+            self.processor_info_v2().get_program_counter()?,
+        ));
+
+        Ok(registers)
+    }
+
+    /// Whether the arguments of calls can be read in the calling convention `convention`
+    fn supports_calling_convention(&self, convention: CallingConvention) -> bool {
+        Self::CALLING_CONVENTIONS.contains(&convention)
+    }
+
+    /// Return the integer argument number `index` (from 0) of the call the processor has just
+    /// entered
+    fn get_call_argument(&mut self, index: usize, convention: CallingConvention) -> Result<u64> {
+        ensure!(
+            self.supports_calling_convention(convention),
+            "The {convention} calling convention is not supported on this architecture"
+        );
+
+        let name = Self::CALL_ARGUMENT_REGISTERS
+            .get(index)
+            .ok_or_else(|| anyhow!("Argument {index} is not passed in a register"))?;
+        Ok(self
+            .int_register()
+            .get_number(name.as_raw_cstr()?)
+            .and_then(|n| self.int_register().read(n))?)
+    }
+
+    /// Return the integer return value of the call the processor is about to return from
+    fn get_call_return_value(&mut self) -> Result<u64> {
+        Ok(self
+            .int_register()
+            .get_number(Self::CALL_RETURN_REGISTER.as_raw_cstr()?)
+            .and_then(|n| self.int_register().read(n))?)
+    }
+
+    /// Return the address the call the processor has just entered will return to
+    fn get_call_return_address(&mut self) -> Result<u64> {
+        Ok(self
+            .int_register()
+            .get_number(Self::CALL_LINK_REGISTER.as_raw_cstr()?)
+            .and_then(|n| self.int_register().read(n))?)
+    }
+
+    /// Return whether the processor is currently executing in user mode
+    fn in_user_mode(&mut self) -> Result<bool> {
+        Ok(matches!(
+            self.processor_info_v2().get_processor_mode()?,
+            processor_mode_t::Sim_CPU_Mode_User
+        ))
+    }
+
+    /// Read `len` bytes of memory starting at a logical address. Memory is read up to 8 bytes
+    /// at a time, and no read crosses a boundary of a 4KiB page, so each read is translated
+    /// separately in case consecutive pages are not contiguous in physical memory.
+    fn read_logical_bytes(&mut self, logical_address: u64, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(len);
+
+        while bytes.len() < len {
+            let address = logical_address.wrapping_add(bytes.len() as u64);
+            let physical_address_block = self
+                .processor_info_v2()
+                .logical_to_physical(address, Access::Sim_Access_Read)?;
+
+            ensure!(
+                physical_address_block.valid != 0,
+                "Invalid linear address {address:#x}"
+            );
+
+            let chunk_len = (len - bytes.len())
+                .min(size_of::<u64>())
+                .min((PAGE_SIZE - (address & (PAGE_SIZE - 1))) as usize);
+            let chunk =
+                read_phys_memory(self.cpu(), physical_address_block.address, chunk_len as i32)?
+                    .to_le_bytes();
+            bytes.extend_from_slice(&chunk[..chunk_len]);
+        }
+
+        Ok(bytes)
+    }
+
     /// Get the magic start information from the harness which takes the arguments:
     ///
     /// - buffer: The address of the buffer containing the testcase
@@ -516,6 +729,12 @@ pub trait ArchitectureOperations {
         Ok(())
     }
 
+    /// Return the classification of an instruction, decoding it only if it has not already
+    /// been decoded at the same physical address with the same bytes
+    fn decode(
+        &mut self,
+        instruction_query: *mut instruction_handle_t,
+    ) -> Result<Arc<DecodedInstruction>>;
     fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry>;
     fn trace_cmp(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry>;
 }
@@ -525,6 +744,8 @@ impl ArchitectureOperations for Architecture {
     const ARGUMENT_REGISTER_0: &'static str = "";
     const ARGUMENT_REGISTER_1: &'static str = "";
     const ARGUMENT_REGISTER_2: &'static str = "";
+    const PAGE_TABLE_ROOT_REGISTER: &'static str = "";
+    const CALL_RETURN_REGISTER: &'static str = "";
 
     fn new(cpu: *mut ConfObject) -> Result<Self>
     where
@@ -625,6 +846,66 @@ impl ArchitectureOperations for Architecture {
         }
     }
 
+    fn get_page_table_root(&mut self) -> Result<u64> {
+        match self {
+            Architecture::X86_64(x86_64) => x86_64.get_page_table_root(),
+            Architecture::I386(i386) => i386.get_page_table_root(),
+            Architecture::Riscv(riscv) => riscv.get_page_table_root(),
+            Architecture::Arm(arm) => arm.get_page_table_root(),
+            Architecture::Aarch64(aarch64) => aarch64.get_page_table_root(),
+        }
+    }
+
+    fn get_tenet_registers(&mut self) -> Result<Vec<(&'static str, u64)>> {
+        match self {
+            Architecture::X86_64(x86_64) => x86_64.get_tenet_registers(),
+            Architecture::I386(i386) => i386.get_tenet_registers(),
+            Architecture::Riscv(riscv) => riscv.get_tenet_registers(),
+            Architecture::Arm(arm) => arm.get_tenet_registers(),
+            Architecture::Aarch64(aarch64) => aarch64.get_tenet_registers(),
+        }
+    }
+
+    fn supports_calling_convention(&self, convention: CallingConvention) -> bool {
+        match self {
+            Architecture::X86_64(x86_64) => x86_64.supports_calling_convention(convention),
+            Architecture::I386(i386) => i386.supports_calling_convention(convention),
+            Architecture::Riscv(riscv) => riscv.supports_calling_convention(convention),
+            Architecture::Arm(arm) => arm.supports_calling_convention(convention),
+            Architecture::Aarch64(aarch64) => aarch64.supports_calling_convention(convention),
+        }
+    }
+
+    fn get_call_argument(&mut self, index: usize, convention: CallingConvention) -> Result<u64> {
+        match self {
+            Architecture::X86_64(x86_64) => x86_64.get_call_argument(index, convention),
+            Architecture::I386(i386) => i386.get_call_argument(index, convention),
+            Architecture::Riscv(riscv) => riscv.get_call_argument(index, convention),
+            Architecture::Arm(arm) => arm.get_call_argument(index, convention),
+            Architecture::Aarch64(aarch64) => aarch64.get_call_argument(index, convention),
+        }
+    }
+
+    fn get_call_return_value(&mut self) -> Result<u64> {
+        match self {
+            Architecture::X86_64(x86_64) => x86_64.get_call_return_value(),
+            Architecture::I386(i386) => i386.get_call_return_value(),
+            Architecture::Riscv(riscv) => riscv.get_call_return_value(),
+            Architecture::Arm(arm) => arm.get_call_return_value(),
+            Architecture::Aarch64(aarch64) => aarch64.get_call_return_value(),
+        }
+    }
+
+    fn get_call_return_address(&mut self) -> Result<u64> {
+        match self {
+            Architecture::X86_64(x86_64) => x86_64.get_call_return_address(),
+            Architecture::I386(i386) => i386.get_call_return_address(),
+            Architecture::Riscv(riscv) => riscv.get_call_return_address(),
+            Architecture::Arm(arm) => arm.get_call_return_address(),
+            Architecture::Aarch64(aarch64) => aarch64.get_call_return_address(),
+        }
+    }
+
     fn get_magic_start_buffer_ptr_size_ptr(&mut self) -> Result<StartInfo> {
         match self {
             Architecture::X86_64(x86_64) => x86_64.get_magic_start_buffer_ptr_size_ptr(),
@@ -675,6 +956,19 @@ impl ArchitectureOperations for Architecture {
         }
     }
 
+    fn decode(
+        &mut self,
+        instruction_query: *mut instruction_handle_t,
+    ) -> Result<Arc<DecodedInstruction>> {
+        match self {
+            Architecture::X86_64(x86_64) => x86_64.decode(instruction_query),
+            Architecture::I386(i386) => i386.decode(instruction_query),
+            Architecture::Riscv(riscv) => riscv.decode(instruction_query),
+            Architecture::Arm(arm) => arm.decode(instruction_query),
+            Architecture::Aarch64(aarch64) => aarch64.decode(instruction_query),
+        }
+    }
+
     fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
         match self {
             Architecture::X86_64(x86_64) => x86_64.trace_pc(instruction_query),
@@ -3,9 +3,12 @@
 
 //! Architecture-specific implementation for x86 architecture
 
-use std::{ffi::CStr, mem::size_of, slice::from_raw_parts};
+use std::{ffi::CStr, mem::size_of, slice::from_raw_parts, sync::Arc};
 
-use super::ArchitectureOperations;
+use super::{
+    decode::{DecodeCache, DecodedInstruction},
+    ArchitectureOperations, CallingConvention,
+};
 use crate::{
     tracer::{CmpExpr, CmpType, CmpValue, TraceEntry},
     traits::TracerDisassembler,
@@ -27,6 +30,7 @@ use yaxpeax_x86::protected_mode::{ConditionCode, InstDecoder, Instruction, Opcod
 pub(crate) struct X86ArchitectureOperations {
     cpu: *mut ConfObject,
     disassembler: Disassembler,
+    decode_cache: DecodeCache,
     int_register: IntRegisterInterface,
     processor_info_v2: ProcessorInfoV2Interface,
     cpu_instruction_query: CpuInstructionQueryInterface,
@@ -40,6 +44,14 @@ impl ArchitectureOperations for X86ArchitectureOperations {
     const ARGUMENT_REGISTER_1: &'static str = "edx";
     const ARGUMENT_REGISTER_2: &'static str = "ecx";
     const POINTER_WIDTH_OVERRIDE: Option<i32> = Some(4);
+    const PAGE_TABLE_ROOT_REGISTER: &'static str = "cr3";
+    const PAGE_TABLE_ROOT_MASK: u64 = 0xffff_ffe0;
+    const TENET_REGISTERS: &'static [&'static str] =
+        &["eax", "ebx", "ecx", "edx", "ebp", "esp", "esi", "edi"];
+    const TENET_PC_REGISTER: &'static str = "eip";
+    const CALL_RETURN_REGISTER: &'static str = "eax";
+    const CALLING_CONVENTIONS: &'static [CallingConvention] =
+        &[CallingConvention::Standard, CallingConvention::Microsoft];
 
     fn new(cpu: *mut ConfObject) -> Result<Self> {
         let mut processor_info_v2: ProcessorInfoV2Interface = get_interface(cpu)?;
@@ -95,6 +107,7 @@ impl ArchitectureOperations for X86ArchitectureOperations {
                 Ok(Self {
                     cpu,
                     disassembler: Disassembler::new(),
+                    decode_cache: DecodeCache::default(),
                     int_register,
                     processor_info_v2,
                     cpu_instruction_query: get_interface(cpu)?,
@@ -112,6 +125,7 @@ impl ArchitectureOperations for X86ArchitectureOperations {
             Ok(Self {
                 cpu,
                 disassembler: Disassembler::new(),
+                decode_cache: DecodeCache::default(),
                 int_register: get_interface(cpu)?,
                 processor_info_v2,
                 cpu_instruction_query: get_interface(cpu)?,
@@ -130,6 +144,7 @@ impl ArchitectureOperations for X86ArchitectureOperations {
         Ok(Self {
             cpu,
             disassembler: Disassembler::new(),
+            decode_cache: DecodeCache::default(),
             int_register: get_interface(cpu)?,
             processor_info_v2: get_interface(cpu)?,
             cpu_instruction_query: get_interface(cpu)?,
@@ -166,19 +181,38 @@ impl ArchitectureOperations for X86ArchitectureOperations {
         &mut self.cycle
     }
 
-    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+    /// Arguments are pushed on the stack above the return address in every convention
+    fn get_call_argument(&mut self, index: usize, _convention: CallingConvention) -> Result<u64> {
+        self.read_stack_word(index + 1)
+    }
+
+    /// The return address is on the top of the stack on entry to the callee
+    fn get_call_return_address(&mut self) -> Result<u64> {
+        self.read_stack_word(0)
+    }
+
+    fn decode(
+        &mut self,
+        instruction_query: *mut instruction_handle_t,
+    ) -> Result<Arc<DecodedInstruction>> {
         let instruction_bytes = self
             .cpu_instruction_query
             .get_instruction_bytes(instruction_query)?;
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
-        if self.disassembler.last_was_call()
-            || self.disassembler.last_was_control_flow()
-            || self.disassembler.last_was_ret()
-        {
+        let physical_address = self
+            .cpu_instruction_query
+            .physical_address(instruction_query)?;
+        self.decode_cache
+            .decode(&mut self.disassembler, physical_address, unsafe {
+                from_raw_parts(instruction_bytes.data, instruction_bytes.size)
+            })
+    }
+
+    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+        let decoded = self.decode(instruction_query)?;
+        if decoded.control_flow {
             Ok(TraceEntry::builder()
                 .edge(self.processor_info_v2.get_program_counter()?)
+                .branch(decoded.branch)
                 .build())
         } else {
             Ok(TraceEntry::default())
@@ -186,18 +220,13 @@ impl ArchitectureOperations for X86ArchitectureOperations {
     }
 
     fn trace_cmp(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
-        let instruction_bytes = self
-            .cpu_instruction_query
-            .get_instruction_bytes(instruction_query)?;
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
-        if self.disassembler.last_was_cmp() {
+        let decoded = self.decode(instruction_query)?;
+        if decoded.cmp {
             let pc = self.processor_info_v2.get_program_counter()?;
             let mut cmp_values = Vec::new();
 
-            for expr in self.disassembler.cmp() {
-                if let Ok(value) = self.simplify(&expr) {
+            for expr in &decoded.cmp_exprs {
+                if let Ok(value) = self.simplify(expr) {
                     cmp_values.push(value);
                 }
             }
@@ -234,7 +263,7 @@ impl ArchitectureOperations for X86ArchitectureOperations {
             Ok(TraceEntry::builder()
                 .cmp((
                     pc,
-                    self.disassembler.cmp_type(),
+                    decoded.cmp_types.clone(),
                     cmp_value.ok_or_else(|| anyhow!("No cmp value available"))?,
                 ))
                 .build())
@@ -244,6 +273,23 @@ impl ArchitectureOperations for X86ArchitectureOperations {
     }
 }
 impl X86ArchitectureOperations {
+    /// Read the 32-bit word `index` words above the top of the stack
+    fn read_stack_word(&mut self, index: usize) -> Result<u64> {
+        let esp = self
+            .int_register
+            .get_number("esp".as_raw_cstr()?)
+            .and_then(|n| self.int_register.read(n))?;
+        let bytes = self.read_logical_bytes(
+            esp.wrapping_add((index * size_of::<u32>()) as u64),
+            size_of::<u32>(),
+        )?;
+        Ok(u32::from_le_bytes(
+            bytes
+                .try_into()
+                .map_err(|_| anyhow!("Failed to read stack word"))?,
+        ) as u64)
+    }
+
     fn simplify(&mut self, expr: &CmpExpr) -> Result<CmpValue> {
         match expr {
             CmpExpr::Deref((expr, width)) => {
@@ -3,7 +3,7 @@
 
 //! Architecture-specific implementation for x86-64 architecture
 
-use std::{ffi::CStr, mem::size_of, slice::from_raw_parts};
+use std::{ffi::CStr, mem::size_of, slice::from_raw_parts, sync::Arc};
 
 use crate::{
     tracer::{CmpExpr, CmpType, CmpValue, TraceEntry},
@@ -15,20 +15,29 @@ use raw_cstr::AsRawCstr;
 use simics::api::{
     get_interface, read_phys_memory, sys::instruction_handle_t, Access, ConfObject,
     CpuInstructionQueryInterface, CpuInstrumentationSubscribeInterface, CycleInterface,
-    IntRegisterInterface, ProcessorInfoV2Interface,
+    IntRegisterInterface, ProcessorInfoV2Interface, X86RegAccessInterface,
+};
+use yaxpeax_x86::amd64::{
+    register_class, ConditionCode, InstDecoder, Instruction, Opcode, Operand,
 };
-use yaxpeax_x86::amd64::{ConditionCode, InstDecoder, Instruction, Opcode, Operand};
 
-use super::ArchitectureOperations;
+use super::{
+    decode::{DecodeCache, DecodedInstruction},
+    ArchitectureOperations, CallingConvention,
+};
 
 pub(crate) struct X86_64ArchitectureOperations {
     cpu: *mut ConfObject,
     disassembler: Disassembler,
+    decode_cache: DecodeCache,
     int_register: IntRegisterInterface,
     processor_info_v2: ProcessorInfoV2Interface,
     cpu_instruction_query: CpuInstructionQueryInterface,
     cpu_instrumentation_subscribe: CpuInstrumentationSubscribeInterface,
     cycle: CycleInterface,
+    /// Used to read vector and floating point registers, which are not available through
+    /// the integer register interface
+    x86_reg_access: Option<X86RegAccessInterface>,
 }
 
 impl ArchitectureOperations for X86_64ArchitectureOperations {
@@ -36,6 +45,21 @@ impl ArchitectureOperations for X86_64ArchitectureOperations {
     const ARGUMENT_REGISTER_0: &'static str = "rsi";
     const ARGUMENT_REGISTER_1: &'static str = "rdx";
     const ARGUMENT_REGISTER_2: &'static str = "rcx";
+    const PAGE_TABLE_ROOT_REGISTER: &'static str = "cr3";
+    // NOTE: Bit 12 is also excluded, because with page table isolation (KPTI) the kernel
+    // switches between the user and kernel copies of a process's page tables, which are
+    // adjacent pages
+    const PAGE_TABLE_ROOT_MASK: u64 = 0x000f_ffff_ffff_e000;
+    const TENET_REGISTERS: &'static [&'static str] = &[
+        "rax", "rbx", "rcx", "rdx", "rbp", "rsp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ];
+    const TENET_PC_REGISTER: &'static str = "rip";
+    const CALL_ARGUMENT_REGISTERS: &'static [&'static str] =
+        &["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+    const CALL_RETURN_REGISTER: &'static str = "rax";
+    const CALLING_CONVENTIONS: &'static [CallingConvention] =
+        &[CallingConvention::Standard, CallingConvention::Microsoft];
 
     fn new(cpu: *mut ConfObject) -> Result<Self> {
         let mut processor_info_v2: ProcessorInfoV2Interface = get_interface(cpu)?;
@@ -74,11 +98,13 @@ impl ArchitectureOperations for X86_64ArchitectureOperations {
                 Ok(Self {
                     cpu,
                     disassembler: Disassembler::new(),
+                    decode_cache: DecodeCache::default(),
                     int_register,
                     processor_info_v2,
                     cpu_instruction_query: get_interface(cpu)?,
                     cpu_instrumentation_subscribe: get_interface(cpu)?,
                     cycle: get_interface(cpu)?,
+                    x86_reg_access: get_interface(cpu).ok(),
                 })
             } else if reg_names.iter().all(|n| {
                 ![
@@ -103,11 +129,13 @@ impl ArchitectureOperations for X86_64ArchitectureOperations {
         Ok(Self {
             cpu,
             disassembler: Disassembler::new(),
+            decode_cache: DecodeCache::default(),
             int_register: get_interface(cpu)?,
             processor_info_v2: get_interface(cpu)?,
             cpu_instruction_query: get_interface(cpu)?,
             cpu_instrumentation_subscribe: get_interface(cpu)?,
             cycle: get_interface(cpu)?,
+            x86_reg_access: get_interface(cpu).ok(),
         })
     }
 
@@ -139,19 +167,55 @@ impl ArchitectureOperations for X86_64ArchitectureOperations {
         &mut self.cycle
     }
 
-    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+    fn get_call_argument(&mut self, index: usize, convention: CallingConvention) -> Result<u64> {
+        let name = match convention {
+            CallingConvention::Standard => Self::CALL_ARGUMENT_REGISTERS.get(index),
+            CallingConvention::Microsoft => ["rcx", "rdx", "r8", "r9"].get(index),
+        }
+        .ok_or_else(|| anyhow!("Argument {index} is not passed in a register"))?;
+
+        Ok(self
+            .int_register
+            .get_number(name.as_raw_cstr()?)
+            .and_then(|n| self.int_register.read(n))?)
+    }
+
+    /// The return address is on the top of the stack on entry to the callee
+    fn get_call_return_address(&mut self) -> Result<u64> {
+        let rsp = self
+            .int_register
+            .get_number("rsp".as_raw_cstr()?)
+            .and_then(|n| self.int_register.read(n))?;
+        let bytes = self.read_logical_bytes(rsp, size_of::<u64>())?;
+        Ok(u64::from_le_bytes(
+            bytes
+                .try_into()
+                .map_err(|_| anyhow!("Failed to read return address"))?,
+        ))
+    }
+
+    fn decode(
+        &mut self,
+        instruction_query: *mut instruction_handle_t,
+    ) -> Result<Arc<DecodedInstruction>> {
         let instruction_bytes = self
             .cpu_instruction_query
             .get_instruction_bytes(instruction_query)?;
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
-        if self.disassembler.last_was_call()
-            || self.disassembler.last_was_control_flow()
-            || self.disassembler.last_was_ret()
-        {
+        let physical_address = self
+            .cpu_instruction_query
+            .physical_address(instruction_query)?;
+        self.decode_cache
+            .decode(&mut self.disassembler, physical_address, unsafe {
+                from_raw_parts(instruction_bytes.data, instruction_bytes.size)
+            })
+    }
+
+    fn trace_pc(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
+        let decoded = self.decode(instruction_query)?;
+        if decoded.control_flow {
             Ok(TraceEntry::builder()
                 .edge(self.processor_info_v2.get_program_counter()?)
+                .branch(decoded.branch)
                 .build())
         } else {
             Ok(TraceEntry::default())
@@ -159,19 +223,18 @@ impl ArchitectureOperations for X86_64ArchitectureOperations {
     }
 
     fn trace_cmp(&mut self, instruction_query: *mut instruction_handle_t) -> Result<TraceEntry> {
-        let instruction_bytes = self
-            .cpu_instruction_query
-            .get_instruction_bytes(instruction_query)?;
-        self.disassembler.disassemble(unsafe {
-            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
-        })?;
-        if self.disassembler.last_was_cmp() {
+        let decoded = self.decode(instruction_query)?;
+        if decoded.cmp {
             let pc = self.processor_info_v2.get_program_counter()?;
             let mut cmp_values = Vec::new();
+            let fpu = decoded
+                .cmp_exprs
+                .iter()
+                .any(|e| matches!(e, CmpExpr::Fpu(_)));
 
-            for expr in self.disassembler.cmp() {
-                if let Ok(value) = self.simplify(&expr) {
-                    cmp_values.push(value);
+            for expr in &decoded.cmp_exprs {
+                if let Ok(value) = self.simplify(expr) {
+                    cmp_values.push(if fpu { widen_fpu_operand(value) } else { value });
                 }
             }
 
@@ -197,6 +260,9 @@ impl ArchitectureOperations for X86_64ArchitectureOperations {
                         u64::from_le_bytes(l.to_le_bytes()),
                         u64::from_le_bytes(r.to_le_bytes()),
                     ))),
+                    (CmpValue::Bytes(l), CmpValue::Bytes(r)) if l.len() == r.len() => {
+                        Some(CmpValues::Bytes((l.clone(), r.clone())))
+                    }
                     (CmpValue::Expr(_), CmpValue::Expr(_)) => None,
                     _ => None,
                 }
@@ -207,7 +273,7 @@ impl ArchitectureOperations for X86_64ArchitectureOperations {
             Ok(TraceEntry::builder()
                 .cmp((
                     pc,
-                    self.disassembler.cmp_type(),
+                    decoded.cmp_types.clone(),
                     cmp_value.ok_or_else(|| anyhow!("No cmp value available"))?,
                 ))
                 .build())
@@ -265,6 +331,10 @@ impl X86_64ArchitectureOperations {
                                 .map_err(|e| anyhow!("Error reading bytes from {:#x}: {}", a, e))?
                                 .to_le_bytes(),
                             )),
+                            // Vector and extended precision floating point operands
+                            Some(w) if *w > 8 => {
+                                CmpValue::Bytes(self.read_logical_bytes(a, *w as usize)?)
+                            }
                             _ => bail!("Can't cast to non-power-of-2 width {:?}", width),
                         };
                         Ok(casted)
@@ -704,6 +774,52 @@ impl X86_64ArchitectureOperations {
                         .to_le_bytes();
                 Ok(CmpValue::U64(u64::from_le_bytes(bytes)))
             }
+            CmpExpr::Vector((index, width)) => {
+                let x86_reg_access = self
+                    .x86_reg_access
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("Vector registers are not accessible"))?;
+
+                let mut bytes = if *width > 16 {
+                    let ymm = x86_reg_access.get_ymm(*index as u32)?;
+                    [ymm.llo64, ymm.lhi64, ymm.hlo64, ymm.hhi64]
+                        .iter()
+                        .flat_map(|q| q.to_le_bytes())
+                        .collect::<Vec<_>>()
+                } else {
+                    let xmm = x86_reg_access.get_xmm(*index as u32)?;
+                    [xmm.lo64, xmm.hi64]
+                        .iter()
+                        .flat_map(|q| q.to_le_bytes())
+                        .collect::<Vec<_>>()
+                };
+
+                if *width as usize > bytes.len() {
+                    bail!("Can't read {width} bytes of vector register {index}");
+                }
+
+                bytes.truncate(*width as usize);
+
+                // Scalar floating point operands are compared as integers of their width
+                match *width {
+                    4 => Ok(CmpValue::U32(u32::from_le_bytes(bytes[..4].try_into()?))),
+                    8 => Ok(CmpValue::U64(u64::from_le_bytes(bytes[..8].try_into()?))),
+                    _ => Ok(CmpValue::Bytes(bytes)),
+                }
+            }
+            CmpExpr::Fpu(index) => {
+                let x86_reg_access = self
+                    .x86_reg_access
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("Floating point registers are not accessible"))?;
+                // NOTE: Registers are read by physical register number, while operands name
+                // registers relative to the top of the register stack, ST(i) being physical
+                // register (TOP + i) mod 8. TOP is bits 11 to 13 of the status word.
+                let top = (x86_reg_access.get_fpu_env()?.sw >> 11) & 0x7;
+                let freg = x86_reg_access.get_freg(((top as u32) + *index as u32) & 0x7)?;
+
+                Ok(CmpValue::U64(extended_to_f64_bits(freg.low, freg.high)))
+            }
             _ => {
                 // There are other types but they are never emitted on x86_64
                 bail!("Unsupported expression type")
@@ -712,6 +828,63 @@ impl X86_64ArchitectureOperations {
     }
 }
 
+/// Convert an x87 extended precision value to the bits of a double precision value, so it
+/// can be compared with doubles in the testcase. The low bits of the mantissa are truncated,
+/// and values out of the range of a double become zero or infinity.
+fn extended_to_f64_bits(mantissa: u64, sign_exponent: u16) -> u64 {
+    let sign = ((sign_exponent >> 15) as u64) << 63;
+    let exponent = (sign_exponent & 0x7fff) as i64;
+    // Drop the explicit integer bit and keep the 52 most significant fraction bits
+    let fraction = (mantissa << 1) >> 12;
+
+    match exponent {
+        0 => sign,
+        0x7fff => sign | (0x7ff << 52) | fraction,
+        _ => match exponent - 16383 + 1023 {
+            e if e <= 0 => sign,
+            e if e >= 0x7ff => sign | (0x7ff << 52),
+            e => sign | ((e as u64) << 52) | fraction,
+        },
+    }
+}
+
+/// Widen the single precision memory operand of an x87 comparison to double precision, so it
+/// can be compared with the stack register operand, which is read as a double
+fn widen_fpu_operand(value: CmpValue) -> CmpValue {
+    match value {
+        CmpValue::U32(bits) => CmpValue::U64(f64::from(f32::from_bits(bits)).to_bits()),
+        value => value,
+    }
+}
+
+/// The width of the operands of scalar floating point comparisons, which operate on only the
+/// low bytes of their vector register operands
+fn scalar_fp_width(opcode: Opcode) -> Option<u8> {
+    match opcode {
+        Opcode::COMISS | Opcode::UCOMISS | Opcode::CMPSS => Some(4),
+        Opcode::COMISD | Opcode::UCOMISD | Opcode::CMPSD => Some(8),
+        _ => None,
+    }
+}
+
+/// Whether a comparison is of floating point values
+fn is_fp_cmp(opcode: Opcode) -> bool {
+    scalar_fp_width(opcode).is_some()
+        || matches!(
+            opcode,
+            Opcode::CMPPD
+                | Opcode::FCOM
+                | Opcode::FCOMI
+                | Opcode::FCOMIP
+                | Opcode::FCOMP
+                | Opcode::FCOMPP
+                | Opcode::FUCOM
+                | Opcode::FUCOMI
+                | Opcode::FUCOMIP
+                | Opcode::FUCOMP
+        )
+}
+
 pub(crate) struct Disassembler {
     decoder: InstDecoder,
     last: Option<Instruction>,
@@ -748,6 +921,12 @@ impl TryFrom<(&Operand, Option<u8>)> for CmpExpr {
             Operand::ImmediateU32(u) => CmpExpr::U32(*u),
             Operand::ImmediateI64(i) => CmpExpr::I64(*i),
             Operand::ImmediateU64(u) => CmpExpr::U64(*u),
+            Operand::Register(r)
+                if r.class() == register_class::X || r.class() == register_class::Y =>
+            {
+                CmpExpr::Vector((r.num(), width.unwrap_or(r.width())))
+            }
+            Operand::Register(r) if r.class() == register_class::ST => CmpExpr::Fpu(r.num()),
             Operand::Register(r) => CmpExpr::Reg((r.name().to_string(), r.width())),
             Operand::DisplacementU32(d) => CmpExpr::Addr(*d as u64),
             Operand::DisplacementU64(d) => CmpExpr::Addr(*d),
@@ -978,7 +1157,9 @@ impl TracerDisassembler for Disassembler {
             if let Some(last) = self.last {
                 for op_idx in 0..last.operand_count() {
                     let op = last.operand(op_idx);
-                    let width = if let Some(width) = op.width() {
+                    let width = if let Some(width) = scalar_fp_width(last.opcode()) {
+                        Some(width)
+                    } else if let Some(width) = op.width() {
                         Some(width)
                     } else if let Some(width) = last.mem_size() {
                         width.bytes_size()
@@ -997,6 +1178,28 @@ impl TracerDisassembler for Disassembler {
     fn cmp_type(&self) -> Vec<CmpType> {
         if self.last_was_cmp() {
             if let Some(last) = self.last {
+                if is_fp_cmp(last.opcode()) {
+                    return vec![CmpType::Equal, CmpType::Fp];
+                }
+
+                match last.opcode() {
+                    Opcode::PCMPEQB | Opcode::PCMPEQD | Opcode::PCMPEQW => {
+                        return vec![CmpType::Equal]
+                    }
+                    Opcode::PCMPGTB
+                    | Opcode::PCMPGTD
+                    | Opcode::PCMPGTQ
+                    | Opcode::PCMPGTW
+                    | Opcode::PMAXSB
+                    | Opcode::PMAXSD
+                    | Opcode::PMAXUD
+                    | Opcode::PMAXUW => return vec![CmpType::Greater],
+                    Opcode::PMINSB | Opcode::PMINSD | Opcode::PMINUD | Opcode::PMINUW => {
+                        return vec![CmpType::Lesser]
+                    }
+                    _ => {}
+                }
+
                 if let Some(condition) = last.opcode().condition() {
                     return match condition {
                         // Overflow
@@ -0,0 +1,191 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Solutions and stops triggered by the output of consoles
+//!
+//! Many targets report bugs only by printing them, for example Linux `BUG:` and `KASAN:`
+//! reports, EDK2 `ASSERT` messages, or Windows bugchecks. Consoles are monitored by
+//! registering a break string matching each line of output with the console, and each line
+//! output while a testcase runs is matched against the patterns configured for the console.
+//! A matching line either stops the testcase as a solution or stops it normally.
+
+use crate::{
+    state::{SolutionKind, StopReason},
+    Tsffs,
+};
+use anyhow::{anyhow, Result};
+use ffi::ffi;
+use raw_cstr::AsRawCstr;
+use regex::Regex;
+use simics::{
+    api::{get_interface, object_name, BreakStringsV2Interface, ConfObject},
+    debug, AsConfObject,
+};
+use std::{
+    ffi::{c_char, c_void, CStr},
+    ptr::null_mut,
+};
+
+/// The break string registered with each monitored console, which matches each line of
+/// output
+const LINE_BREAK_STRING: &str = "[^\n]*\n";
+
+/// The maximum length of a line of output kept while waiting for the end of the line. Longer
+/// lines are matched in pieces of this length.
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone)]
+/// A pattern matched against each line of output of a console
+pub(crate) struct ConsolePattern {
+    regex: Regex,
+    /// Whether a matching line is a solution. Otherwise, it stops the testcase normally.
+    solution: bool,
+}
+
+#[derive(Debug)]
+/// A console whose output is matched against patterns while testcases run
+pub(crate) struct ConsoleMonitor {
+    console: *mut ConfObject,
+    patterns: Vec<ConsolePattern>,
+    /// The output of the current line which has not been matched yet. This is cleared every
+    /// execution.
+    line: String,
+}
+
+impl ConsoleMonitor {
+    /// Add output of the console and return the first complete line which matches a
+    /// pattern, along with the pattern
+    fn output(&mut self, output: &str) -> Option<(&ConsolePattern, String)> {
+        self.line.push_str(output);
+
+        while let Some(end) = self
+            .line
+            .find('\n')
+            .or_else(|| (self.line.len() >= MAX_LINE_LENGTH).then_some(self.line.len()))
+        {
+            let rest = self.line.split_off((end + 1).min(self.line.len()));
+            let line = std::mem::replace(&mut self.line, rest)
+                .trim_end_matches(['\r', '\n'])
+                .to_string();
+
+            if let Some(pattern) = self.patterns.iter().find(|p| p.regex.is_match(&line)) {
+                return Some((pattern, line));
+            }
+        }
+
+        None
+    }
+}
+
+impl Tsffs {
+    /// Match each line of output of `console` against `pattern` while testcases run. A
+    /// matching line is a solution if `solution` is set, otherwise it stops the testcase
+    /// normally. The console is monitored the first time a pattern is added for it.
+    pub fn insert_console_pattern(
+        &mut self,
+        console: *mut ConfObject,
+        pattern: &str,
+        solution: bool,
+    ) -> Result<()> {
+        let pattern = ConsolePattern {
+            regex: Regex::new(pattern)
+                .map_err(|e| anyhow!("Invalid console pattern {pattern}: {e}"))?,
+            solution,
+        };
+
+        debug!(
+            self.as_conf_object(),
+            "Adding {} pattern {} for console {}",
+            if solution { "solution" } else { "stop" },
+            pattern.regex,
+            object_name(console)?
+        );
+
+        if let Some(monitor) = self
+            .console_monitors
+            .iter_mut()
+            .find(|m| m.console == console)
+        {
+            monitor.patterns.push(pattern);
+            return Ok(());
+        }
+
+        let mut break_strings: BreakStringsV2Interface = get_interface(console)?;
+        break_strings.add_regexp(
+            LINE_BREAK_STRING.as_raw_cstr()?,
+            Some(tsffs::on_console_output),
+            self as *mut Self as *mut _,
+        )?;
+
+        self.console_monitors.push(ConsoleMonitor {
+            console,
+            patterns: vec![pattern],
+            line: String::new(),
+        });
+
+        Ok(())
+    }
+
+    /// Discard the output of the current line of each monitored console
+    pub fn reset_console_monitors(&mut self) {
+        self.console_monitors
+            .iter_mut()
+            .for_each(|m| m.line.clear());
+    }
+}
+
+#[ffi(from_ptr, expect, self_ty = "*mut c_void")]
+impl Tsffs {
+    #[ffi(arg(rest), arg(self))]
+    /// Callback when a monitored console outputs a line
+    ///
+    /// # Arguments
+    ///
+    /// * `console` - The console which output the line
+    /// * `output` - The output matching the line break string
+    /// * `_break_id` - The ID of the break string
+    pub fn on_console_output(
+        &mut self,
+        console: *mut ConfObject,
+        output: *const c_char,
+        _break_id: i64,
+    ) -> Result<()> {
+        if !self.coverage_enabled {
+            return Ok(());
+        }
+
+        let output = unsafe { CStr::from_ptr(output) }.to_string_lossy();
+
+        let Some((pattern, line)) = self
+            .console_monitors
+            .iter_mut()
+            .find(|m| m.console == console)
+            .and_then(|m| m.output(&output))
+            .map(|(p, line)| (p.clone(), line))
+        else {
+            return Ok(());
+        };
+
+        debug!(
+            self.as_conf_object(),
+            "Console {} output matched pattern {}: {line}",
+            object_name(console)?,
+            pattern.regex
+        );
+
+        if pattern.solution {
+            self.stop_simulation(StopReason::Solution {
+                kind: SolutionKind::Console {
+                    console: object_name(console)?,
+                    pattern: pattern.regex.to_string(),
+                    line,
+                },
+                processor: null_mut(),
+            })?;
+        } else {
+            self.stop_simulation(StopReason::ManualStop)?;
+        }
+
+        Ok(())
+    }
+}
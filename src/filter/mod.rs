@@ -0,0 +1,315 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filtering of traced instructions by address
+//!
+//! Include and exclude ranges restrict which instructions feed the coverage map and the
+//! comparison map, so code the campaign does not target (the OS, interrupt handlers, boot
+//! services) does not add noise to either. Ranges are either virtual, compared against the
+//! program counter, or physical, compared against the program counter translated by the
+//! processor. Ranges can be declared directly or loaded from the executable sections of an
+//! ELF or PE file loaded at a known base address.
+//!
+//! On OS targets, tracing can also be restricted to the address space the harness started
+//! in, identified by the page table root loaded on the start processor at that time, so
+//! other processes scheduled while the testcase runs do not pollute coverage.
+
+use crate::{arch::ArchitectureOperations, Tsffs};
+use anyhow::{anyhow, bail, Result};
+use goblin::{
+    elf::{
+        program_header::{PF_X, PT_LOAD},
+        section_header::SHF_EXECINSTR,
+    },
+    pe::section_table::IMAGE_SCN_MEM_EXECUTE,
+    Object,
+};
+use simics::{debug, info, warn, Access, AsConfObject};
+use std::{fs::read, path::Path};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A range of addresses to include in or exclude from tracing
+pub(crate) struct AddressRange {
+    /// The first address in the range
+    pub start: u64,
+    /// The address after the last address in the range
+    pub end: u64,
+    /// Whether the range is of physical addresses instead of virtual addresses
+    pub physical: bool,
+    /// Whether instructions in the range are excluded instead of included
+    pub exclude: bool,
+    /// The module the range belongs to, if any. This is only used to describe the range.
+    pub module: Option<String>,
+}
+
+impl AddressRange {
+    fn contains(&self, virtual_address: u64, physical_address: Option<u64>) -> bool {
+        let address = if self.physical {
+            physical_address
+        } else {
+            Some(virtual_address)
+        };
+
+        address.is_some_and(|a| a >= self.start && a < self.end)
+    }
+
+    /// Create a range for each executable section of an ELF or PE file loaded at `base`. For
+    /// ELF files, `base` is added to the address of each section, so it should be 0 for
+    /// executables which are not relocated. For PE files, `base` is added to the relative
+    /// virtual address of each section, so it should be the address the image was loaded at.
+    pub fn from_executable<P>(
+        executable: P,
+        base: u64,
+        physical: bool,
+        exclude: bool,
+    ) -> Result<Vec<Self>>
+    where
+        P: AsRef<Path>,
+    {
+        let executable = executable.as_ref();
+        let contents = read(executable)?;
+        let module = executable
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string());
+
+        let sections = match Object::parse(&contents)? {
+            Object::Elf(elf) => {
+                let sections = elf
+                    .section_headers
+                    .iter()
+                    .filter(|s| s.sh_flags & SHF_EXECINSTR as u64 != 0 && s.sh_size > 0)
+                    .map(|s| (s.sh_addr, s.sh_size))
+                    .collect::<Vec<_>>();
+
+                if sections.is_empty() {
+                    // Stripped files may have no section headers, so fall back to the
+                    // executable segments
+                    elf.program_headers
+                        .iter()
+                        .filter(|p| p.p_type == PT_LOAD && p.p_flags & PF_X != 0)
+                        .map(|p| (p.p_vaddr, p.p_memsz))
+                        .collect()
+                } else {
+                    sections
+                }
+            }
+            Object::PE(pe) => pe
+                .sections
+                .iter()
+                .filter(|s| s.characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+                .map(|s| {
+                    (
+                        s.virtual_address as u64,
+                        s.virtual_size.max(s.size_of_raw_data) as u64,
+                    )
+                })
+                .collect(),
+            _ => bail!(
+                "Unsupported executable format for {}, expected ELF or PE",
+                executable.display()
+            ),
+        };
+
+        if sections.is_empty() {
+            bail!("No executable sections found in {}", executable.display());
+        }
+
+        Ok(sections
+            .into_iter()
+            .map(|(address, size)| Self {
+                start: base.wrapping_add(address),
+                end: base.wrapping_add(address).wrapping_add(size),
+                physical,
+                exclude,
+                module: module.clone(),
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// The set of ranges instructions are filtered by
+pub(crate) struct AddressFilter {
+    ranges: Vec<AddressRange>,
+}
+
+impl AddressFilter {
+    pub fn add(&mut self, range: AddressRange) {
+        self.ranges.push(range);
+    }
+
+    /// Whether any range requires the physical address of an instruction
+    fn needs_physical(&self) -> bool {
+        self.ranges.iter().any(|r| r.physical)
+    }
+
+    /// Whether an instruction at an address is traced: it is not in any excluded range, and
+    /// is in an included range if there are any
+    fn allows(&self, virtual_address: u64, physical_address: Option<u64>) -> bool {
+        if self
+            .ranges
+            .iter()
+            .any(|r| r.exclude && r.contains(virtual_address, physical_address))
+        {
+            return false;
+        }
+
+        let mut included = self.ranges.iter().filter(|r| !r.exclude).peekable();
+
+        included.peek().is_none() || included.any(|r| r.contains(virtual_address, physical_address))
+    }
+}
+
+impl Tsffs {
+    /// Add a range to the address filter
+    pub fn add_address_range(&mut self, range: AddressRange) {
+        debug!(self.as_conf_object(), "Adding address range {range:?}");
+        self.address_filter.add(range);
+    }
+
+    /// Record the page table root of the start processor as the address space to trace, if
+    /// `filter_address_space` is enabled, along with whether the harness started in kernel
+    /// mode
+    pub fn record_address_space_root(&mut self) -> Result<()> {
+        if !self.filter_address_space {
+            return Ok(());
+        }
+
+        let start_processor = self
+            .start_processor()
+            .ok_or_else(|| anyhow!("No start processor"))?;
+        let root = start_processor.get_page_table_root()?;
+        let started_in_kernel = !start_processor.in_user_mode()?;
+
+        info!(
+            self.as_conf_object(),
+            "Tracing only address space with page table root {root:#x}"
+        );
+
+        if started_in_kernel && !self.address_space_include_kernel {
+            warn!(
+                self.as_conf_object(),
+                "Harness started in kernel mode, kernel mode execution in the address space will be traced"
+            );
+        }
+
+        self.address_space_root = Some(root);
+        self.address_space_started_in_kernel = started_in_kernel;
+
+        Ok(())
+    }
+
+    /// Whether the processor with number `processor_number` is executing in the recorded
+    /// address space, and in user mode unless `address_space_include_kernel` is enabled or
+    /// the harness started in kernel mode
+    fn in_address_space(&mut self, processor_number: i32) -> Result<bool> {
+        let Some(root) = self.address_space_root else {
+            return Ok(true);
+        };
+
+        let include_kernel =
+            self.address_space_include_kernel || self.address_space_started_in_kernel;
+        let processor = self
+            .processors
+            .get_mut(&processor_number)
+            .ok_or_else(|| anyhow!("No processor {processor_number}"))?;
+
+        Ok(processor.get_page_table_root()? == root
+            && (include_kernel || processor.in_user_mode()?))
+    }
+
+    /// Whether an instruction at `pc` on the processor with number `processor_number` should
+    /// be traced according to the address filter and the recorded address space
+    pub fn address_allowed(&mut self, processor_number: i32, pc: u64) -> Result<bool> {
+        if !self.in_address_space(processor_number)? {
+            return Ok(false);
+        }
+
+        if self.address_filter.ranges.is_empty() {
+            return Ok(true);
+        }
+
+        let physical_address = if self.address_filter.needs_physical() {
+            let block = self
+                .processors
+                .get_mut(&processor_number)
+                .ok_or_else(|| anyhow!("No processor {processor_number}"))?
+                .processor_info_v2()
+                .logical_to_physical(pc, Access::Sim_Access_Execute)?;
+
+            (block.valid != 0).then_some(block.address)
+        } else {
+            None
+        };
+
+        Ok(self.address_filter.allows(pc, physical_address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64, physical: bool, exclude: bool) -> AddressRange {
+        AddressRange {
+            start,
+            end,
+            physical,
+            exclude,
+            module: None,
+        }
+    }
+
+    #[test]
+    fn test_empty_filter_allows_everything() {
+        let filter = AddressFilter::default();
+
+        assert!(filter.allows(0x1000, None));
+        assert!(filter.allows(u64::MAX, Some(0)));
+    }
+
+    #[test]
+    fn test_included_range() {
+        let mut filter = AddressFilter::default();
+        filter.add(range(0x1000, 0x2000, false, false));
+
+        assert!(!filter.allows(0xfff, None));
+        assert!(filter.allows(0x1000, None));
+        assert!(filter.allows(0x1fff, None));
+        assert!(!filter.allows(0x2000, None));
+    }
+
+    #[test]
+    fn test_excluded_range() {
+        let mut filter = AddressFilter::default();
+        filter.add(range(0x1000, 0x2000, false, true));
+
+        assert!(filter.allows(0xfff, None));
+        assert!(!filter.allows(0x1000, None));
+        assert!(filter.allows(0x2000, None));
+    }
+
+    #[test]
+    fn test_exclusion_inside_inclusion() {
+        let mut filter = AddressFilter::default();
+        filter.add(range(0x1000, 0x3000, false, false));
+        filter.add(range(0x1800, 0x2000, false, true));
+
+        assert!(filter.allows(0x1000, None));
+        assert!(!filter.allows(0x1800, None));
+        assert!(filter.allows(0x2000, None));
+        assert!(!filter.allows(0x3000, None));
+    }
+
+    #[test]
+    fn test_physical_range() {
+        let mut filter = AddressFilter::default();
+        filter.add(range(0x1000, 0x2000, true, false));
+
+        assert!(filter.needs_physical());
+        assert!(filter.allows(0x8000, Some(0x1000)));
+        assert!(!filter.allows(0x1000, Some(0x8000)));
+        // Untranslatable addresses are never in a physical range
+        assert!(!filter.allows(0x1000, None));
+    }
+}
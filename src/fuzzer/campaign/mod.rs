@@ -0,0 +1,155 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Persistence of campaign state, allowing a fuzzing campaign to be resumed after the
+//! simulator is restarted.
+//!
+//! A campaign directory holds two pieces of state: the serialized LibAFL state owned by the
+//! fuzzer thread (corpus bookkeeping, scheduler and MOpt metadata, feedback history maps) and
+//! the state owned by the module on the simulator thread (iteration count and edges seen).
+//!
+//! The two pieces are snapshotted at the same point: the fuzzer thread serializes its state
+//! at the top of every iteration of the fuzzing loop and marks the next testcase it sends,
+//! and the simulator snapshots its state when it receives that testcase. Only snapshots
+//! which both sides have taken are written, so the saved files always agree with each other.
+
+use crate::Tsffs;
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use simics::{debug, info, AsConfObject};
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, read_to_string, rename, write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Extension of temporary files written before being atomically moved into place, so an
+/// interrupted save never clobbers the previous state
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// The part of the campaign state owned by the simulator thread
+pub(crate) struct SimulatorState {
+    pub iterations: usize,
+    pub edges_seen: HashSet<u64>,
+}
+
+/// Write `value` as JSON to `path`, going through a temporary file
+pub(crate) fn save_state<T>(path: &Path, value: &T) -> Result<()>
+where
+    T: Serialize,
+{
+    save_state_bytes(path, &serde_json::to_vec(value)?)
+}
+
+/// Write already serialized state to `path`, going through a temporary file
+pub(crate) fn save_state_bytes(path: &Path, bytes: &[u8]) -> Result<()> {
+    let temporary_path = path.with_extension(TEMPORARY_EXTENSION);
+    write(&temporary_path, bytes)?;
+    rename(&temporary_path, path)?;
+    Ok(())
+}
+
+/// Read JSON from `path`, or `None` if nothing has been saved there yet
+pub(crate) fn load_state<T>(path: &Path) -> Result<Option<T>>
+where
+    T: DeserializeOwned,
+{
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    serde_json::from_str(&read_to_string(path)?)
+        .map(Some)
+        .map_err(|e| anyhow!("Failed to load campaign state from {}: {e}", path.display()))
+}
+
+impl Tsffs {
+    /// The name of the file in the campaign directory holding the simulator thread's state
+    const SIMULATOR_STATE_FILE_NAME: &'static str = "simulator-state.json";
+
+    /// The path in the campaign directory of the file holding the fuzzer thread's state.
+    /// Each client in parallel mode keeps its own state.
+    pub(crate) fn campaign_fuzzer_state_path(&self) -> PathBuf {
+        self.campaign_directory
+            .join(format!("fuzzer-state-{}.json", self.client_id))
+    }
+
+    fn campaign_simulator_state_path(&self) -> PathBuf {
+        self.campaign_directory
+            .join(Self::SIMULATOR_STATE_FILE_NAME)
+    }
+
+    /// Create the campaign directory and restore the simulator thread's state from it, if
+    /// a previous run saved any
+    pub fn load_campaign_simulator_state(&mut self) -> Result<()> {
+        create_dir_all(&self.campaign_directory)?;
+
+        let path = self.campaign_simulator_state_path();
+
+        if let Some(state) = load_state::<SimulatorState>(&path)? {
+            info!(
+                self.as_conf_object(),
+                "Resuming campaign from {} after {} iterations ({} edges seen)",
+                self.campaign_directory.display(),
+                state.iterations,
+                state.edges_seen.len()
+            );
+
+            self.iterations = state.iterations;
+            self.edges_seen = state.edges_seen;
+        }
+
+        self.snapshot_campaign_simulator_state();
+        self.campaign_last_saved = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Snapshot the simulator thread's state. Called when the fuzzer thread marks a testcase
+    /// as the first one after it snapshotted its own state.
+    pub(crate) fn snapshot_campaign_simulator_state(&mut self) {
+        self.campaign_snapshot = Some(SimulatorState {
+            iterations: self.iterations,
+            edges_seen: self.edges_seen.clone(),
+        });
+    }
+
+    /// Save the last snapshot of the simulator thread's state to the campaign directory
+    pub fn save_campaign_simulator_state(&mut self) -> Result<()> {
+        let Some(snapshot) = self.campaign_snapshot.as_ref() else {
+            return Ok(());
+        };
+
+        let path = self.campaign_simulator_state_path();
+
+        debug!(
+            self.as_conf_object(),
+            "Saving campaign simulator state to {}",
+            path.display()
+        );
+
+        save_state(&path, snapshot)?;
+
+        self.campaign_last_saved = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Save the simulator thread's state if campaign persistence is enabled and the save
+    /// interval has elapsed since the last save
+    pub fn save_campaign_simulator_state_if_needed(&mut self) -> Result<()> {
+        if !self.persist_campaign {
+            return Ok(());
+        }
+
+        if self.campaign_last_saved.is_some_and(|last_saved| {
+            last_saved.elapsed() >= Duration::from_secs(self.campaign_save_interval)
+        }) {
+            self.save_campaign_simulator_state()?;
+        }
+
+        Ok(())
+    }
+}
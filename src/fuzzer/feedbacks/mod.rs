@@ -6,17 +6,21 @@ use libafl::{
     feedbacks::{Feedback, HasObserverName, IsNovel, MapFeedback, MapFeedbackMetadata, Reducer},
     inputs::HasTargetBytes,
     observers::UsesObserver,
-    prelude::{ExitKind, MapObserver, Observer, ObserversTuple, UsesInput},
-    state::{HasCorpus, HasNamedMetadata, State},
+    prelude::{ExitKind, MapObserver, Observer, ObserversTuple, Testcase, UsesInput},
+    state::{HasCorpus, HasMetadata, HasNamedMetadata, State},
 };
 use libafl_bolts::{AsIter, AsSlice, Named};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     fmt::Debug,
-    sync::{mpsc::Sender, OnceLock},
+    sync::{
+        mpsc::{Receiver, Sender},
+        OnceLock,
+    },
 };
 
 use super::messages::FuzzerMessage;
+use crate::state::SolutionMetadata;
 
 #[derive(Clone, Debug)]
 pub(crate) struct ReportingMapFeedback<N, O, R, S, T> {
@@ -175,3 +179,85 @@ where
         Self { base, sender }
     }
 }
+
+#[derive(Debug)]
+/// A feedback which attaches the details of each solution reported by the simulator to the
+/// solution's testcase, and which is not interesting for solutions which duplicate a smaller
+/// solution in their bucket. Used as part of the objective, so the details are saved in the
+/// solution's metadata file and duplicate solutions are not saved.
+pub(crate) struct SolutionMetadataFeedback {
+    /// A receiver for solution details, which are sent before the exit kind of the execution
+    /// which produced them
+    receiver: Receiver<SolutionMetadata>,
+    /// The details of the solution being evaluated
+    metadata: Option<SolutionMetadata>,
+}
+
+impl<S> Feedback<S> for SolutionMetadataFeedback
+where
+    S: State,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &<S>::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, libafl::Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        // Details of solutions which were not evaluated (for example, from cmplog executions)
+        // may be left in the channel, so only the most recent details belong to this
+        // execution
+        self.metadata = self.receiver.try_iter().last();
+
+        Ok(self.metadata.as_ref().map_or(true, |m| !m.duplicate))
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _observers: &OT,
+        testcase: &mut Testcase<<S>::Input>,
+    ) -> Result<(), libafl::Error>
+    where
+        OT: ObserversTuple<S>,
+        EM: EventFirer<State = S>,
+    {
+        if let Some(metadata) = self.metadata.take() {
+            testcase.add_metadata(metadata);
+        }
+
+        Ok(())
+    }
+
+    fn discard_metadata(
+        &mut self,
+        _state: &mut S,
+        _input: &<S>::Input,
+    ) -> Result<(), libafl::Error> {
+        self.metadata = None;
+        Ok(())
+    }
+}
+
+impl Named for SolutionMetadataFeedback {
+    #[inline]
+    fn name(&self) -> &str {
+        "solution_metadata"
+    }
+}
+
+impl SolutionMetadataFeedback {
+    #[must_use]
+    pub fn new(receiver: Receiver<SolutionMetadata>) -> Self {
+        Self {
+            receiver,
+            metadata: None,
+        }
+    }
+}
@@ -7,4 +7,5 @@ use serde::Serialize;
 pub(crate) enum FuzzerMessage {
     String(String),
     Interesting { indices: Vec<usize>, input: Vec<u8> },
+    Stats { corpus_count: usize },
 }
@@ -5,21 +5,28 @@
 
 use crate::{
     fuzzer::{
-        executors::inprocess::InProcessExecutor, feedbacks::ReportingMapFeedback,
+        campaign::{load_state, save_state_bytes},
+        executors::inprocess::InProcessExecutor,
+        feedbacks::{ReportingMapFeedback, SolutionMetadataFeedback},
         messages::FuzzerMessage,
     },
+    log::LogMessage,
+    state::SolutionMetadata,
+    tracer::CoverageMode,
     Tsffs,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Error, Result};
 use libafl::{
-    feedback_or, feedback_or_fast,
+    events::{EventConfig, EventRestarter, LlmpEventBroker, LlmpEventManager},
+    feedback_and_fast, feedback_not, feedback_or, feedback_or_fast,
     inputs::{HasBytesVec, Input},
     prelude::{
         havoc_mutations, ondisk::OnDiskMetadataFormat, tokens_mutations, AFLppRedQueen, BytesInput,
         CachedOnDiskCorpus, Corpus, CrashFeedback, ExitKind, HasCurrentCorpusIdx, HasTargetBytes,
-        HitcountsMapObserver, I2SRandReplace, MaxMapFeedback, OnDiskCorpus, RandBytesGenerator,
-        SimpleEventManager, SimpleMonitor, StdCmpValuesObserver, StdMOptMutator, StdMapObserver,
-        StdScheduledMutator, TimeFeedback, TimeObserver, Tokens,
+        HitcountsMapObserver, I2SRandReplace, MaxMapFeedback, MultiMonitor, OnDiskCorpus,
+        RandBytesGenerator, SimpleEventManager, SimpleMonitor, StdCmpValuesObserver,
+        StdMOptMutator, StdMapObserver, StdScheduledMutator, TimeFeedback, TimeObserver,
+        TimeoutFeedback, Tokens,
     },
     schedulers::{
         powersched::PowerSchedule, IndexesLenTimeMinimizerScheduler, StdWeightedScheduler,
@@ -29,21 +36,38 @@ use libafl::{
         GeneralizationStage, IfStage, StdMutationalStage, StdPowerMutationalStage,
         SyncFromDiskStage, TracingStage,
     },
-    state::{HasCorpus, HasMetadata, StdState},
+    state::{HasCorpus, HasMetadata, HasSolutions, StdState},
     Fuzzer, StdFuzzer,
 };
 use libafl_bolts::{
     current_nanos,
     prelude::{OwnedMutSlice, OwnedRefMut},
     rands::StdRand,
+    shmem::{ShMemProvider, StdShMemProvider},
     tuples::{tuple_list, Merge},
     AsMutSlice, AsSlice,
 };
 use libafl_targets::{AFLppCmpLogObserver, AFLppCmplogTracingStage};
-use simics::{api::AsConfObject, debug, trace, warn};
+use simics::{
+    api::{AsConfObject, AttrValue, AttrValueType},
+    debug, info, trace, warn,
+};
 use std::{
-    cell::RefCell, fmt::Debug, fs::write, io::stderr, slice::from_raw_parts_mut,
-    sync::mpsc::channel, thread::spawn,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::{Debug, Display},
+    fs::write,
+    io::stderr,
+    num::NonZeroUsize,
+    slice::from_raw_parts_mut,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc,
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
 };
 use tokenize::{tokenize_executable_file, tokenize_src_file};
 use tracing::{level_filters::LevelFilter, Level};
@@ -51,6 +75,7 @@ use tracing_subscriber::{
     filter::filter_fn, fmt, layer::SubscriberExt, registry, util::SubscriberInitExt, Layer,
 };
 
+pub mod campaign;
 pub mod executors;
 pub mod feedbacks;
 pub mod messages;
@@ -60,6 +85,9 @@ pub mod tokenize;
 pub(crate) struct Testcase {
     pub testcase: BytesInput,
     pub cmplog: bool,
+    /// Whether this is the first testcase sent since the fuzzer thread snapshotted its
+    /// campaign state, in which case the simulator snapshots its own campaign state too
+    pub snapshot: bool,
 }
 
 impl Debug for Testcase {
@@ -83,12 +111,105 @@ impl Debug for Testcase {
                 ),
             )
             .field("cmplog", &self.cmplog)
+            .field("snapshot", &self.snapshot)
             .finish()
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
-pub(crate) struct ShutdownMessage {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd, Hash, Default)]
+/// The power schedule used by the scheduler to assign energy to corpus entries
+pub(crate) enum PowerScheduleKind {
+    #[default]
+    Explore,
+    Exploit,
+    Fast,
+    Coe,
+    Lin,
+    Quad,
+}
+
+impl PowerScheduleKind {
+    const AS_STRING: &'static [(&'static str, Self)] = &[
+        ("explore", Self::Explore),
+        ("exploit", Self::Exploit),
+        ("fast", Self::Fast),
+        ("coe", Self::Coe),
+        ("lin", Self::Lin),
+        ("quad", Self::Quad),
+    ];
+}
+
+impl FromStr for PowerScheduleKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let as_string = Self::AS_STRING.iter().cloned().collect::<HashMap<_, _>>();
+
+        as_string.get(s).cloned().ok_or_else(|| {
+            anyhow!(
+                "Invalid power schedule {}. Expected one of {}",
+                s,
+                Self::AS_STRING
+                    .iter()
+                    .map(|i| i.0)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+}
+
+impl Display for PowerScheduleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let to_string = Self::AS_STRING
+            .iter()
+            .map(|(k, v)| (v, k))
+            .collect::<HashMap<_, _>>();
+        if let Some(name) = to_string.get(self) {
+            write!(f, "{}", name)
+        } else {
+            panic!("Invalid state for enum");
+        }
+    }
+}
+
+impl TryFrom<AttrValue> for PowerScheduleKind {
+    type Error = Error;
+
+    fn try_from(value: AttrValue) -> Result<Self> {
+        String::try_from(value)?.parse()
+    }
+}
+
+impl From<PowerScheduleKind> for AttrValueType {
+    fn from(value: PowerScheduleKind) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl From<PowerScheduleKind> for AttrValue {
+    fn from(value: PowerScheduleKind) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl From<PowerScheduleKind> for PowerSchedule {
+    fn from(value: PowerScheduleKind) -> Self {
+        match value {
+            PowerScheduleKind::Explore => PowerSchedule::EXPLORE,
+            PowerScheduleKind::Exploit => PowerSchedule::EXPLOIT,
+            PowerScheduleKind::Fast => PowerSchedule::FAST,
+            PowerScheduleKind::Coe => PowerSchedule::COE,
+            PowerScheduleKind::Lin => PowerSchedule::LIN,
+            PowerScheduleKind::Quad => PowerSchedule::QUAD,
+        }
+    }
+}
+
+/// The concrete type of the fuzzer state, which is named so it can be deserialized when
+/// resuming a campaign
+type FuzzerState =
+    StdState<BytesInput, CachedOnDiskCorpus<BytesInput>, StdRand, OnDiskCorpus<BytesInput>>;
 
 impl Tsffs {
     const EDGES_OBSERVER_NAME: &'static str = "coverage";
@@ -97,6 +218,103 @@ impl Tsffs {
     const TIME_OBSERVER_NAME: &'static str = "time";
     const TIMEOUT_FEEDBACK_NAME: &'static str = "time";
     const CORPUS_CACHE_SIZE: usize = 4096;
+    const EVENT_CONFIG_NAME: &'static str = "tsffs";
+    /// The time to wait at shutdown for clients on other instances to disconnect from the
+    /// broker
+    const BROKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Start the LLMP broker thread used in parallel mode. The broker listens on the
+    /// configured broker port, forwards events between all connected instances (including the
+    /// fuzzer thread in this process), and reports aggregated statistics as fuzzer messages.
+    /// The broker exits once every client which connected to it has disconnected.
+    fn start_broker_thread(&mut self, mtx: Sender<FuzzerMessage>) -> Result<()> {
+        if self.broker_thread.get().is_some() {
+            return Ok(());
+        }
+
+        debug!(
+            self.as_conf_object(),
+            "Starting broker thread on port {}", self.broker_port
+        );
+
+        let broker_port = self.broker_port;
+        let (btx, brx) = channel::<Result<()>>();
+
+        self.broker_thread
+            .set(spawn(move || -> Result<()> {
+                // NOTE: The receiver is dropped when the simulator shuts down, after which
+                // monitor messages are discarded
+                let monitor = MultiMonitor::new(move |s| {
+                    mtx.send(FuzzerMessage::String(s.to_string())).ok();
+                });
+
+                let broker = StdShMemProvider::new()
+                    .and_then(|shmem_provider| {
+                        LlmpEventBroker::<BytesInput, _, _>::on_port(
+                            shmem_provider,
+                            monitor,
+                            broker_port,
+                        )
+                    })
+                    .map_err(|e| anyhow!("Couldn't start broker on port {broker_port}: {e}"));
+
+                let mut broker = match broker {
+                    Ok(mut broker) => {
+                        broker.set_exit_cleanly_after(NonZeroUsize::MIN);
+                        btx.send(Ok(()))?;
+                        broker
+                    }
+                    Err(e) => {
+                        eprintln!("{e}");
+                        btx.send(Err(anyhow!("{e}")))?;
+                        return Err(e);
+                    }
+                };
+
+                broker.broker_loop().map_err(|e| {
+                    eprintln!("Broker loop exited: {e}");
+                    anyhow!("Broker loop exited: {e}")
+                })
+            }))
+            .map_err(|_| anyhow!("Broker thread already set"))?;
+
+        // Wait for the broker to bind its port before any client tries to attach to it
+        brx.recv()
+            .map_err(|e| anyhow!("Error receiving broker status: {e}"))??;
+
+        Ok(())
+    }
+
+    /// Wait for the broker thread to exit, which it does once every client has disconnected.
+    /// Clients on other instances may stay connected, in which case the broker thread is
+    /// left running after waiting for [`Self::BROKER_SHUTDOWN_TIMEOUT`].
+    fn stop_broker_thread(&mut self) -> Result<()> {
+        let Some(broker_thread) = self.broker_thread.take() else {
+            return Ok(());
+        };
+
+        let waiting_since = Instant::now();
+
+        while !broker_thread.is_finished()
+            && waiting_since.elapsed() < Self::BROKER_SHUTDOWN_TIMEOUT
+        {
+            sleep(Duration::from_millis(100));
+        }
+
+        if broker_thread.is_finished() {
+            broker_thread
+                .join()
+                .map_err(|_| anyhow!("Broker thread panicked"))??;
+        } else {
+            warn!(
+                self.as_conf_object(),
+                "Clients are still connected to the broker on port {}, not waiting for the broker to exit",
+                self.broker_port
+            );
+        }
+
+        Ok(())
+    }
 
     /// Start the fuzzing thread.
     pub fn start_fuzzer_thread(&mut self) -> Result<()> {
@@ -106,26 +324,84 @@ impl Tsffs {
             return Ok(());
         }
 
+        if self.coverage_mode == CoverageMode::NGram && self.coverage_ngram_size < 2 {
+            bail!("N-gram coverage size must be at least 2");
+        }
+
+        let coverage_mode = if self.coverage_mode == CoverageMode::NGram {
+            format!("{} (n = {})", self.coverage_mode, self.coverage_ngram_size)
+        } else {
+            self.coverage_mode.to_string()
+        };
+
+        info!(self.as_conf_object(), "Using coverage mode {coverage_mode}");
+        self.log(LogMessage::Message(format!(
+            "Coverage mode: {coverage_mode}"
+        )))?;
+
+        if self.corpus_minimization {
+            // Corpus entries are run directly when minimizing the corpus, without the fuzzer
+            return self.start_corpus_minimization();
+        }
+
         debug!(self.as_conf_object_mut(), "Starting fuzzer thread");
 
+        let power_schedule = PowerSchedule::from(self.power_schedule);
+
+        if self.mopt_max_stack_pow == 0 {
+            bail!("MOpt maximum stack power must be at least 1");
+        }
+
+        if self.mopt_swarm_num == 0 {
+            bail!("MOpt swarm count must be at least 1");
+        }
+
+        debug!(
+            self.as_conf_object(),
+            "Using power schedule {} with stages: generalization={} mopt={} (max stack pow {}, {} swarms) redqueen={} input-to-state={}",
+            self.power_schedule,
+            self.use_generalization_stage,
+            self.use_mopt_stage,
+            self.mopt_max_stack_pow,
+            self.mopt_swarm_num,
+            self.use_redqueen_stage,
+            self.use_input_to_state_stage
+        );
+
         let (tx, orx) = channel::<ExitKind>();
         let (otx, rx) = channel::<Testcase>();
-        let (stx, srx) = channel::<ShutdownMessage>();
+        let shutdown = Arc::new(AtomicBool::new(false));
         let (mtx, mrx) = channel::<FuzzerMessage>();
+        let (smtx, smrx) = channel::<SolutionMetadata>();
 
         self.fuzzer_tx
             .set(tx)
             .map_err(|_| anyhow!("Fuzzer sender already set"))?;
+        self.fuzzer_solution_metadata
+            .set(smtx)
+            .map_err(|_| anyhow!("Fuzzer solution metadata sender already set"))?;
         self.fuzzer_rx
             .set(rx)
             .map_err(|_| anyhow!("Fuzzer receiver already set"))?;
         self.fuzzer_shutdown
-            .set(stx)
-            .map_err(|_| anyhow!("Fuzzer shutdown sender already set"))?;
+            .set(shutdown.clone())
+            .map_err(|_| anyhow!("Fuzzer shutdown flag already set"))?;
         self.fuzzer_messages
             .set(mrx)
             .map_err(|_| anyhow!("Fuzzer messages receiver already set"))?;
 
+        if self.parallel && self.is_broker {
+            self.start_broker_thread(mtx.clone())?;
+        }
+
+        if self.persist_campaign {
+            self.load_campaign_simulator_state()?;
+        }
+
+        if self.metrics_server {
+            self.start_metrics_server()?;
+        }
+
         let client = RefCell::new((otx, orx));
 
         let coverage_map = unsafe {
@@ -157,7 +433,12 @@ impl Tsffs {
             )
         });
 
-        let cmplog_enabled = self.cmplog;
+        let generalization_enabled = self.use_generalization_stage;
+        let mopt_enabled = self.use_mopt_stage;
+        let redqueen_enabled = self.cmplog && self.use_redqueen_stage;
+        let input_to_state_enabled = self.cmplog && self.use_input_to_state_stage;
+        let mopt_max_stack_pow = self.mopt_max_stack_pow;
+        let mopt_swarm_num = self.mopt_swarm_num;
         let corpus_directory = self.corpus_directory.clone();
         let solutions_directory = self.solutions_directory.clone();
         let executable_tokens = self
@@ -184,6 +465,13 @@ impl Tsffs {
         let generate_random_corpus = self.generate_random_corpus;
         let initial_random_corpus_size = self.initial_random_corpus_size;
         let debug_log_libafl = self.debug_log_libafl;
+        let parallel = self.parallel;
+        let broker_port = self.broker_port;
+        let client_id = self.client_id;
+        let campaign_state_path = self
+            .persist_campaign
+            .then(|| self.campaign_fuzzer_state_path());
+        let campaign_save_interval = Duration::from_secs(self.campaign_save_interval);
         let initial_contents = self
             .use_initial_as_corpus
             .then(|| {
@@ -220,18 +508,53 @@ impl Tsffs {
                         .ok();
                 }
 
-                let mut harness = |input: &BytesInput| {
-                    let testcase = BytesInput::new(input.target_bytes().as_slice().to_vec());
+                // Set when the simulator hangs up while shutting down. The executions left in
+                // the current iteration of the fuzzing loop are not run, so the fuzzer state
+                // after the hang up is never saved.
+                let interrupted = Cell::new(false);
+
+                // The campaign state is serialized at the top of every iteration of the fuzzing
+                // loop as a pending snapshot. The next testcase sent to the simulator is marked
+                // so the simulator snapshots its own state, and the pending snapshot is committed
+                // once it is sent. Only committed snapshots are saved, so the fuzzer and simulator
+                // state files always describe the same point in the campaign.
+                let snapshot_pending = Cell::new(false);
+                let pending_snapshot = RefCell::new(None::<Vec<u8>>);
+                let committed_snapshot = RefCell::new(None::<Vec<u8>>);
+
+                let send_testcase = |testcase: BytesInput, cmplog: bool| {
+                    let snapshot = snapshot_pending.replace(false);
+
                     client
                         .borrow_mut()
                         .0
                         .send(Testcase {
                             testcase,
-                            cmplog: false,
+                            cmplog,
+                            snapshot,
                         })
                         .expect("Failed to send testcase message");
 
+                    if snapshot {
+                        *committed_snapshot.borrow_mut() = pending_snapshot.borrow_mut().take();
+                    }
+                };
+
+                let mut harness = |input: &BytesInput| {
+                    if interrupted.get() {
+                        return ExitKind::Ok;
+                    }
+
+                    send_testcase(
+                        BytesInput::new(input.target_bytes().as_slice().to_vec()),
+                        false,
+                    );
+
                     let status = match client.borrow_mut().1.recv() {
+                        Err(_) if shutdown.load(Ordering::SeqCst) => {
+                            interrupted.set(true);
+                            ExitKind::Ok
+                        }
                         Err(e) => panic!("Error receiving status: {e}"),
                         Ok(m) => m,
                     };
@@ -240,17 +563,20 @@ impl Tsffs {
                 };
 
                 let mut aflpp_cmp_harness = |input: &BytesInput| {
-                    let testcase = BytesInput::new(input.target_bytes().as_slice().to_vec());
-                    client
-                        .borrow_mut()
-                        .0
-                        .send(Testcase {
-                            testcase,
-                            cmplog: true,
-                        })
-                        .expect("Failed to send testcase message");
+                    if interrupted.get() {
+                        return ExitKind::Ok;
+                    }
+
+                    send_testcase(
+                        BytesInput::new(input.target_bytes().as_slice().to_vec()),
+                        true,
+                    );
 
                     let status = match client.borrow_mut().1.recv() {
+                        Err(_) if shutdown.load(Ordering::SeqCst) => {
+                            interrupted.set(true);
+                            ExitKind::Ok
+                        }
                         Err(e) => panic!("Error receiving status: {e}"),
                         Ok(m) => m,
                     };
@@ -286,6 +612,7 @@ impl Tsffs {
 
                 let crash_feedback = CrashFeedback::new();
                 let timeout_feedback = TimeFeedback::new(Self::TIMEOUT_FEEDBACK_NAME);
+                let solution_metadata_feedback = SolutionMetadataFeedback::new(smrx);
 
                 let solutions = OnDiskCorpus::with_meta_format(
                     solutions_directory.clone(),
@@ -311,20 +638,53 @@ impl Tsffs {
                 let colorization_stage = ColorizationStage::new(&edges_observer);
                 let generalization_stage = GeneralizationStage::new(&edges_observer);
 
-                let mut feedback = feedback_or!(map_feedback, time_feedback);
-                let mut objective = feedback_or_fast!(crash_feedback, timeout_feedback);
+                // NOTE: Crashing and timed out inputs are never added to the corpus. Otherwise
+                // solutions vetoed by the solution metadata feedback below would be added to
+                // the corpus whenever they also found new coverage.
+                let mut feedback = feedback_and_fast!(
+                    feedback_not!(feedback_or_fast!(
+                        CrashFeedback::new(),
+                        TimeoutFeedback::new()
+                    )),
+                    feedback_or!(map_feedback, time_feedback)
+                );
+                // NOTE: Solutions which duplicate a smaller solution in their bucket are
+                // vetoed by the solution metadata feedback, so they are not saved
+                let mut objective = feedback_and_fast!(
+                    feedback_or_fast!(crash_feedback, timeout_feedback),
+                    solution_metadata_feedback
+                );
 
-                let mut state = StdState::new(
-                    StdRand::with_seed(current_nanos()),
-                    corpus,
-                    solutions,
-                    &mut feedback,
-                    &mut objective,
-                )
-                .map_err(|e| {
-                    eprintln!("Couldn't initialize fuzzer state: {e}");
-                    anyhow!("Couldn't initialize state: {e}")
-                })?;
+                let resumed_state = campaign_state_path
+                    .as_ref()
+                    .map(|path| load_state::<FuzzerState>(path))
+                    .transpose()
+                    .map_err(|e| {
+                        eprintln!("Couldn't load campaign state: {e}");
+                        e
+                    })?
+                    .flatten();
+
+                let mut state = if let Some(state) = resumed_state {
+                    mtx.send(FuzzerMessage::String(format!(
+                        "Resuming campaign with {} corpus entries and {} solutions",
+                        state.corpus().count(),
+                        state.solutions().count()
+                    )))?;
+                    state
+                } else {
+                    FuzzerState::new(
+                        StdRand::with_seed(current_nanos() ^ client_id as u64),
+                        corpus,
+                        solutions,
+                        &mut feedback,
+                        &mut objective,
+                    )
+                    .map_err(|e| {
+                        eprintln!("Couldn't initialize fuzzer state: {e}");
+                        anyhow!("Couldn't initialize state: {e}")
+                    })?
+                };
 
                 let mut tokens = Tokens::default().add_from_files(token_files)?;
 
@@ -338,211 +698,305 @@ impl Tsffs {
                     IndexesLenTimeMinimizerScheduler::new(StdWeightedScheduler::with_schedule(
                         &mut state,
                         &edges_observer,
-                        Some(PowerSchedule::EXPLORE),
+                        Some(power_schedule),
                     ));
 
                 let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
 
-                let monitor = {
-                    let mtx = mtx.clone();
-                    SimpleMonitor::new(move |s| {
-                        mtx.send(FuzzerMessage::String(s.to_string()))
-                            .expect("Failed to send monitor message");
-                    })
-                };
-
-                let mut manager = SimpleEventManager::new(monitor);
-
-                let mut executor = InProcessExecutor::new(
-                    &mut harness,
-                    tuple_list!(edges_observer, time_observer),
-                    &mut fuzzer,
-                    &mut manager,
-                )
-                .map_err(|e| {
-                    eprintln!("Couldn't initialize fuzzer executor: {e}");
-                    anyhow!("Couldn't initialize fuzzer executor: {e}")
-                })?;
-
-                let aflpp_cmp_executor = InProcessExecutor::new(
-                    &mut aflpp_cmp_harness,
-                    tuple_list!(aflpp_cmp_observer),
-                    &mut fuzzer,
-                    &mut manager,
-                )
-                .map_err(|e| {
-                    eprintln!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}");
-                    anyhow!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}")
-                })?;
-
-                let tracing_executor = InProcessExecutor::new(
-                    &mut tracing_harness,
-                    tuple_list!(cmplog_observer),
-                    &mut fuzzer,
-                    &mut manager,
-                )
-                .map_err(|e| {
-                    eprintln!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}");
-                    anyhow!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}")
-                })?;
-
-                let input_to_state_stage = StdMutationalStage::new(StdScheduledMutator::new(
-                    tuple_list!(I2SRandReplace::new()),
-                ));
-                let havoc_mutational_stage = StdPowerMutationalStage::new(
-                    StdScheduledMutator::new(havoc_mutations().merge(tokens_mutations())),
-                );
-                let mopt_mutational_stage = StdPowerMutationalStage::new(
-                    StdMOptMutator::new(
-                        &mut state,
-                        havoc_mutations().merge(tokens_mutations()),
-                        7,
-                        5,
-                    )
-                    .map_err(|e| {
-                        eprintln!("Couldn't initialize fuzzer MOpt mutator: {e}");
-                        anyhow!("Couldn't initialize fuzzer MOpt mutator: {e}")
-                    })?,
-                );
-                let redqueen_mutational_stage =
-                    MultiMutationalStage::new(AFLppRedQueen::with_cmplog_options(true, true));
-                let aflpp_tracing_stage = AFLppCmplogTracingStage::with_cmplog_observer_name(
-                    aflpp_cmp_executor,
-                    Self::AFLPP_CMP_OBSERVER_NAME,
-                );
-                let tracing_stage = TracingStage::new(tracing_executor);
-                let synchronize_corpus_stage =
-                    SyncFromDiskStage::with_from_file(corpus_directory.clone());
-                let dump_corpus_stage = DumpToDiskStage::new(
-                    |input: &BytesInput, _state: &_| input.target_bytes().as_slice().to_vec(),
-                    corpus_directory.clone(),
-                    solutions_directory.clone(),
-                )
-                .map_err(|e| {
-                    eprintln!("Couldn't initialize fuzzer dump to disk stage: {e}");
-                    anyhow!("Couldn't initialize fuzzer dump to disk stage: {e}")
-                })?;
+                // NOTE: The event manager type is part of the type of every executor and stage,
+                // so the remainder of the fuzzer is instantiated once per event manager type.
+                macro_rules! run_fuzzer {
+                    ($manager:ident) => {{
+                        let mut executor = InProcessExecutor::new(
+                            &mut harness,
+                            tuple_list!(edges_observer, time_observer),
+                            &mut fuzzer,
+                            &mut $manager,
+                        )
+                        .map_err(|e| {
+                            eprintln!("Couldn't initialize fuzzer executor: {e}");
+                            anyhow!("Couldn't initialize fuzzer executor: {e}")
+                        })?;
 
-                if let Some(contents) = initial_contents {
-                    write(
-                        corpus_directory.join(contents.generate_name(0)),
-                        contents.bytes(),
-                    )?;
-                }
+                        let aflpp_cmp_executor = InProcessExecutor::new(
+                            &mut aflpp_cmp_harness,
+                            tuple_list!(aflpp_cmp_observer),
+                            &mut fuzzer,
+                            &mut $manager,
+                        )
+                        .map_err(|e| {
+                            eprintln!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}");
+                            anyhow!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}")
+                        })?;
 
-                if state.must_load_initial_inputs() {
-                    state
-                        .load_initial_inputs(
+                        let tracing_executor = InProcessExecutor::new(
+                            &mut tracing_harness,
+                            tuple_list!(cmplog_observer),
                             &mut fuzzer,
-                            &mut executor,
-                            &mut manager,
-                            &[corpus_directory.clone()],
+                            &mut $manager,
                         )
                         .map_err(|e| {
-                            eprintln!(
-                                "Error loading initial inputs from {corpus_directory:?}: {e}"
-                            );
-                            anyhow!("Error loading initial inputs from {corpus_directory:?}: {e}")
+                            eprintln!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}");
+                            anyhow!("Couldn't initialize fuzzer AFL++ cmplog executor: {e}")
                         })?;
 
-                    if state.corpus().count() < 1 && generate_random_corpus {
-                        let mut generator = RandBytesGenerator::new(64);
-                        state
-                            .generate_initial_inputs(
-                                &mut fuzzer,
-                                &mut executor,
-                                &mut generator,
-                                &mut manager,
-                                initial_random_corpus_size,
+                        let input_to_state_stage = StdMutationalStage::new(StdScheduledMutator::new(
+                            tuple_list!(I2SRandReplace::new()),
+                        ));
+                        let havoc_mutational_stage = StdPowerMutationalStage::new(
+                            StdScheduledMutator::new(havoc_mutations().merge(tokens_mutations())),
+                        );
+                        let mopt_mutational_stage = StdPowerMutationalStage::new(
+                            StdMOptMutator::new(
+                                &mut state,
+                                havoc_mutations().merge(tokens_mutations()),
+                                mopt_max_stack_pow,
+                                mopt_swarm_num,
                             )
                             .map_err(|e| {
-                                eprintln!("Error generating random inputs: {e}");
-                                anyhow!("Error generating random inputs: {e}")
-                            })?;
-                    }
-                }
-
-                if state.corpus().count() < 1 {
-                    panic!(
-                        "No interesting cases found from inputs! This may mean \
-                            your harness is incorrect (check your arguments), your inputs \
-                            are not triggering new code paths, or all inputs are causing \
-                            crashes.",
-                    );
-                }
+                                eprintln!("Couldn't initialize fuzzer MOpt mutator: {e}");
+                                anyhow!("Couldn't initialize fuzzer MOpt mutator: {e}")
+                            })?,
+                        );
+                        let redqueen_mutational_stage =
+                            MultiMutationalStage::new(AFLppRedQueen::with_cmplog_options(true, true));
+                        let aflpp_tracing_stage = AFLppCmplogTracingStage::with_cmplog_observer_name(
+                            aflpp_cmp_executor,
+                            Self::AFLPP_CMP_OBSERVER_NAME,
+                        );
+                        let tracing_stage = TracingStage::new(tracing_executor);
+                        let synchronize_corpus_stage =
+                            SyncFromDiskStage::with_from_file(corpus_directory.clone());
+                        let dump_corpus_stage = DumpToDiskStage::new(
+                            |input: &BytesInput, _state: &_| input.target_bytes().as_slice().to_vec(),
+                            corpus_directory.clone(),
+                            solutions_directory.clone(),
+                        )
+                        .map_err(|e| {
+                            eprintln!("Couldn't initialize fuzzer dump to disk stage: {e}");
+                            anyhow!("Couldn't initialize fuzzer dump to disk stage: {e}")
+                        })?;
 
-                let mut stages = tuple_list!(
-                    calibration_stage,
-                    generalization_stage,
-                    IfStage::new(
-                        |_fuzzer: &mut _,
-                         _executor: &mut _,
-                         state: &mut StdState<_, CachedOnDiskCorpus<_>, _, _>,
-                         _event_manager: &mut _|
-                         -> Result<bool, libafl::Error> {
-                            Ok(cmplog_enabled
-                                && state
-                                    .corpus()
-                                    .get(
-                                        state
-                                            .current_corpus_idx()
+                        if let Some(contents) = initial_contents {
+                            write(
+                                corpus_directory.join(contents.generate_name(0)),
+                                contents.bytes(),
+                            )?;
+                        }
+
+                        if state.must_load_initial_inputs() {
+                            state
+                                .load_initial_inputs(
+                                    &mut fuzzer,
+                                    &mut executor,
+                                    &mut $manager,
+                                    &[corpus_directory.clone()],
+                                )
+                                .map_err(|e| {
+                                    eprintln!(
+                                        "Error loading initial inputs from {corpus_directory:?}: {e}"
+                                    );
+                                    anyhow!("Error loading initial inputs from {corpus_directory:?}: {e}")
+                                })?;
+
+                            if state.corpus().count() < 1 && generate_random_corpus {
+                                let mut generator = RandBytesGenerator::new(64);
+                                state
+                                    .generate_initial_inputs(
+                                        &mut fuzzer,
+                                        &mut executor,
+                                        &mut generator,
+                                        &mut $manager,
+                                        initial_random_corpus_size,
+                                    )
+                                    .map_err(|e| {
+                                        eprintln!("Error generating random inputs: {e}");
+                                        anyhow!("Error generating random inputs: {e}")
+                                    })?;
+                            }
+                        }
+
+                        if state.corpus().count() < 1 {
+                            panic!(
+                                "No interesting cases found from inputs! This may mean \
+                                    your harness is incorrect (check your arguments), your inputs \
+                                    are not triggering new code paths, or all inputs are causing \
+                                    crashes.",
+                            );
+                        }
+
+                        let mut stages = tuple_list!(
+                            calibration_stage,
+                            IfStage::new(
+                                |_fuzzer: &mut _,
+                                 _executor: &mut _,
+                                 _state: &mut StdState<_, CachedOnDiskCorpus<_>, _, _>,
+                                 _event_manager: &mut _|
+                                 -> Result<bool, libafl::Error> {
+                                    Ok(generalization_enabled)
+                                },
+                                tuple_list!(generalization_stage)
+                            ),
+                            IfStage::new(
+                                |_fuzzer: &mut _,
+                                 _executor: &mut _,
+                                 state: &mut StdState<_, CachedOnDiskCorpus<_>, _, _>,
+                                 _event_manager: &mut _|
+                                 -> Result<bool, libafl::Error> {
+                                    Ok(redqueen_enabled
+                                        && state
+                                            .corpus()
+                                            .get(
+                                                state
+                                                    .current_corpus_idx()
+                                                    .map_err(|e| {
+                                                        eprintln!(
+                                                            "Error getting current corpus index: {e}"
+                                                        );
+                                                        // libafl::Error::unkown(format!(
+                                                        //     "Error getting current corpus index: {e}"
+                                                        // ))
+                                                        e
+                                                    })?
+                                                    .ok_or_else(|| {
+                                                        eprintln!("No current corpus index");
+
+                                                        libafl::Error::unknown("No current corpus index")
+                                                    })?,
+                                            )
                                             .map_err(|e| {
-                                                eprintln!(
-                                                    "Error getting current corpus index: {e}"
-                                                );
-                                                // libafl::Error::unkown(format!(
-                                                //     "Error getting current corpus index: {e}"
-                                                // ))
+                                                eprintln!("Error getting current corpus entry: {e}");
                                                 e
                                             })?
-                                            .ok_or_else(|| {
-                                                eprintln!("No current corpus index");
+                                            .borrow()
+                                            .scheduled_count()
+                                            == 1)
+                                },
+                                tuple_list!(
+                                    colorization_stage,
+                                    aflpp_tracing_stage,
+                                    redqueen_mutational_stage
+                                )
+                            ),
+                            IfStage::new(
+                                |_fuzzer: &mut _,
+                                 _executor: &mut _,
+                                 _state: &mut StdState<_, CachedOnDiskCorpus<_>, _, _>,
+                                 _event_manager: &mut _|
+                                 -> Result<bool, libafl::Error> {
+                                    Ok(input_to_state_enabled)
+                                },
+                                tuple_list!(tracing_stage, input_to_state_stage)
+                            ),
+                            havoc_mutational_stage,
+                            IfStage::new(
+                                |_fuzzer: &mut _,
+                                 _executor: &mut _,
+                                 _state: &mut StdState<_, CachedOnDiskCorpus<_>, _, _>,
+                                 _event_manager: &mut _|
+                                 -> Result<bool, libafl::Error> {
+                                    Ok(mopt_enabled)
+                                },
+                                tuple_list!(mopt_mutational_stage)
+                            ),
+                            dump_corpus_stage,
+                            synchronize_corpus_stage,
+                        );
+
+                        let mut campaign_last_saved = Instant::now();
+                        let mut reported_corpus_count = None;
+
+                        loop {
+                            // Check if the simulator is shutting down, and if so, exit.
+                            if shutdown.load(Ordering::SeqCst) {
+                                break;
+                            }
+
+                            if campaign_state_path.is_some() {
+                                *pending_snapshot.borrow_mut() = Some(serde_json::to_vec(&state)?);
+                                snapshot_pending.set(true);
+                            }
+
+                            fuzzer
+                                .fuzz_one(&mut stages, &mut executor, &mut state, &mut $manager)
+                                .map_err(|e| {
+                                    eprintln!("Error running iteration of fuzzing loop: {e}");
+                                    anyhow!("Error running iteration of fuzzing loop: {e}")
+                                })?;
+
+                            if interrupted.get() {
+                                break;
+                            }
+
+                            let corpus_count = state.corpus().count();
+
+                            if reported_corpus_count != Some(corpus_count) {
+                                mtx.send(FuzzerMessage::Stats { corpus_count })?;
+                                reported_corpus_count = Some(corpus_count);
+                            }
+
+                            if let Some(path) = campaign_state_path.as_ref() {
+                                if campaign_last_saved.elapsed() >= campaign_save_interval {
+                                    if let Some(snapshot) = committed_snapshot.borrow().as_ref() {
+                                        save_state_bytes(path, snapshot).map_err(|e| {
+                                            eprintln!("Error saving campaign state: {e}");
+                                            e
+                                        })?;
+                                    }
+                                    campaign_last_saved = Instant::now();
+                                }
+                            }
+                        }
+
+                        // NOTE: The state after the last iteration includes executions the
+                        // simulator may not have run, so the last committed snapshot, which the
+                        // simulator saves its matching state for, is saved instead
+                        if let Some(path) = campaign_state_path.as_ref() {
+                            if let Some(snapshot) = committed_snapshot.borrow().as_ref() {
+                                save_state_bytes(path, snapshot).map_err(|e| {
+                                    eprintln!("Error saving campaign state: {e}");
+                                    e
+                                })?;
+                            }
+                        }
+                    }};
+                }
 
-                                                libafl::Error::unknown("No current corpus index")
-                                            })?,
-                                    )
-                                    .map_err(|e| {
-                                        eprintln!("Error getting current corpus entry: {e}");
-                                        e
-                                    })?
-                                    .borrow()
-                                    .scheduled_count()
-                                    == 1)
-                        },
-                        tuple_list!(
-                            colorization_stage,
-                            aflpp_tracing_stage,
-                            redqueen_mutational_stage
-                        )
-                    ),
-                    IfStage::new(
-                        |_fuzzer: &mut _,
-                         _executor: &mut _,
-                         _state: &mut StdState<_, CachedOnDiskCorpus<_>, _, _>,
-                         _event_manager: &mut _|
-                         -> Result<bool, libafl::Error> {
-                            Ok(cmplog_enabled)
-                        },
-                        tuple_list!(tracing_stage, input_to_state_stage)
-                    ),
-                    havoc_mutational_stage,
-                    mopt_mutational_stage,
-                    dump_corpus_stage,
-                    synchronize_corpus_stage,
-                );
+                if parallel {
+                    let mut manager = LlmpEventManager::new_on_port(
+                        StdShMemProvider::new()?,
+                        broker_port,
+                        EventConfig::from_name(Self::EVENT_CONFIG_NAME),
+                    )
+                    .map_err(|e| {
+                        eprintln!("Couldn't connect to broker on port {broker_port}: {e}");
+                        anyhow!("Couldn't connect to broker on port {broker_port}: {e}")
+                    })?;
+
+                    mtx.send(FuzzerMessage::String(format!(
+                        "Client {client_id} connected to broker on port {broker_port}"
+                    )))?;
+
+                    // NOTE: The broker is told this client is exiting however the fuzzing loop
+                    // ends, so it does not wait for the client to come back
+                    let result = (|| -> Result<()> {
+                        run_fuzzer!(manager);
+                        Ok(())
+                    })();
+
+                    manager.send_exiting()?;
+                    result?;
+                } else {
+                    let monitor = {
+                        let mtx = mtx.clone();
+                        SimpleMonitor::new(move |s| {
+                            mtx.send(FuzzerMessage::String(s.to_string())).ok();
+                        })
+                    };
 
-                loop {
-                    // Check if we have a message to shut down, and if so, exit.
-                    if let Ok(_msg) = srx.try_recv() {
-                        break;
-                    }
+                    let mut manager = SimpleEventManager::new(monitor);
 
-                    fuzzer
-                        .fuzz_one(&mut stages, &mut executor, &mut state, &mut manager)
-                        .map_err(|e| {
-                            eprintln!("Error running iteration of fuzzing loop: {e}");
-                            anyhow!("Error running iteration of fuzzing loop: {e}")
-                        })?;
+                    run_fuzzer!(manager);
                 }
 
                 println!("Fuzzing loop exited.");
@@ -555,10 +1009,41 @@ impl Tsffs {
     }
 
     pub fn send_shutdown(&mut self) -> Result<()> {
-        if let Some(stx) = self.fuzzer_shutdown.get_mut() {
-            stx.send(ShutdownMessage::default())?;
+        if let Some(shutdown) = self.fuzzer_shutdown.get() {
+            shutdown.store(true, Ordering::SeqCst);
+        }
+
+        if self.persist_campaign {
+            // A testcase the fuzzer thread already sent may be the one marking its latest
+            // snapshot, in which case it is committed on the fuzzer side and the simulator
+            // must take the matching snapshot before saving
+            let snapshot = self
+                .fuzzer_rx
+                .get()
+                .is_some_and(|fuzzer_rx| fuzzer_rx.try_iter().any(|testcase| testcase.snapshot));
+
+            if snapshot {
+                self.snapshot_campaign_simulator_state();
+            }
+
+            self.save_campaign_simulator_state()?;
+        }
+
+        if self.persist_campaign || self.parallel {
+            // Hang up on the fuzzer thread so it stops waiting for the outcome of testcases,
+            // then wait for it to save its state and disconnect from the broker before the
+            // simulator has a chance to quit.
+            self.fuzzer_tx.take();
+
+            if let Some(fuzz_thread) = self.fuzz_thread.take() {
+                fuzz_thread
+                    .join()
+                    .map_err(|_| anyhow!("Fuzzer thread panicked"))??;
+            }
         }
 
+        self.stop_broker_thread()?;
+
         Ok(())
     }
 
@@ -568,6 +1053,20 @@ impl Tsffs {
             Testcase {
                 testcase: BytesInput::new(testcase.clone()),
                 cmplog: false,
+                snapshot: false,
+            }
+        } else if let Some(minimizer) = self.minimizer.as_ref() {
+            debug!(self.as_conf_object(), "Using minimization candidate");
+            Testcase {
+                testcase: BytesInput::new(minimizer.candidate().to_vec()),
+                cmplog: false,
+                snapshot: false,
+            }
+        } else if let Some(corpus_minimizer) = self.corpus_minimizer.as_mut() {
+            Testcase {
+                testcase: BytesInput::new(corpus_minimizer.candidate().to_vec()),
+                cmplog: false,
+                snapshot: false,
             }
         } else {
             self.fuzzer_rx
@@ -595,6 +1094,14 @@ impl Tsffs {
 
         self.cmplog_enabled = testcase.cmplog;
 
+        if testcase.snapshot {
+            self.snapshot_campaign_simulator_state();
+        }
+
+        if self.bucket_solutions {
+            self.current_testcase = Some(testcase.testcase.bytes().to_vec());
+        }
+
         debug!(self.as_conf_object(), "Testcase: {testcase:?}");
 
         Ok(testcase)
@@ -0,0 +1,183 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of out-of-bounds accesses around the testcase buffer
+//!
+//! Reads past the end of the testcase buffer usually read whatever happens to follow it in
+//! memory and do not raise an exception. When the guard is enabled, breakpoints are set on
+//! the memory around the buffer each time a testcase is written, and an access to them is a
+//! solution.
+
+use crate::{arch::ArchitectureOperations, state::SolutionKind, Tsffs};
+use anyhow::{anyhow, Error, Result};
+use simics::api::{
+    breakpoint, delete_breakpoint, sys::breakpoint_kind_t, Access, AttrValue, AttrValueType,
+    BreakpointFlag, GenericTransaction,
+};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd, Hash, Default)]
+/// The memory guarded after the testcase buffer
+pub(crate) enum TestcaseGuardMode {
+    #[default]
+    /// The testcase buffer is not guarded
+    None,
+    /// The `testcase_guard_size` bytes following the end of the buffer at its maximum size
+    End,
+    /// The bytes of the buffer between the end of the testcase and the maximum size, for
+    /// buffers which are followed by other data
+    Unused,
+}
+
+impl TestcaseGuardMode {
+    const AS_STRING: &'static [(&'static str, Self)] = &[
+        ("none", Self::None),
+        ("end", Self::End),
+        ("unused", Self::Unused),
+    ];
+}
+
+impl FromStr for TestcaseGuardMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let as_string = Self::AS_STRING.iter().cloned().collect::<HashMap<_, _>>();
+
+        as_string.get(s).cloned().ok_or_else(|| {
+            anyhow!(
+                "Invalid testcase guard mode {}. Expected one of {}",
+                s,
+                Self::AS_STRING
+                    .iter()
+                    .map(|i| i.0)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+}
+
+impl Display for TestcaseGuardMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let to_string = Self::AS_STRING
+            .iter()
+            .map(|(k, v)| (v, k))
+            .collect::<HashMap<_, _>>();
+        if let Some(name) = to_string.get(self) {
+            write!(f, "{}", name)
+        } else {
+            panic!("Invalid state for enum");
+        }
+    }
+}
+
+impl TryFrom<AttrValue> for TestcaseGuardMode {
+    type Error = Error;
+
+    fn try_from(value: AttrValue) -> Result<Self> {
+        String::try_from(value)?.parse()
+    }
+}
+
+impl From<TestcaseGuardMode> for AttrValueType {
+    fn from(value: TestcaseGuardMode) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl From<TestcaseGuardMode> for AttrValue {
+    fn from(value: TestcaseGuardMode) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl Tsffs {
+    /// Set the breakpoints guarding the testcase buffer after a testcase of `length` bytes
+    /// was written to it, replacing those set for the previous testcase
+    pub fn arm_testcase_guard(&mut self, length: usize) -> Result<()> {
+        self.disarm_testcase_guard()?;
+
+        if self.testcase_guard_mode == TestcaseGuardMode::None {
+            return Ok(());
+        }
+
+        let start_info = self
+            .start_info
+            .get()
+            .ok_or_else(|| anyhow!("No start info"))?;
+        let address = start_info.address.physical_address();
+        let maximum_size = start_info.size.maximum_size() as u64;
+        let length = (length as u64).min(maximum_size);
+
+        let mut regions = Vec::new();
+
+        if self.testcase_guard_before && self.testcase_guard_size > 0 {
+            regions.push((
+                address.wrapping_sub(self.testcase_guard_size),
+                self.testcase_guard_size,
+            ));
+        }
+
+        match self.testcase_guard_mode {
+            TestcaseGuardMode::End if self.testcase_guard_size > 0 => {
+                regions.push((address.wrapping_add(maximum_size), self.testcase_guard_size))
+            }
+            TestcaseGuardMode::Unused if length < maximum_size => {
+                regions.push((address.wrapping_add(length), maximum_size - length))
+            }
+            _ => {}
+        }
+
+        let physical_memory = self
+            .start_processor()
+            .ok_or_else(|| anyhow!("No start processor"))?
+            .processor_info_v2()
+            .get_physical_memory()?;
+
+        for (start, size) in regions {
+            self.testcase_guard_breakpoints.push(breakpoint(
+                physical_memory,
+                breakpoint_kind_t::Sim_Break_Physical,
+                Access::Sim_Access_Read | Access::Sim_Access_Write,
+                start,
+                size,
+                BreakpointFlag::Sim_Breakpoint_Simulation | BreakpointFlag::Sim_Breakpoint_Private,
+            )?);
+        }
+
+        Ok(())
+    }
+
+    /// Delete the breakpoints guarding the testcase buffer
+    pub fn disarm_testcase_guard(&mut self) -> Result<()> {
+        for id in self.testcase_guard_breakpoints.drain(..) {
+            delete_breakpoint(id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the solution a hit of the breakpoint `breakpoint` is, if it is guarding the
+    /// testcase buffer
+    pub fn testcase_guard_violation(
+        &self,
+        breakpoint: i64,
+        transaction: *mut GenericTransaction,
+    ) -> Option<SolutionKind> {
+        if !self
+            .testcase_guard_breakpoints
+            .iter()
+            .any(|id| *id as i64 == breakpoint)
+        {
+            return None;
+        }
+
+        let address = unsafe { (*transaction).physical_address };
+        let start = self.start_info.get()?.address.physical_address();
+
+        Some(SolutionKind::OutOfBounds {
+            address,
+            offset: address.wrapping_sub(start) as i64,
+        })
+    }
+}
@@ -3,7 +3,7 @@
 
 //! Handlers for HAPs in the simulator
 
-use std::time::SystemTime;
+use std::{ptr::null_mut, time::SystemTime};
 
 use crate::{
     arch::ArchitectureOperations,
@@ -53,12 +53,13 @@ impl Tsffs {
                 .set(SystemTime::now())
                 .map_err(|_| anyhow!("Failed to set start time"))?;
             self.coverage_enabled = true;
+            self.record_address_space_root()?;
             self.save_initial_snapshot()?;
             self.get_and_write_testcase()?;
             self.post_timeout_event()?;
         }
 
-        self.execution_trace.0.clear();
+        self.reset_execution_trace()?;
         self.save_repro_bookmark_if_needed()?;
 
         debug!(self.as_conf_object(), "Resuming simulation");
@@ -72,7 +73,18 @@ impl Tsffs {
     }
 
     fn on_simulation_stopped_magic_assert(&mut self) -> Result<()> {
-        self.on_simulation_stopped_solution(SolutionKind::Manual)
+        let id = self
+            .start_processor()
+            .ok_or_else(|| anyhow!("No start processor"))?
+            .get_magic_index_selector()?;
+
+        self.on_simulation_stopped_solution(
+            SolutionKind::Manual {
+                id,
+                message: "Harness assertion".to_string(),
+            },
+            null_mut(),
+        )
     }
 
     fn on_simulation_stopped_magic_stop(&mut self) -> Result<()> {
@@ -101,6 +113,14 @@ impl Tsffs {
                 return Ok(());
             }
 
+            if self.minimizer.is_some() {
+                return self.on_minimize_iteration(None);
+            }
+
+            if self.corpus_minimizer.is_some() {
+                return self.on_corpus_minimize_iteration(None);
+            }
+
             self.iterations += 1;
 
             if self.iteration_limit != 0 && self.iterations >= self.iteration_limit {
@@ -202,6 +222,7 @@ impl Tsffs {
                 .set(SystemTime::now())
                 .map_err(|_| anyhow!("Failed to set start time"))?;
             self.coverage_enabled = true;
+            self.record_address_space_root()?;
             self.save_initial_snapshot()?;
 
             self.get_and_write_testcase()?;
@@ -209,7 +230,7 @@ impl Tsffs {
             self.post_timeout_event()?;
         }
 
-        self.execution_trace.0.clear();
+        self.reset_execution_trace()?;
         self.save_repro_bookmark_if_needed()?;
 
         debug!(self.as_conf_object(), "Resuming simulation");
@@ -234,12 +255,13 @@ impl Tsffs {
                 .set(SystemTime::now())
                 .map_err(|_| anyhow!("Failed to set start time"))?;
             self.coverage_enabled = true;
+            self.record_address_space_root()?;
             self.save_initial_snapshot()?;
 
             self.post_timeout_event()?;
         }
 
-        self.execution_trace.0.clear();
+        self.reset_execution_trace()?;
         self.save_repro_bookmark_if_needed()?;
 
         debug!(self.as_conf_object(), "Resuming simulation");
@@ -278,6 +300,14 @@ impl Tsffs {
                 return Ok(());
             }
 
+            if self.minimizer.is_some() {
+                return self.on_minimize_iteration(None);
+            }
+
+            if self.corpus_minimizer.is_some() {
+                return self.on_corpus_minimize_iteration(None);
+            }
+
             self.iterations += 1;
 
             if self.iteration_limit != 0 && self.iterations >= self.iteration_limit {
@@ -344,7 +374,11 @@ impl Tsffs {
         Ok(())
     }
 
-    fn on_simulation_stopped_solution(&mut self, kind: SolutionKind) -> Result<()> {
+    fn on_simulation_stopped_solution(
+        &mut self,
+        kind: SolutionKind,
+        processor: *mut ConfObject,
+    ) -> Result<()> {
         if !self.have_initial_snapshot() {
             warn!(
                 self.as_conf_object(),
@@ -370,6 +404,14 @@ impl Tsffs {
                 return Ok(());
             }
 
+            if self.minimizer.is_some() {
+                return self.on_minimize_iteration(Some(kind));
+            }
+
+            if self.corpus_minimizer.is_some() {
+                return self.on_corpus_minimize_iteration(Some(kind));
+            }
+
             self.iterations += 1;
 
             if self.iteration_limit != 0 && self.iterations >= self.iteration_limit {
@@ -400,6 +442,21 @@ impl Tsffs {
                 }
             }
 
+            // NOTE: The metadata must be sent before the exit kind, so it is available to the
+            // fuzzer when it evaluates the objective for this execution
+            let mut metadata = self.solution_metadata(kind.clone(), processor)?;
+
+            self.stats.record_solution(&kind);
+
+            if self.bucket_solutions {
+                metadata.duplicate = !self.bucket_solution(&metadata)?;
+            }
+
+            self.fuzzer_solution_metadata
+                .get()
+                .ok_or_else(|| anyhow!("No fuzzer solution metadata channel"))?
+                .send(metadata)?;
+
             let fuzzer_tx = self
                 .fuzzer_tx
                 .get()
@@ -407,9 +464,14 @@ impl Tsffs {
 
             match kind {
                 SolutionKind::Timeout => fuzzer_tx.send(ExitKind::Timeout)?,
-                SolutionKind::Exception | SolutionKind::Breakpoint | SolutionKind::Manual => {
-                    fuzzer_tx.send(ExitKind::Crash)?
-                }
+                SolutionKind::Exception { .. }
+                | SolutionKind::Breakpoint { .. }
+                | SolutionKind::Manual { .. }
+                | SolutionKind::Sanitizer { .. }
+                | SolutionKind::ControlFlowIntegrity { .. }
+                | SolutionKind::OutOfBounds { .. }
+                | SolutionKind::MemoryRegion { .. }
+                | SolutionKind::Console { .. } => fuzzer_tx.send(ExitKind::Crash)?,
             }
 
             self.restore_initial_snapshot()?;
@@ -458,7 +520,9 @@ impl Tsffs {
                 self.on_simulation_stopped_manual_start_without_buffer(processor)
             }
             StopReason::ManualStop => self.on_simulation_stopped_manual_stop(),
-            StopReason::Solution { kind } => self.on_simulation_stopped_solution(kind),
+            StopReason::Solution { kind, processor } => {
+                self.on_simulation_stopped_solution(kind, processor)
+            }
         }
     }
 
@@ -515,6 +579,9 @@ impl Tsffs {
 
         //  Log information from the fuzzer
         self.log_messages()?;
+        self.save_campaign_simulator_state_if_needed()?;
+        self.write_stats_if_needed()?;
+        self.update_metrics()?;
 
         if let Some(reason) = self.stop_reason.take() {
             self.on_simulation_stopped_with_reason(reason)
@@ -525,24 +592,47 @@ impl Tsffs {
 
     /// Called on core exception HAP. Check to see if this exception is configured as a solution
     /// or all exceptions are solutions and trigger a stop if so
-    pub fn on_exception(&mut self, _obj: *mut ConfObject, exception: i64) -> Result<()> {
+    pub fn on_exception(&mut self, obj: *mut ConfObject, exception: i64) -> Result<()> {
+        if self.return_address_solutions && self.coverage_enabled {
+            // The handler returns to the interrupted instruction, which was not called
+            self.return_address_stacks
+                .entry(get_processor_number(obj)?)
+                .or_default()
+                .interrupt();
+        }
+
         if self.all_exceptions_are_solutions || self.exceptions.contains(&exception) {
             self.stop_simulation(StopReason::Solution {
-                kind: SolutionKind::Exception,
+                kind: SolutionKind::Exception { number: exception },
+                processor: obj,
             })?;
         }
         Ok(())
     }
 
-    /// Called on breakpoint memory operation HAP. Check to see if this breakpoint is configured
-    /// as a solution or if all breakpoints are solutions and trigger a stop if so
+    /// Called on breakpoint memory operation HAP. Check to see if this breakpoint is set by the
+    /// heap sanitizer and the access is invalid, if it guards the testcase buffer or a memory
+    /// region, or if it is configured as a solution or if all breakpoints are solutions and
+    /// trigger a stop if so
     pub fn on_breakpoint_memop(
         &mut self,
         obj: *mut ConfObject,
         breakpoint: i64,
         transaction: *mut GenericTransaction,
     ) -> Result<()> {
-        if self.all_breakpoints_are_solutions || self.breakpoints.contains(&(breakpoint as i32)) {
+        if let Some(kind) = self
+            .sanitizer_violation(breakpoint, transaction)
+            .or_else(|| self.testcase_guard_violation(breakpoint, transaction))
+            .or_else(|| self.memory_region_violation(breakpoint, transaction))
+        {
+            self.stop_simulation(StopReason::Solution {
+                kind,
+                processor: null_mut(),
+            })?;
+        } else if !self.sanitizer.owns(breakpoint)
+            && (self.all_breakpoints_are_solutions
+                || self.breakpoints.contains(&(breakpoint as i32)))
+        {
             info!(
                 self.as_conf_object(),
                 "on_breakpoint_memop({:#x}, {}, {:#x})",
@@ -552,7 +642,8 @@ impl Tsffs {
             );
 
             self.stop_simulation(StopReason::Solution {
-                kind: SolutionKind::Breakpoint,
+                kind: SolutionKind::Breakpoint { id: breakpoint },
+                processor: null_mut(),
             })?;
         }
         Ok(())
@@ -1,13 +1,34 @@
 // Copyright (C) 2024 Intel Corporation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{arch::ArchitectureHint, Tsffs};
-use simics::{debug, get_processor_number, interface, AsConfObject, ConfObject, Result};
+use crate::{
+    arch::ArchitectureHint,
+    filter::AddressRange,
+    regions::{MemoryRegion, RegionAccess},
+    routines::{symbol_address, CmpRoutine},
+    sanitizer::AllocatorRoutine,
+    Tsffs,
+};
+use anyhow::anyhow;
+use simics::{
+    debug, get_processor_number, interface, lookup_file, AsConfObject, ConfObject, Result,
+};
 use std::{
     ffi::{c_char, CStr},
     str::FromStr,
 };
 
+/// Read an optional string, where a null pointer or empty string is none
+fn optional_string(string: *mut c_char) -> Result<Option<String>> {
+    if string.is_null() {
+        return Ok(None);
+    }
+
+    let string = unsafe { CStr::from_ptr(string) }.to_str()?;
+
+    Ok((!string.is_empty()).then(|| string.to_string()))
+}
+
 #[interface(name = "config")]
 impl Tsffs {
     /// Add a processor to be traced. By default, only the processor the start event occurs on
@@ -39,4 +60,246 @@ impl Tsffs {
 
         Ok(())
     }
+
+    /// Include a range of addresses from `start` up to (but not including) `end` in tracing.
+    /// Once any range is included, only instructions in included ranges feed the coverage
+    /// and comparison maps. If `physical` is set, the range is of physical addresses,
+    /// otherwise it is of virtual addresses. `module` optionally names the module the range
+    /// belongs to.
+    pub fn include_address_range(
+        &mut self,
+        start: u64,
+        end: u64,
+        physical: bool,
+        module: *mut c_char,
+    ) -> Result<()> {
+        let module = optional_string(module)?;
+
+        self.add_address_range(AddressRange {
+            start,
+            end,
+            physical,
+            exclude: false,
+            module,
+        });
+
+        Ok(())
+    }
+
+    /// Exclude a range of addresses from `start` up to (but not including) `end` from
+    /// tracing. Excluded ranges take precedence over included ranges. If `physical` is set,
+    /// the range is of physical addresses, otherwise it is of virtual addresses. `module`
+    /// optionally names the module the range belongs to.
+    pub fn exclude_address_range(
+        &mut self,
+        start: u64,
+        end: u64,
+        physical: bool,
+        module: *mut c_char,
+    ) -> Result<()> {
+        let module = optional_string(module)?;
+
+        self.add_address_range(AddressRange {
+            start,
+            end,
+            physical,
+            exclude: true,
+            module,
+        });
+
+        Ok(())
+    }
+
+    /// Include the executable sections of an ELF or PE file loaded at `base` in tracing. For
+    /// ELF files, `base` is added to each section's address and should be 0 for executables
+    /// which are not relocated. For PE files, `base` is the address the image was loaded at.
+    pub fn include_executable(
+        &mut self,
+        executable: *mut c_char,
+        base: u64,
+        physical: bool,
+    ) -> Result<()> {
+        let executable = lookup_file(unsafe { CStr::from_ptr(executable) }.to_str()?)?;
+
+        AddressRange::from_executable(executable, base, physical, false)?
+            .into_iter()
+            .for_each(|r| self.add_address_range(r));
+
+        Ok(())
+    }
+
+    /// Exclude the executable sections of an ELF or PE file loaded at `base` from tracing.
+    /// `base` is interpreted as for `include_executable`.
+    pub fn exclude_executable(
+        &mut self,
+        executable: *mut c_char,
+        base: u64,
+        physical: bool,
+    ) -> Result<()> {
+        let executable = lookup_file(unsafe { CStr::from_ptr(executable) }.to_str()?)?;
+
+        AddressRange::from_executable(executable, base, physical, true)?
+            .into_iter()
+            .for_each(|r| self.add_address_range(r));
+
+        Ok(())
+    }
+
+    /// Declare a routine entered at `address` which compares two buffers, like `memcmp` or
+    /// `strcmp`. When the routine is entered, the buffers pointed to by `left_register` and
+    /// `right_register` are logged as a comparison. `length_register` holds the number of
+    /// bytes compared, or is empty if the buffers are NUL-terminated strings. `name`
+    /// optionally names the routine.
+    pub fn add_cmp_routine(
+        &mut self,
+        address: u64,
+        left_register: *mut c_char,
+        right_register: *mut c_char,
+        length_register: *mut c_char,
+        name: *mut c_char,
+    ) -> Result<()> {
+        let routine = CmpRoutine {
+            name: optional_string(name)?,
+            left_register: unsafe { CStr::from_ptr(left_register) }
+                .to_str()?
+                .to_string(),
+            right_register: unsafe { CStr::from_ptr(right_register) }
+                .to_str()?
+                .to_string(),
+            length_register: optional_string(length_register)?,
+        };
+
+        self.insert_cmp_routine(address, routine)?;
+
+        Ok(())
+    }
+
+    /// Declare a routine which compares two buffers by its symbol in an ELF or PE file loaded
+    /// at `base`. For ELF files, `base` is added to the symbol's value and should be 0 for
+    /// executables which are not relocated. For PE files, the symbol must be exported and
+    /// `base` is the address the image was loaded at. The registers are as for
+    /// `add_cmp_routine`.
+    pub fn add_cmp_routine_symbol(
+        &mut self,
+        executable: *mut c_char,
+        symbol: *mut c_char,
+        base: u64,
+        left_register: *mut c_char,
+        right_register: *mut c_char,
+        length_register: *mut c_char,
+    ) -> Result<()> {
+        let executable = lookup_file(unsafe { CStr::from_ptr(executable) }.to_str()?)?;
+        let symbol = unsafe { CStr::from_ptr(symbol) }.to_str()?;
+        let address = symbol_address(executable, symbol, base)?;
+
+        let routine = CmpRoutine {
+            name: Some(symbol.to_string()),
+            left_register: unsafe { CStr::from_ptr(left_register) }
+                .to_str()?
+                .to_string(),
+            right_register: unsafe { CStr::from_ptr(right_register) }
+                .to_str()?
+                .to_string(),
+            length_register: optional_string(length_register)?,
+        };
+
+        self.insert_cmp_routine(address, routine)?;
+
+        Ok(())
+    }
+
+    /// Declare an allocation routine for the heap sanitizer, entered at `entry_address` and
+    /// returning from `exit_address`, whose argument number `size_argument` (from 0) is the
+    /// size of the allocation and whose return value is a pointer to it. Routines which
+    /// return from multiple addresses are added once for each exit address.
+    pub fn add_allocator(
+        &mut self,
+        entry_address: u64,
+        exit_address: u64,
+        size_argument: u64,
+    ) -> Result<()> {
+        self.insert_allocator_routine(
+            entry_address,
+            exit_address,
+            AllocatorRoutine::Allocate {
+                size_argument: size_argument as usize,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Declare a free routine for the heap sanitizer, entered at `entry_address` and returning
+    /// from `exit_address`, whose argument number `pointer_argument` (from 0) is the pointer
+    /// to the allocation being freed. Routines which return from multiple addresses are
+    /// added once for each exit address.
+    pub fn add_deallocator(
+        &mut self,
+        entry_address: u64,
+        exit_address: u64,
+        pointer_argument: u64,
+    ) -> Result<()> {
+        self.insert_allocator_routine(
+            entry_address,
+            exit_address,
+            AllocatorRoutine::Free {
+                pointer_argument: pointer_argument as usize,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Declare a memory region named `name` from `start` up to (but not including) `end` in
+    /// which the kinds of access in `access` are solutions. `access` is made of the
+    /// characters 'r' (read), 'w' (write), and 'x' (execute), for example "wx". If `physical`
+    /// is set, the region is of physical addresses, otherwise it is of virtual addresses in
+    /// the context of the start processor. A region with the same name is replaced.
+    pub fn add_memory_region(
+        &mut self,
+        name: *mut c_char,
+        start: u64,
+        end: u64,
+        access: *mut c_char,
+        physical: bool,
+    ) -> Result<()> {
+        let region = MemoryRegion::new(
+            unsafe { CStr::from_ptr(name) }.to_str()?.to_string(),
+            start,
+            end,
+            physical,
+            RegionAccess::parse_mask(unsafe { CStr::from_ptr(access) }.to_str()?)?,
+        )?;
+
+        self.insert_memory_region(region)?;
+
+        Ok(())
+    }
+
+    /// Remove the memory region named `name` and the breakpoints set for it
+    pub fn remove_memory_region(&mut self, name: *mut c_char) -> Result<()> {
+        let name = unsafe { CStr::from_ptr(name) }.to_str()?;
+
+        if !self.delete_memory_region(name)? {
+            return Err(anyhow!("No memory region named {name}").into());
+        }
+
+        Ok(())
+    }
+
+    /// Match each line output by the console `console` while testcases run against the
+    /// regular expression `pattern`. A matching line is a solution if `solution` is set,
+    /// otherwise it stops the testcase normally as if the harness had stopped it.
+    pub fn add_console_pattern(
+        &mut self,
+        console: *mut ConfObject,
+        pattern: *mut c_char,
+        solution: bool,
+    ) -> Result<()> {
+        let pattern = unsafe { CStr::from_ptr(pattern) }.to_str()?;
+
+        self.insert_console_pattern(console, pattern, solution)?;
+
+        Ok(())
+    }
 }
@@ -2,18 +2,22 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{
+    minimize::TestcaseMinimizer,
     state::{SolutionKind, StopReason},
     ManualStartAddress, ManualStartInfo, ManualStartSize, Tsffs,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use libafl::inputs::HasBytesVec;
 use simics::{
     continue_simulation, debug, interface, lookup_file, run_alone, AsConfObject, AttrValue,
-    ConfObject, GenericAddress,
+    AttrValueType, ConfObject, GenericAddress,
 };
 use std::{
+    collections::BTreeMap,
     ffi::{c_char, CStr},
     fs::read,
+    ptr::null_mut,
+    time::SystemTime,
 };
 
 #[interface(name = "fuzz")]
@@ -57,6 +61,57 @@ impl Tsffs {
         Ok(())
     }
 
+    /// Minimize a solution testcase. The testcase is run repeatedly from the start snapshot
+    /// with blocks of bytes removed and bytes normalized, keeping only changes which still
+    /// produce the same solution as the original testcase. When no further changes can be
+    /// made, the minimized testcase is written next to the original with a `.min` extension
+    /// and the simulation is left stopped.
+    ///
+    /// Like `repro`, this can be called during configuration *or* after stopping the fuzzer.
+    pub fn minimize(&mut self, testcase_file: *mut c_char) -> Result<()> {
+        let simics_path = unsafe { CStr::from_ptr(testcase_file) }.to_str()?;
+
+        let testcase_file = lookup_file(simics_path)?;
+
+        debug!(
+            self.as_conf_object(),
+            "minimize({})",
+            testcase_file.display()
+        );
+
+        if self.repro_testcase.is_some() {
+            bail!("Cannot minimize a testcase while reproducing a testcase");
+        }
+
+        let contents = read(&testcase_file).map_err(|e| {
+            anyhow!(
+                "Failed to read testcase file to minimize {}: {}",
+                testcase_file.display(),
+                e
+            )
+        })?;
+
+        if contents.is_empty() {
+            bail!("Testcase file {} is empty", testcase_file.display());
+        }
+
+        self.minimizer = Some(TestcaseMinimizer::new(testcase_file, contents));
+
+        if self.iterations > 0 {
+            // We've done an iteration already, so we need to reset and run
+            self.restore_initial_snapshot()?;
+            self.get_and_write_testcase()?;
+            self.post_timeout_event()?;
+
+            run_alone(|| {
+                continue_simulation(0)?;
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
     /// Interface method to manually start the fuzzing loop by taking a snapshot, saving the
     /// testcase and size address and resuming execution of the simulation. This method does
     /// not need to be called if `set_start_on_harness` is enabled.
@@ -242,9 +297,75 @@ impl Tsffs {
         debug!(self.as_conf_object(), "solution({id:#x}, {message})");
 
         self.stop_simulation(StopReason::Solution {
-            kind: SolutionKind::Manual,
+            kind: SolutionKind::Manual {
+                id,
+                message: message.to_string(),
+            },
+            processor: null_mut(),
         })?;
 
         Ok(())
     }
+
+    /// Interface method to query the status of the campaign.
+    ///
+    /// # Return Value
+    ///
+    /// Returns an [`AttrValue`] dictionary with the keys:
+    ///
+    /// * `iterations` - The number of testcases executed
+    /// * `elapsed` - The number of seconds since fuzzing started
+    /// * `execs_per_sec` - The average number of executions per second
+    /// * `corpus_size` - The number of entries in the corpus
+    /// * `solutions` - A dictionary of the number of solutions found of each kind, by the
+    ///   name of the kind (for example `timeout`, `exception`, or `sanitizer`). Every kind
+    ///   is present, including kinds with no solutions.
+    /// * `edges` - The total number of edges seen
+    /// * `new_edges` - The number of edges seen since the status was last queried
+    /// * `fuzzer_running` - Whether the fuzzer thread is running
+    pub fn get_status(&mut self) -> Result<AttrValue> {
+        let elapsed = self
+            .start_time
+            .get()
+            .map(|t| SystemTime::now().duration_since(*t))
+            .transpose()?
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+
+        let solutions = SolutionKind::NAMES
+            .iter()
+            .map(|k| (*k, 0))
+            .chain(self.stats.solutions.iter().map(|(k, v)| (*k, *v)))
+            .collect::<BTreeMap<_, _>>();
+
+        let new_edges = self.edges_seen.len().saturating_sub(self.status_edges_seen);
+        self.status_edges_seen = self.edges_seen.len();
+
+        let status = BTreeMap::<AttrValueType, AttrValueType>::from([
+            ("iterations".into(), self.iterations.into()),
+            ("elapsed".into(), elapsed.into()),
+            (
+                "execs_per_sec".into(),
+                if elapsed > 0.0 {
+                    self.iterations as f64 / elapsed
+                } else {
+                    0.0
+                }
+                .into(),
+            ),
+            ("corpus_size".into(), self.stats.corpus_count.into()),
+            ("solutions".into(), solutions.into()),
+            ("edges".into(), self.edges_seen.len().into()),
+            ("new_edges".into(), new_edges.into()),
+            (
+                "fuzzer_running".into(),
+                self.fuzz_thread
+                    .get()
+                    .is_some_and(|t| !t.is_finished())
+                    .into(),
+            ),
+        ]);
+
+        Ok(AttrValueType::Dict(status).into())
+    }
 }
@@ -29,27 +29,35 @@
 #![warn(missing_docs)]
 
 use crate::interfaces::{config::config, fuzz::fuzz};
-use crate::state::SolutionKind;
+use crate::state::{SolutionKind, SolutionMetadata};
 #[cfg(simics_version_6)]
 use crate::util::Utils;
-use anyhow::{anyhow, Result};
-use arch::{Architecture, ArchitectureHint, ArchitectureOperations};
-use fuzzer::{messages::FuzzerMessage, ShutdownMessage, Testcase};
+use anyhow::{anyhow, ensure, Result};
+use arch::{Architecture, ArchitectureHint, ArchitectureOperations, CallingConvention};
+use console::ConsoleMonitor;
+use filter::AddressFilter;
+use fuzzer::{campaign::SimulatorState, messages::FuzzerMessage, PowerScheduleKind, Testcase};
+use guard::TestcaseGuardMode;
 use indoc::indoc;
 use libafl::{inputs::HasBytesVec, prelude::ExitKind};
 use libafl_bolts::prelude::OwnedMutSlice;
 use libafl_targets::AFLppCmpLogMap;
 use magic::MagicNumber;
+use metrics::CampaignMetrics;
+use minimize::{corpus::CorpusMinimizer, TestcaseMinimizer};
 use num_traits::FromPrimitive as _;
+use regions::MemoryRegion;
+use routines::CmpRoutine;
+use sanitizer::Sanitizer;
 use serde::{Deserialize, Serialize};
 use serde_json::to_writer;
 use simics::{
     break_simulation, class, debug, error, free_attribute, get_class, get_interface,
     get_processor_number, info, lookup_file, object_clock, run_command, run_python, simics_init,
-    trace, version_base, AsConfObject, BreakpointId, ClassCreate, ClassObjectsFinalize, ConfObject,
-    CoreBreakpointMemopHap, CoreExceptionHap, CoreMagicInstructionHap, CoreSimulationStoppedHap,
-    CpuInstrumentationSubscribeInterface, Event, EventClassFlag, FromConfObject, HapHandle,
-    Interface, IntoAttrValueDict,
+    sys::cpu_access_scope_t, trace, version_base, AsConfObject, BreakpointId, ClassCreate,
+    ClassObjectsFinalize, ConfObject, CoreBreakpointMemopHap, CoreExceptionHap,
+    CoreMagicInstructionHap, CoreSimulationStoppedHap, CpuInstrumentationSubscribeInterface, Event,
+    EventClassFlag, FromConfObject, HapHandle, Interface, IntoAttrValueDict,
 };
 #[cfg(simics_version_6)]
 use simics::{
@@ -60,36 +68,60 @@ use simics::{
 // which is necessary because this module is compatible with base versions which cross the
 // deprecation boundary
 use simics::{restore_snapshot, save_snapshot, sys::save_flags_t, write_configuration_to_file};
+use solutions::SolutionBucket;
 use state::StopReason;
+use stats::FuzzerStats;
 #[cfg(simics_version_7)]
 use std::fs::remove_dir_all;
 use std::{
     alloc::{alloc_zeroed, Layout},
     cell::OnceCell,
-    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
-    fs::{create_dir_all, File},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fs::{create_dir_all, remove_file, rename, File},
     hash::{DefaultHasher, Hash, Hasher},
+    io::BufWriter,
     path::PathBuf,
+    process,
     ptr::null_mut,
     str::FromStr,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
     thread::JoinHandle,
-    time::SystemTime,
+    time::{Instant, SystemTime},
 };
 use tracer::{
-    tsffs::{on_instruction_after, on_instruction_before},
-    ExecutionTrace,
+    tenet::TenetTrace,
+    tsffs::{
+        on_cached_instruction, on_instruction_after, on_instruction_before, on_read_after,
+        on_write_before,
+    },
+    CoverageMode, ExecutionTrace, ExecutionTraceFormat, HashWriter, ReturnAddressStack,
+    ShadowCallStack,
 };
+use tsffs_trace::TraceWriter;
 use typed_builder::TypedBuilder;
 use versions::{Requirement, Versioning};
 
 pub(crate) mod arch;
+pub(crate) mod console;
+pub(crate) mod filter;
 pub(crate) mod fuzzer;
+pub(crate) mod guard;
 pub(crate) mod haps;
 pub(crate) mod interfaces;
 pub(crate) mod log;
 pub(crate) mod magic;
+pub(crate) mod metrics;
+pub(crate) mod minimize;
+pub(crate) mod regions;
+pub(crate) mod routines;
+pub(crate) mod sanitizer;
+pub(crate) mod solutions;
 pub(crate) mod state;
+pub(crate) mod stats;
 pub(crate) mod tracer;
 pub(crate) mod traits;
 pub(crate) mod util;
@@ -244,6 +276,28 @@ pub(crate) struct Tsffs {
     /// $bp = (bp.memory.break -x $addr)
     /// @tsffs.breakpoints = [simenv.bp]
     pub breakpoints: BTreeSet<BreakpointId>,
+    #[class(attribute(optional, default = false))]
+    /// Whether returns which do not go back to their caller are treated as solutions. When set
+    /// to `True`, a shadow stack of the return addresses pushed by calls is kept for each
+    /// processor, and a return to any address other than the one pushed by its matching call
+    /// is a control flow integrity solution. This detects stack buffer overflows which
+    /// overwrite a return address without causing an exception.
+    pub return_address_solutions: bool,
+    #[class(attribute(optional, default = TestcaseGuardMode::None))]
+    /// The memory after the testcase buffer which is guarded against accesses. One of "none",
+    /// "end" (the `testcase_guard_size` bytes following the buffer at its maximum size), or
+    /// "unused" (the bytes of the buffer between the end of each testcase and the maximum
+    /// size, for buffers which are followed by other data). An access to guarded memory is an
+    /// out of bounds solution. The guard is set each time a testcase is written.
+    pub testcase_guard_mode: TestcaseGuardMode,
+    #[class(attribute(optional, default = 16))]
+    /// The size, in bytes, of the memory guarded after the testcase buffer when
+    /// `testcase_guard_mode` is "end", and before it when `testcase_guard_before` is set
+    pub testcase_guard_size: u64,
+    #[class(attribute(optional, default = false))]
+    /// Whether the `testcase_guard_size` bytes preceding the testcase buffer are also guarded
+    /// when `testcase_guard_mode` is not "none"
+    pub testcase_guard_before: bool,
     #[class(attribute(optional, default = 5.0))]
     /// The timeout in seconds of virtual time for each iteration of the fuzzer. If the virtual
     /// time timeout is exceeded for a single iteration, the iteration is stopped and the testcase
@@ -329,6 +383,40 @@ pub(crate) struct Tsffs {
     /// Whether coverage reporting should be enabled. When enabled, new edge addresses will
     /// be logged.
     pub coverage_reporting: bool,
+    #[class(attribute(optional, default = CoverageMode::HitCount))]
+    /// The scheme used to record coverage. One of "hit-count" (AFL-style edges with hit
+    /// counts), "once" (AFL-style edges, recording only whether each edge was hit), "block"
+    /// (basic blocks with hit counts), or "ngram" (edges over the last
+    /// `coverage_ngram_size` branch targets with hit counts).
+    pub coverage_mode: CoverageMode,
+    #[class(attribute(optional, default = 4))]
+    /// The number of branch targets hashed into each map index when `coverage_mode` is
+    /// "ngram". Must be at least 2.
+    pub coverage_ngram_size: usize,
+    #[class(attribute(optional, default = false))]
+    /// Whether coverage is context-sensitive. When enabled, a shadow call stack is kept for
+    /// each processor and a hash of the call sites on it is mixed into each coverage map
+    /// index, so the same edge reached through different callers is counted separately.
+    pub context_sensitive_coverage: bool,
+    #[class(attribute(optional, default = false))]
+    /// Whether only instructions executed in the address space the harness started in are
+    /// traced. The page table root (CR3, satp, or TTBR0) of the start processor is recorded
+    /// when the harness starts, and instructions executed while a different root is loaded,
+    /// for example by other processes, do not add coverage or comparisons.
+    pub filter_address_space: bool,
+    #[class(attribute(optional, default = false))]
+    /// Whether instructions executed in kernel mode in the harness's address space are also
+    /// traced when `filter_address_space` is enabled, for example system calls made by the
+    /// target. By default only user mode instructions are traced, unless the harness started
+    /// in kernel mode.
+    pub address_space_include_kernel: bool,
+    #[class(attribute(optional, default = false))]
+    /// Whether to trace using cached instruction instrumentation. Instead of running a
+    /// callback before and after every instruction, each instruction is classified once when
+    /// SIMICS caches it, and callbacks are registered only on branches and, when `cmplog` is
+    /// enabled, comparisons. Saving execution traces still requires a callback on every
+    /// instruction. Must be set before the fuzzer starts.
+    pub cached_instrumentation: bool,
     #[class(attribute(optional))]
     #[attr_value(fallible)]
     /// A set of executable files to tokenize. Tokens will be extracted from these files and
@@ -399,6 +487,137 @@ pub(crate) struct Tsffs {
     #[class(attribute(optional, default = false))]
     /// Whether execution traces should include just PC (vs instruction text and bytes)
     pub execution_trace_pc_only: bool,
+    #[class(attribute(optional, default = ExecutionTraceFormat::Json))]
+    /// The format to save execution traces in. One of "json" (one JSON file per trace,
+    /// accumulated in memory while the testcase runs), "binary" (a compact binary format
+    /// streamed to disk while the testcase runs, which can be read with the `tsffs-trace`
+    /// crate and converted back to JSON with its `tsffs-trace` tool), or "tenet" (the text
+    /// format of the Tenet trace explorer, with the registers changed and memory accessed by
+    /// each instruction of the start processor).
+    pub execution_trace_format: ExecutionTraceFormat,
+    #[class(attribute(optional, default = false))]
+    /// Whether to run the fuzzer in parallel mode. When set to `True`, the fuzzer connects to
+    /// an LLMP broker on `broker_port` instead of running standalone, and exchanges new corpus
+    /// entries and solutions with every other Simics instance connected to the same broker.
+    /// Exactly one instance should set `is_broker` to `True`.
+    pub parallel: bool,
+    #[class(attribute(optional, default = 1337))]
+    /// The TCP port on localhost the LLMP broker listens on in parallel mode. All instances
+    /// participating in the same campaign must use the same port.
+    pub broker_port: u16,
+    #[class(attribute(optional, default = false))]
+    /// Whether this instance hosts the LLMP broker in parallel mode. The broker instance
+    /// aggregates statistics from all connected clients into its log output and also fuzzes
+    /// as a client itself.
+    pub is_broker: bool,
+    #[class(attribute(optional, default = 0))]
+    /// The identifier of this instance in parallel mode. Each instance connected to the same
+    /// broker should use a distinct value. The identifier names this instance's statistics
+    /// directory and campaign state file, and diversifies the random seed of its mutators.
+    pub client_id: u32,
+    #[class(attribute(optional, default = false))]
+    /// Whether to persist the campaign state to `campaign_directory`. When enabled, the
+    /// fuzzer's state (scheduler, mutator, and feedback metadata), the iteration count, and
+    /// the set of edges seen are saved every `campaign_save_interval` seconds and when the
+    /// fuzzer shuts down, and a run using a campaign directory which already contains state
+    /// resumes from it instead of starting over.
+    pub persist_campaign: bool,
+    #[class(attribute(optional, default = lookup_file("%simics%")?.join("campaign")))]
+    #[attr_value(fallible)]
+    /// The directory campaign state is saved to and resumed from when `persist_campaign` is
+    /// set. This directory may be a SIMICS relative path prefixed with "%simics%". If not
+    /// provided, "%simics%/campaign" will be used by default.
+    pub campaign_directory: PathBuf,
+    #[class(attribute(optional, default = 300))]
+    /// The interval, in seconds, at which campaign state is saved when `persist_campaign` is
+    /// set
+    pub campaign_save_interval: u64,
+    #[class(attribute(optional, default = PowerScheduleKind::Explore))]
+    /// The power schedule used by the scheduler to assign energy to corpus entries. One of
+    /// "explore", "exploit", "fast", "coe", "lin", or "quad". See the AFLFast paper for a
+    /// description of each schedule.
+    pub power_schedule: PowerScheduleKind,
+    #[class(attribute(optional, default = true))]
+    /// Whether to run the generalization stage, which tracks which parts of interesting
+    /// inputs are necessary to reach their new coverage
+    pub use_generalization_stage: bool,
+    #[class(attribute(optional, default = true))]
+    /// Whether to run the MOpt mutational stage in addition to the havoc mutational stage
+    pub use_mopt_stage: bool,
+    #[class(attribute(optional, default = true))]
+    /// Whether to run the colorization, AFL++ cmplog tracing, and redqueen stages. These
+    /// stages only run when `cmplog` is also enabled.
+    pub use_redqueen_stage: bool,
+    #[class(attribute(optional, default = true))]
+    /// Whether to run the comparison tracing and input-to-state replacement stages. These
+    /// stages only run when `cmplog` is also enabled.
+    pub use_input_to_state_stage: bool,
+    #[class(attribute(optional, default = 7))]
+    /// The maximum power of two of the number of stacked mutations the MOpt mutator applies
+    /// to a testcase. Must be at least 1.
+    pub mopt_max_stack_pow: usize,
+    #[class(attribute(optional, default = 5))]
+    /// The number of swarms (particles) used by the MOpt mutator's particle swarm
+    /// optimization. Must be at least 1.
+    pub mopt_swarm_num: usize,
+    #[class(attribute(optional, default = false))]
+    /// Whether to minimize the corpus instead of fuzzing. When set to `True`, each entry in
+    /// `corpus_directory` is run once from the start snapshot, and the smallest and fastest
+    /// set of entries which covers every map index covered by the full corpus is written to
+    /// `minimized_corpus_directory`. Entries which produce a solution are excluded. Once
+    /// minimization is finished, SIMICS exits if `quit_on_iteration_limit` is set.
+    pub corpus_minimization: bool,
+    #[class(attribute(optional, default = lookup_file("%simics%")?.join("corpus-minimized")))]
+    #[attr_value(fallible)]
+    /// The directory to save the minimized corpus to when `corpus_minimization` is set. This
+    /// directory may be a SIMICS relative path prefixed with "%simics%". If not provided,
+    /// "%simics%/corpus-minimized" will be used by default.
+    pub minimized_corpus_directory: PathBuf,
+    #[class(attribute(optional, default = false))]
+    /// Whether to deduplicate solutions into buckets by their signature: the kind of solution
+    /// (including the exception number, breakpoint ID, or manual solution ID), the program
+    /// counter when the simulation stopped (except for timeouts), and a hash of the last
+    /// `bucket_edge_history` edges executed. Each bucket is saved in its own directory in
+    /// `solutions_directory/buckets`, along with a summary index file `index.json`. Only the
+    /// smallest solution in each bucket is kept, both in its bucket and in the solutions
+    /// directory, but every solution is counted.
+    pub bucket_solutions: bool,
+    #[class(attribute(optional, default = 8))]
+    /// The number of most recently executed edges included in the signature of a solution
+    /// when `bucket_solutions` is set
+    pub bucket_edge_history: usize,
+    #[class(attribute(optional, default = false))]
+    /// Whether to write AFL++-compatible `fuzzer_stats` and `plot_data` files to
+    /// `stats_directory`, which tools like `afl-whatsup` and `afl-plot` can read
+    pub stats_to_file: bool,
+    #[class(attribute(optional, default = lookup_file("%simics%")?.join("stats")))]
+    #[attr_value(fallible)]
+    /// The directory statistics are written to when `stats_to_file` is set. Like an AFL++
+    /// output directory, each instance writes to its own subdirectory: `default`, or
+    /// `client-<client_id>` in parallel mode.
+    pub stats_directory: PathBuf,
+    #[class(attribute(optional, default = 5))]
+    /// The interval, in seconds, at which statistics are written when `stats_to_file` is set
+    pub stats_interval: u64,
+    #[class(attribute(optional, default = false))]
+    /// Whether to serve the status of the campaign over HTTP on localhost on `metrics_port`
+    /// once fuzzing starts. The status is served as Prometheus metrics at `/metrics` and as
+    /// JSON on any other path.
+    pub metrics_server: bool,
+    #[class(attribute(optional, default = 9180))]
+    /// The TCP port on localhost the metrics server listens on when `metrics_server` is set
+    pub metrics_port: u16,
+    #[class(attribute(optional, default = 16))]
+    /// The size, in bytes, of the redzones guarded before and after each live allocation
+    /// tracked by the heap sanitizer. Accesses to a redzone outside of the allocator are
+    /// reported as heap buffer overflows. A size of 0 disables overflow detection.
+    pub sanitizer_redzone_size: u64,
+    #[class(attribute(optional, default = CallingConvention::Standard))]
+    /// The calling convention used to read the arguments of the allocator routines tracked by
+    /// the heap sanitizer. One of "standard" (the standard convention of the architecture) or
+    /// "microsoft" (the Microsoft x64 convention used by UEFI and Windows targets). The
+    /// Microsoft convention is only supported on x86-64 and i386 processors.
+    pub sanitizer_calling_convention: CallingConvention,
 
     #[attr_value(skip)]
     /// Handle for the core simulation stopped hap
@@ -422,21 +641,63 @@ pub(crate) struct Tsffs {
     /// CPU core is not known at the time the fuzzer is started. Specifically, x86 cores which
     /// report their architecture as x86_64 can be overridden to x86.
     pub architecture_hints: HashMap<i32, ArchitectureHint>,
+    #[attr_value(skip)]
+    /// Ranges of addresses restricting which instructions are traced for coverage and
+    /// comparisons. Ranges are added with the config interface.
+    address_filter: AddressFilter,
+    #[attr_value(skip)]
+    /// The page table root of the start processor when the harness started, if
+    /// `filter_address_space` is enabled
+    address_space_root: Option<u64>,
+    #[attr_value(skip)]
+    /// Whether the start processor was in kernel mode when the harness started. Kernel mode
+    /// execution in the recorded address space is then always traced, because the target
+    /// itself runs in kernel mode.
+    address_space_started_in_kernel: bool,
+    #[attr_value(skip)]
+    /// Routines which compare buffers, by the address they are entered at. The buffers they
+    /// compare are logged when `cmplog` is enabled. Routines are added with the config
+    /// interface.
+    cmp_routines: HashMap<u64, CmpRoutine>,
+    #[attr_value(skip)]
+    /// The allocator routines and the allocations tracked by the heap sanitizer. Routines
+    /// are added with the config interface, and allocations are cleared every execution.
+    sanitizer: Sanitizer,
+    #[attr_value(skip)]
+    /// The breakpoints guarding the testcase buffer, which are set each time a testcase is
+    /// written
+    testcase_guard_breakpoints: Vec<BreakpointId>,
+    #[attr_value(skip)]
+    /// Named memory regions in which some kinds of access are solutions. Regions are added
+    /// with the config interface, and their breakpoints are set once the start processor is
+    /// known.
+    memory_regions: Vec<MemoryRegion>,
+    #[attr_value(skip)]
+    /// The consoles whose output is matched against patterns while testcases run. Patterns
+    /// are added with the config interface.
+    console_monitors: Vec<ConsoleMonitor>,
     // Threads and message channels
     #[attr_value(skip)]
     /// Fuzzer thread
     fuzz_thread: OnceCell<JoinHandle<Result<()>>>,
     #[attr_value(skip)]
+    /// LLMP broker thread, only present in parallel mode on the broker instance
+    broker_thread: OnceCell<JoinHandle<Result<()>>>,
+    #[attr_value(skip)]
     /// Message sender to the fuzzer thread. TSFFS sends exit kinds to the fuzzer thread to
     /// report whether testcases resulted in normal exit, timeout, or solutions.
     fuzzer_tx: OnceCell<Sender<ExitKind>>,
     #[attr_value(skip)]
+    /// Message sender to the fuzzer thread. TSFFS sends the details of each solution to the
+    /// fuzzer thread before its exit kind, to be saved as metadata of the solution.
+    fuzzer_solution_metadata: OnceCell<Sender<SolutionMetadata>>,
+    #[attr_value(skip)]
     /// Message receiver from the fuzzer thread. TSFFS receives new testcases and run configuration
     /// from the fuzzer thread.
     fuzzer_rx: OnceCell<Receiver<Testcase>>,
     #[attr_value(skip)]
-    /// A message sender to inform the fuzzer thread that it should exit.
-    fuzzer_shutdown: OnceCell<Sender<ShutdownMessage>>,
+    /// A flag shared with the fuzzer thread, set to inform it that it should exit.
+    fuzzer_shutdown: OnceCell<Arc<AtomicBool>>,
     #[attr_value(skip)]
     /// Reciever from the fuzzer thread to receive messages from the fuzzer thread
     /// including status messages and structured introspection data like new edge findings.
@@ -456,6 +717,22 @@ pub(crate) struct Tsffs {
     /// The previous location for coverage for calculating the hash of edges.
     coverage_prev_loc: u64,
     #[attr_value(skip)]
+    /// The previous locations hashed into the map index in n-gram coverage mode. This is
+    /// cleared every execution.
+    coverage_ngram_history: VecDeque<u64>,
+    #[attr_value(skip)]
+    /// The shadow call stack of each processor, by processor number, used for
+    /// context-sensitive coverage. This is cleared every execution.
+    shadow_call_stacks: HashMap<i32, ShadowCallStack>,
+    #[attr_value(skip)]
+    /// A hash of the configuration cached instructions are instrumented with, set each time
+    /// the instruction caches are flushed when `cached_instrumentation` is enabled
+    cached_instrumentation_key: Option<u64>,
+    #[attr_value(skip)]
+    /// The return address stack of each processor, by processor number, used to detect
+    /// corrupted return addresses. This is cleared every execution.
+    return_address_stacks: HashMap<i32, ReturnAddressStack>,
+    #[attr_value(skip)]
     /// The registered timeout event which is registered and used to detect timeouts in
     /// virtual time
     timeout_event: OnceCell<Event>,
@@ -469,6 +746,24 @@ pub(crate) struct Tsffs {
     #[attr_value(skip)]
     /// The set of PCs comprising the current execution trace. This is cleared every execution.
     execution_trace: ExecutionTrace,
+    #[attr_value(skip)]
+    /// The writer of the current binary execution trace, opened when the first instruction
+    /// of an execution is traced. This is discarded every execution unless it is saved.
+    execution_trace_writer: Option<TraceWriter<HashWriter<BufWriter<File>>>>,
+    #[attr_value(skip)]
+    /// The current Tenet execution trace, opened when the first instruction of an execution
+    /// is traced. This is discarded every execution unless it is saved.
+    tenet_trace: Option<TenetTrace>,
+    #[attr_value(skip)]
+    /// The most recent edges in the current execution as (source, target) pairs, used to
+    /// bucket solutions. This is cleared every execution.
+    recent_edges: VecDeque<(u64, u64)>,
+    #[attr_value(skip)]
+    /// The solution buckets, loaded when the first solution is bucketed
+    solution_buckets: Option<BTreeMap<String, SolutionBucket>>,
+    #[attr_value(skip)]
+    /// The contents of the testcase currently executing, saved when bucketing solutions
+    current_testcase: Option<Vec<u8>>,
 
     #[attr_value(skip)]
     /// The name of the fuzz snapshot, if saved
@@ -516,11 +811,39 @@ pub(crate) struct Tsffs {
     /// Whether the fuzzer is currently stopped in repro mode
     stopped_for_repro: bool,
     #[attr_value(skip)]
+    /// The solution testcase being minimized, if in minimization mode
+    minimizer: Option<TestcaseMinimizer>,
+    #[attr_value(skip)]
+    /// The corpus being minimized, if in corpus minimization mode
+    corpus_minimizer: Option<CorpusMinimizer>,
+    #[attr_value(skip)]
     /// The number of iterations which have been executed so far
     iterations: usize,
     #[attr_value(skip)]
     /// Whether snapshots are used. Snapshots are used on Simics 7.0.0 and later.
     use_snapshots: bool,
+    #[attr_value(skip)]
+    /// The last time the simulator side of the campaign state was saved
+    campaign_last_saved: Option<Instant>,
+    #[attr_value(skip)]
+    /// The simulator side of the campaign state, snapshotted at the same iteration boundary
+    /// as the last snapshot of the fuzzer side
+    campaign_snapshot: Option<SimulatorState>,
+    #[attr_value(skip)]
+    /// Statistics written to `stats_directory`
+    stats: FuzzerStats,
+    #[attr_value(skip)]
+    /// When a new edge was last seen
+    last_new_edge: Option<SystemTime>,
+    #[attr_value(skip)]
+    /// Metrics shared with the metrics server thread
+    metrics: Arc<Mutex<CampaignMetrics>>,
+    #[attr_value(skip)]
+    /// Metrics server thread, only present when `metrics_server` is set
+    metrics_thread: OnceCell<JoinHandle<()>>,
+    #[attr_value(skip)]
+    /// The number of edges seen when the status was last queried
+    status_edges_seen: usize,
 }
 
 impl ClassObjectsFinalize for Tsffs {
@@ -667,27 +990,95 @@ impl Tsffs {
             };
             e.insert(architecture);
             let mut cpu_interface: CpuInstrumentationSubscribeInterface = get_interface(cpu)?;
-            cpu_interface.register_instruction_after_cb(
-                null_mut(),
-                Some(on_instruction_after),
-                self as *mut Self as *mut _,
-            )?;
-            cpu_interface.register_instruction_before_cb(
-                null_mut(),
-                Some(on_instruction_before),
-                self as *mut Self as *mut _,
-            )?;
+            if self.cached_instrumentation {
+                // Callbacks are registered on individual instructions as they are cached
+                cpu_interface.register_cached_instruction_cb(
+                    null_mut(),
+                    Some(on_cached_instruction),
+                    self as *mut Self as *mut _,
+                )?;
+            } else {
+                cpu_interface.register_instruction_after_cb(
+                    null_mut(),
+                    Some(on_instruction_after),
+                    self as *mut Self as *mut _,
+                )?;
+                cpu_interface.register_instruction_before_cb(
+                    null_mut(),
+                    Some(on_instruction_before),
+                    self as *mut Self as *mut _,
+                )?;
+            }
+
+            if self.execution_trace_format == ExecutionTraceFormat::Tenet {
+                cpu_interface.register_read_after_cb(
+                    null_mut(),
+                    cpu_access_scope_t::CPU_Access_Scope_Explicit,
+                    Some(on_read_after),
+                    self as *mut Self as *mut _,
+                )?;
+                cpu_interface.register_write_before_cb(
+                    null_mut(),
+                    cpu_access_scope_t::CPU_Access_Scope_Explicit,
+                    Some(on_write_before),
+                    self as *mut Self as *mut _,
+                )?;
+            }
+
+            self.validate_cmp_routines(cpu_number)?;
+
+            ensure!(
+                self.processors.get(&cpu_number).is_some_and(
+                    |p| p.supports_calling_convention(self.sanitizer_calling_convention)
+                ),
+                "Processor {cpu_number} does not support the {} calling convention",
+                self.sanitizer_calling_convention
+            );
         }
 
         if is_start {
             self.start_processor_number
                 .set(cpu_number)
                 .map_err(|_| anyhow!("Start processor number already set"))?;
+            self.arm_memory_regions()?;
         }
 
         Ok(())
     }
 
+    /// Collect the details of a solution which just stopped the simulation on `processor`, or on
+    /// the start processor if `processor` is null or not a tracked processor
+    pub fn solution_metadata(
+        &mut self,
+        kind: SolutionKind,
+        processor: *mut ConfObject,
+    ) -> Result<SolutionMetadata> {
+        let processor_number = if processor.is_null() {
+            None
+        } else {
+            get_processor_number(processor)
+                .ok()
+                .filter(|n| self.processors.contains_key(n))
+        };
+
+        let processor_number = processor_number
+            .or_else(|| self.start_processor_number.get().cloned())
+            .ok_or_else(|| anyhow!("No start processor number"))?;
+
+        let processor = self
+            .processors
+            .get_mut(&processor_number)
+            .ok_or_else(|| anyhow!("Processor {processor_number} not found"))?;
+
+        Ok(SolutionMetadata {
+            kind,
+            processor_number,
+            pc: processor.processor_info_v2().get_program_counter()?,
+            virtual_time: processor.cycle().get_time()?,
+            duplicate: false,
+        })
+    }
+
     /// Return a reference to the saved "start processor" if there is one. There will be no
     /// "start processor" before a start harness (manual or magic) is executed.
     pub fn start_processor(&mut self) -> Option<&mut Architecture> {
@@ -761,6 +1152,14 @@ impl Tsffs {
     /// Restore the initial snapshot using the configured method (either rev-exec micro checkpoints
     /// or snapshots)
     pub fn restore_initial_snapshot(&mut self) -> Result<()> {
+        self.recent_edges.clear();
+        self.coverage_ngram_history.clear();
+        self.shadow_call_stacks.clear();
+        self.return_address_stacks.clear();
+        self.reset_sanitizer()?;
+        self.disarm_testcase_guard()?;
+        self.reset_console_monitors();
+
         #[cfg(simics_version_7)]
         restore_snapshot(Self::SNAPSHOT_NAME)?;
         #[cfg(simics_version_6)]
@@ -802,6 +1201,8 @@ impl Tsffs {
 impl Tsffs {
     /// Get a testcase from the fuzzer and write it to memory along with, optionally, a size
     pub fn get_and_write_testcase(&mut self) -> Result<()> {
+        self.flush_cached_instrumentation_if_changed()?;
+
         let testcase = self.get_testcase()?;
 
         // TODO: Fix cloning - refcell?
@@ -817,6 +1218,8 @@ impl Tsffs {
 
         start_processor.write_start(testcase.testcase.bytes(), &start_info)?;
 
+        self.arm_testcase_guard(testcase.testcase.bytes().len())?;
+
         Ok(())
     }
 
@@ -851,6 +1254,7 @@ impl Tsffs {
                     tsffs
                         .stop_simulation(StopReason::Solution {
                             kind: SolutionKind::Timeout,
+                            processor: obj,
                         })
                         .expect("Error calling timeout callback");
                 },
@@ -893,8 +1297,78 @@ impl Tsffs {
         Ok(())
     }
 
+    /// The path the binary execution trace of the current execution is written to until it
+    /// is saved or discarded
+    fn current_execution_trace_path(&self) -> PathBuf {
+        self.execution_trace_directory.join(format!(
+            ".current-{}.{}",
+            process::id(),
+            self.execution_trace_format.extension()
+        ))
+    }
+
+    /// Start writing the binary or Tenet execution trace of the current execution
+    pub fn open_execution_trace(&mut self) -> Result<()> {
+        if !self.execution_trace_directory.is_dir() {
+            create_dir_all(&self.execution_trace_directory)?;
+        }
+
+        let trace_file = File::create(self.current_execution_trace_path())?;
+
+        if self.execution_trace_format == ExecutionTraceFormat::Tenet {
+            self.tenet_trace = Some(TenetTrace::new(trace_file));
+        } else {
+            self.execution_trace_writer = Some(TraceWriter::new(
+                HashWriter::new(BufWriter::new(trace_file)),
+                self.execution_trace_pc_only,
+            )?);
+        }
+
+        Ok(())
+    }
+
+    /// Discard the execution trace of the current execution
+    pub fn reset_execution_trace(&mut self) -> Result<()> {
+        self.execution_trace.0.clear();
+
+        let binary_open = self.execution_trace_writer.take().is_some();
+        let tenet_open = self.tenet_trace.take().is_some();
+
+        if binary_open || tenet_open {
+            remove_file(self.current_execution_trace_path())?;
+        }
+
+        Ok(())
+    }
+
     /// Save the current execution trace to a file
     pub fn save_execution_trace(&mut self) -> Result<()> {
+        if self.execution_trace_format != ExecutionTraceFormat::Json {
+            // No trace is open if no instruction was traced since the last one was saved
+            let hash = if let Some(writer) = self.execution_trace_writer.take() {
+                writer.finish()?.finish()?
+            } else if let Some(tenet_trace) = self.tenet_trace.take() {
+                tenet_trace.finish()?
+            } else {
+                return Ok(());
+            };
+
+            let current_path = self.current_execution_trace_path();
+            let trace_path = self.execution_trace_directory.join(format!(
+                "{:x}.{}",
+                hash,
+                self.execution_trace_format.extension()
+            ));
+
+            if trace_path.exists() {
+                remove_file(current_path)?;
+            } else {
+                rename(current_path, trace_path)?;
+            }
+
+            return Ok(());
+        }
+
         let mut hasher = DefaultHasher::new();
         self.execution_trace.hash(&mut hasher);
         let hash = hasher.finish();
@@ -903,9 +1377,11 @@ impl Tsffs {
             create_dir_all(&self.execution_trace_directory)?;
         }
 
-        let trace_path = self
-            .execution_trace_directory
-            .join(format!("{:x}.json", hash));
+        let trace_path = self.execution_trace_directory.join(format!(
+            "{:x}.{}",
+            hash,
+            self.execution_trace_format.extension()
+        ));
 
         if !trace_path.exists() {
             let trace_file = File::create(trace_path)?;
@@ -950,4 +1426,33 @@ fn init() {
         e
     })
     .expect("Failed to run python");
+    run_python(indoc! {r#"
+        def minimize_tsffs_solution_cmd(testcase):
+            tsffs_objects = list(SIM_object_iterator_for_class("tsffs"))
+
+            if not tsffs_objects:
+                raise CliError("TSFFS is not initialized. Initialize it with init-tsffs first.")
+
+            try:
+                tsffs_objects[0].iface.fuzz.minimize(testcase)
+            except Exception as e:
+                raise CliError(f"Failed to minimize {testcase}: {e}")
+    "#})
+    .expect("Failed to run python");
+    run_python(indoc! {r#"
+        new_command(
+            "minimize-tsffs-solution",
+            minimize_tsffs_solution_cmd,
+            [arg(filename_t(exist = True), "testcase")],
+            type = ["Fuzzing"],
+            see_also = ["init-tsffs"],
+            short = "Minimize a solution testcase",
+            doc = "Minimize the solution testcase <arg>testcase</arg> by repeatedly running shrunken versions of it from the start snapshot, keeping only those which produce the same solution. The minimized testcase is saved next to the original with a .min extension."
+        )
+    "#})
+    .map_err(|e| {
+        error!("{e}");
+        e
+    })
+    .expect("Failed to run python");
 }
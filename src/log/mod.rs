@@ -42,7 +42,12 @@ impl Tsffs {
                     info!(self.as_conf_object(), "Fuzzer message: {s}");
                     self.log(LogMessage::Message(s.clone()))?;
                 }
+                FuzzerMessage::Stats { corpus_count } => {
+                    self.stats.corpus_count = *corpus_count;
+                }
                 FuzzerMessage::Interesting { indices, input } => {
+                    self.stats.record_find(indices);
+
                     info!(
                         self.as_conf_object(),
                         "Interesting input for AFL indices {indices:?} with input {input:?}"
@@ -0,0 +1,256 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Local HTTP server exposing the status of a running campaign
+//!
+//! The simulator thread copies the values it tracks into a shared [`CampaignMetrics`] each
+//! time the simulation stops, and a server thread answers requests on localhost from that
+//! copy, so serving a request never touches the simulator. `/metrics` serves the Prometheus
+//! text exposition format and any other path serves JSON.
+
+use crate::{state::StopReason, Tsffs};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use simics::{info, AsConfObject};
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::spawn,
+    time::{Duration, SystemTime},
+};
+
+/// How long the server waits for a client to send its request or accept the response before
+/// dropping the connection, so a client which never sends anything cannot stall the server
+const METRICS_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default)]
+/// The values served by the metrics server, shared between the simulator thread and the
+/// server thread
+pub(crate) struct CampaignMetrics {
+    pub iterations: usize,
+    pub corpus_count: usize,
+    pub solutions: usize,
+    pub timeouts: usize,
+    pub edges_seen: usize,
+    pub stop_reason: Option<&'static str>,
+    pub start_time: Option<SystemTime>,
+    pub last_new_edge: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// The status of the campaign at the time of a request
+struct CampaignStatus {
+    iterations: usize,
+    execs_per_sec: f64,
+    corpus_count: usize,
+    solutions: usize,
+    timeouts: usize,
+    edges_seen: usize,
+    stop_reason: Option<&'static str>,
+    seconds_since_last_new_edge: Option<f64>,
+}
+
+impl CampaignMetrics {
+    fn status(&self) -> CampaignStatus {
+        let now = SystemTime::now();
+        let elapsed = |time: Option<SystemTime>| {
+            time.and_then(|t| now.duration_since(t).ok())
+                .map(|d| d.as_secs_f64())
+        };
+
+        CampaignStatus {
+            iterations: self.iterations,
+            execs_per_sec: elapsed(self.start_time)
+                .filter(|s| *s > 0.0)
+                .map(|s| self.iterations as f64 / s)
+                .unwrap_or_default(),
+            corpus_count: self.corpus_count,
+            solutions: self.solutions,
+            timeouts: self.timeouts,
+            edges_seen: self.edges_seen,
+            stop_reason: self.stop_reason,
+            seconds_since_last_new_edge: elapsed(self.last_new_edge),
+        }
+    }
+}
+
+impl CampaignStatus {
+    /// Format the status in the Prometheus text exposition format
+    fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+
+        [
+            (
+                "tsffs_iterations_total",
+                "counter",
+                "Testcase executions run",
+                self.iterations as f64,
+            ),
+            (
+                "tsffs_execs_per_second",
+                "gauge",
+                "Average testcase executions per second",
+                self.execs_per_sec,
+            ),
+            (
+                "tsffs_corpus_count",
+                "gauge",
+                "Entries in the corpus",
+                self.corpus_count as f64,
+            ),
+            (
+                "tsffs_solutions_total",
+                "counter",
+                "Solutions found, excluding timeouts",
+                self.solutions as f64,
+            ),
+            (
+                "tsffs_timeouts_total",
+                "counter",
+                "Timeouts found",
+                self.timeouts as f64,
+            ),
+            (
+                "tsffs_edges_seen",
+                "gauge",
+                "Distinct edges seen",
+                self.edges_seen as f64,
+            ),
+            (
+                "tsffs_seconds_since_last_new_edge",
+                "gauge",
+                "Seconds since a new edge was last seen",
+                self.seconds_since_last_new_edge.unwrap_or(f64::NAN),
+            ),
+        ]
+        .iter()
+        .for_each(|(name, kind, help, value)| {
+            let _ = write!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        });
+
+        if let Some(reason) = self.stop_reason {
+            let _ = write!(
+                text,
+                "# HELP tsffs_stop_reason The reason for the most recent stop\n# TYPE tsffs_stop_reason gauge\ntsffs_stop_reason{{reason=\"{reason}\"}} 1\n"
+            );
+        }
+
+        text
+    }
+}
+
+/// A short name for a stop reason
+fn stop_reason_name(reason: &StopReason) -> &'static str {
+    match reason {
+        StopReason::Magic { .. } => "magic",
+        StopReason::ManualStart { .. } => "manual_start",
+        StopReason::ManualStartWithoutBuffer { .. } => "manual_start_without_buffer",
+        StopReason::ManualStop => "manual_stop",
+        StopReason::Solution { kind, .. } => kind.name(),
+    }
+}
+
+/// Answer a single request with the current metrics
+fn serve(mut stream: TcpStream, metrics: &Mutex<CampaignMetrics>) -> Result<()> {
+    stream.set_read_timeout(Some(METRICS_CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(METRICS_CONNECTION_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the request headers
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let status = metrics
+        .lock()
+        .map_err(|_| anyhow!("Metrics lock poisoned"))?
+        .status();
+
+    let (content_type, body) = if path == "/metrics" {
+        ("text/plain; version=0.0.4", status.to_prometheus())
+    } else {
+        ("application/json", serde_json::to_string(&status)?)
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+
+    Ok(())
+}
+
+impl Tsffs {
+    /// Start the metrics server on `metrics_port` on localhost, if it is not already running
+    pub fn start_metrics_server(&mut self) -> Result<()> {
+        if self.metrics_thread.get().is_some() {
+            return Ok(());
+        }
+
+        let listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, self.metrics_port)).map_err(|e| {
+                anyhow!(
+                    "Couldn't start metrics server on port {}: {e}",
+                    self.metrics_port
+                )
+            })?;
+
+        info!(
+            self.as_conf_object(),
+            "Serving campaign metrics on http://{}",
+            listener.local_addr()?
+        );
+
+        let metrics = self.metrics.clone();
+
+        self.metrics_thread
+            .set(spawn(move || {
+                listener.incoming().for_each(|stream| {
+                    if let Err(e) = stream
+                        .map_err(|e| anyhow!("{e}"))
+                        .and_then(|stream| serve(stream, &metrics))
+                    {
+                        eprintln!("Error serving metrics request: {e}");
+                    }
+                });
+            }))
+            .map_err(|_| anyhow!("Metrics thread already set"))?;
+
+        Ok(())
+    }
+
+    /// Copy the current values tracked by the module into the metrics served by the server
+    pub fn update_metrics(&mut self) -> Result<()> {
+        if !self.metrics_server {
+            return Ok(());
+        }
+
+        let mut metrics = self
+            .metrics
+            .lock()
+            .map_err(|_| anyhow!("Metrics lock poisoned"))?;
+
+        metrics.iterations = self.iterations;
+        metrics.corpus_count = self.stats.corpus_count;
+        metrics.solutions = self.stats.crashes;
+        metrics.timeouts = self.stats.hangs;
+        metrics.edges_seen = self.edges_seen.len();
+        metrics.stop_reason = self.stop_reason.as_ref().map(stop_reason_name);
+        metrics.start_time = self.start_time.get().cloned();
+        metrics.last_new_edge = self.last_new_edge;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimization of the corpus
+//!
+//! Every entry in the corpus directory is run once from the start snapshot and the AFL map
+//! indices it covers are recorded. A subset of entries covering every index covered by the
+//! full corpus is then selected, preferring smaller and faster entries, and copied to the
+//! minimized corpus directory.
+
+use crate::{state::SolutionKind, Tsffs};
+use anyhow::{anyhow, bail, Result};
+use libafl_bolts::{AsMutSlice, AsSlice};
+use simics::{
+    api::{continue_simulation, quit, run_alone, set_log_level, LogLevel},
+    debug, info, warn, AsConfObject,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{copy, create_dir_all, read, read_dir},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+/// The coverage of a single corpus entry
+struct CorpusEntryCoverage {
+    path: PathBuf,
+    size: usize,
+    duration: Duration,
+    indices: BTreeSet<usize>,
+}
+
+#[derive(Debug, Clone)]
+/// State of an in-progress corpus minimization
+pub(crate) struct CorpusMinimizer {
+    /// The corpus entries, in the order they are run
+    entries: Vec<PathBuf>,
+    /// The index in `entries` of the entry currently being executed
+    current: usize,
+    /// The contents of the entry currently being executed
+    contents: Vec<u8>,
+    /// When the execution of the current entry started
+    execution_start: Instant,
+    /// Coverage of each entry which ran to a normal stop
+    coverage: Vec<CorpusEntryCoverage>,
+    /// The number of entries which produced a solution and are excluded
+    solutions: usize,
+}
+
+impl CorpusMinimizer {
+    /// Create a minimizer for every file in `corpus_directory`. Hidden files, which LibAFL
+    /// uses for corpus metadata, are skipped.
+    pub fn new(corpus_directory: &Path) -> Result<Self> {
+        let mut entries = read_dir(corpus_directory)
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to read corpus directory {}: {e}",
+                    corpus_directory.display()
+                )
+            })?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.is_file()
+                    && !p
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .is_some_and(|n| n.starts_with('.'))
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort();
+
+        let Some(first) = entries.first() else {
+            bail!(
+                "Corpus directory {} is empty, nothing to minimize",
+                corpus_directory.display()
+            );
+        };
+
+        Ok(Self {
+            contents: read(first)?,
+            entries,
+            current: 0,
+            execution_start: Instant::now(),
+            coverage: Vec::new(),
+            solutions: 0,
+        })
+    }
+
+    /// The path of the entry currently being executed
+    fn current_entry(&self) -> &Path {
+        &self.entries[self.current]
+    }
+
+    /// The contents of the entry to execute next. Called when the entry is written to the
+    /// target, so this also starts timing the execution.
+    pub fn candidate(&mut self) -> &[u8] {
+        self.execution_start = Instant::now();
+        &self.contents
+    }
+
+    /// Record the outcome and coverage of the current entry and move to the next. Returns
+    /// `true` once every entry has been run.
+    fn record(&mut self, outcome: Option<&SolutionKind>, coverage_map: &[u8]) -> Result<bool> {
+        if outcome.is_some() {
+            self.solutions += 1;
+        } else {
+            self.coverage.push(CorpusEntryCoverage {
+                path: self.current_entry().to_path_buf(),
+                size: self.contents.len(),
+                duration: self.execution_start.elapsed(),
+                indices: coverage_map
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, c)| (*c != 0).then_some(i))
+                    .collect(),
+            });
+        }
+
+        self.current += 1;
+
+        if let Some(next) = self.entries.get(self.current) {
+            self.contents = read(next)?;
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Select the entries to keep. For each covered index, the smallest (and among those the
+    /// fastest) entry covering it is the best entry for that index. Walking the indices in
+    /// order, the best entry of each index not yet covered by a selected entry is selected.
+    fn select(&self) -> Vec<&CorpusEntryCoverage> {
+        let mut best = BTreeMap::<usize, &CorpusEntryCoverage>::new();
+
+        self.coverage.iter().for_each(|entry| {
+            entry.indices.iter().for_each(|index| {
+                best.entry(*index)
+                    .and_modify(|b| {
+                        if (entry.size, entry.duration) < (b.size, b.duration) {
+                            *b = entry;
+                        }
+                    })
+                    .or_insert(entry);
+            })
+        });
+
+        let mut covered = BTreeSet::new();
+        let mut selected = Vec::new();
+
+        best.iter().for_each(|(index, entry)| {
+            if !covered.contains(index) {
+                covered.extend(entry.indices.iter().cloned());
+                selected.push(*entry);
+            }
+        });
+
+        selected
+    }
+}
+
+impl Tsffs {
+    /// Begin corpus minimization in place of starting the fuzzer
+    pub fn start_corpus_minimization(&mut self) -> Result<()> {
+        if self.corpus_minimizer.is_some() {
+            return Ok(());
+        }
+
+        let minimizer = CorpusMinimizer::new(&self.corpus_directory)?;
+
+        info!(
+            self.as_conf_object(),
+            "Minimizing corpus of {} entries from {}",
+            minimizer.entries.len(),
+            self.corpus_directory.display()
+        );
+
+        self.corpus_minimizer = Some(minimizer);
+
+        Ok(())
+    }
+
+    /// Called when an execution of a corpus entry finishes with the solution it produced, if
+    /// any. Either starts the next entry or writes out the minimized corpus.
+    pub fn on_corpus_minimize_iteration(&mut self, outcome: Option<SolutionKind>) -> Result<()> {
+        if let Some(kind) = outcome.as_ref() {
+            warn!(
+                self.as_conf_object(),
+                "Corpus entry {} produced solution {kind:?}, excluding it from the minimized corpus",
+                self.corpus_minimizer
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Not minimizing the corpus"))?
+                    .current_entry()
+                    .display()
+            );
+        }
+
+        let coverage_map = self
+            .coverage_map
+            .get_mut()
+            .ok_or_else(|| anyhow!("Coverage map not set"))?;
+
+        let minimizer = self
+            .corpus_minimizer
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not minimizing the corpus"))?;
+
+        let finished = minimizer.record(outcome.as_ref(), coverage_map.as_slice())?;
+
+        // The fuzzer is not running to reset the map before each execution, so reset it here
+        coverage_map.as_mut_slice().fill(0);
+
+        if finished {
+            return self.finish_corpus_minimization();
+        }
+
+        let entry = minimizer.current_entry().to_path_buf();
+
+        debug!(
+            self.as_conf_object(),
+            "Minimizing corpus: running entry {}",
+            entry.display()
+        );
+
+        self.restore_initial_snapshot()?;
+        self.coverage_prev_loc = 0;
+        self.get_and_write_testcase()?;
+        self.post_timeout_event()?;
+
+        run_alone(|| {
+            continue_simulation(0)?;
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Copy the selected entries to the minimized corpus directory and stop, quitting if
+    /// configured to quit on the iteration limit
+    fn finish_corpus_minimization(&mut self) -> Result<()> {
+        let minimizer = self
+            .corpus_minimizer
+            .take()
+            .ok_or_else(|| anyhow!("Not minimizing the corpus"))?;
+
+        create_dir_all(&self.minimized_corpus_directory)?;
+
+        let selected = minimizer.select();
+
+        selected.iter().try_for_each(|entry| {
+            let file_name = entry
+                .path
+                .file_name()
+                .ok_or_else(|| anyhow!("No file name for {}", entry.path.display()))?;
+            copy(&entry.path, self.minimized_corpus_directory.join(file_name))?;
+            Ok::<(), anyhow::Error>(())
+        })?;
+
+        // Set the log level so this message always prints
+        set_log_level(self.as_conf_object_mut(), LogLevel::Info)?;
+
+        info!(
+            self.as_conf_object(),
+            "Minimized corpus of {} entries to {} entries covering {} map indices ({} entries excluded as solutions). Saved minimized corpus to {}",
+            minimizer.entries.len(),
+            selected.len(),
+            minimizer
+                .coverage
+                .iter()
+                .flat_map(|e| e.indices.iter())
+                .collect::<BTreeSet<_>>()
+                .len(),
+            minimizer.solutions,
+            self.minimized_corpus_directory.display()
+        );
+
+        if self.quit_on_iteration_limit {
+            quit(0)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coverage(path: &str, size: usize, duration: u64, indices: &[usize]) -> CorpusEntryCoverage {
+        CorpusEntryCoverage {
+            path: PathBuf::from(path),
+            size,
+            duration: Duration::from_millis(duration),
+            indices: indices.iter().cloned().collect(),
+        }
+    }
+
+    fn minimizer(coverage: Vec<CorpusEntryCoverage>) -> CorpusMinimizer {
+        CorpusMinimizer {
+            entries: coverage.iter().map(|c| c.path.clone()).collect(),
+            current: coverage.len(),
+            contents: Vec::new(),
+            execution_start: Instant::now(),
+            coverage,
+            solutions: 0,
+        }
+    }
+
+    fn selected(minimizer: &CorpusMinimizer) -> Vec<&str> {
+        minimizer
+            .select()
+            .iter()
+            .filter_map(|c| c.path.to_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_select_covers_every_index() {
+        let minimizer = minimizer(vec![
+            coverage("large", 10, 1, &[1, 2, 3]),
+            coverage("small", 2, 1, &[1]),
+            coverage("medium", 3, 1, &[2, 3]),
+            coverage("other", 5, 1, &[4]),
+        ]);
+
+        assert_eq!(selected(&minimizer), vec!["small", "medium", "other"]);
+    }
+
+    #[test]
+    fn test_select_skips_redundant_entries() {
+        let minimizer = minimizer(vec![
+            coverage("all", 4, 1, &[1, 2]),
+            coverage("first", 4, 2, &[1]),
+            coverage("second", 4, 2, &[2]),
+        ]);
+
+        assert_eq!(selected(&minimizer), vec!["all"]);
+    }
+
+    #[test]
+    fn test_select_prefers_faster_entries_of_same_size() {
+        let minimizer = minimizer(vec![
+            coverage("slow", 4, 10, &[1]),
+            coverage("fast", 4, 1, &[1]),
+        ]);
+
+        assert_eq!(selected(&minimizer), vec!["fast"]);
+    }
+
+    #[test]
+    fn test_select_nothing_without_coverage() {
+        let minimizer = minimizer(Vec::new());
+
+        assert!(minimizer.select().is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimization of solution testcases and of the corpus
+//!
+//! Minimization runs outside of the fuzzing loop. Each candidate is written to the target with
+//! the same snapshot restore and testcase write path used while fuzzing, and the outcome of
+//! each execution decides the next candidate.
+
+use crate::{state::SolutionKind, Tsffs};
+use anyhow::{anyhow, bail, Result};
+use simics::{
+    api::{continue_simulation, run_alone, set_log_level, LogLevel},
+    debug, error, info, AsConfObject,
+};
+use std::{fs::write, path::PathBuf};
+
+pub mod corpus;
+
+/// The byte candidates are normalized to, chosen to be printable so minimized text inputs
+/// stay readable
+const NORMALIZED_BYTE: u8 = b'0';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MinimizePhase {
+    /// Remove blocks of `block_size` bytes starting at `position`, halving the block size each
+    /// time the end of the testcase is reached
+    Trim { block_size: usize, position: usize },
+    /// Replace the byte at `position` with [`NORMALIZED_BYTE`]
+    Normalize { position: usize },
+}
+
+#[derive(Debug, Clone)]
+/// State of an in-progress testcase minimization
+pub(crate) struct TestcaseMinimizer {
+    /// The path of the testcase being minimized
+    path: PathBuf,
+    /// The size of the testcase being minimized
+    original_size: usize,
+    /// The solution produced by the original testcase. Not known until it has run once.
+    expected: Option<SolutionKind>,
+    /// The smallest testcase found so far which produces the expected solution
+    best: Vec<u8>,
+    /// The testcase currently being executed
+    candidate: Vec<u8>,
+    phase: MinimizePhase,
+    executions: usize,
+}
+
+impl TestcaseMinimizer {
+    pub fn new(path: PathBuf, contents: Vec<u8>) -> Self {
+        Self {
+            path,
+            original_size: contents.len(),
+            expected: None,
+            phase: MinimizePhase::Trim {
+                block_size: (contents.len() / 2).max(1),
+                position: 0,
+            },
+            best: contents.clone(),
+            candidate: contents,
+            executions: 0,
+        }
+    }
+
+    /// The testcase to execute next
+    pub fn candidate(&self) -> &[u8] {
+        &self.candidate
+    }
+
+    /// The path the minimized testcase is written to, next to the original
+    pub fn output_path(&self) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".min");
+        self.path.with_file_name(file_name)
+    }
+
+    /// Record the solution (or lack of one) produced by executing the current candidate and
+    /// select the next candidate. Returns `true` once no candidates remain.
+    pub fn record(&mut self, outcome: Option<&SolutionKind>) -> Result<bool> {
+        self.executions += 1;
+
+        let Some(expected) = self.expected.as_ref() else {
+            let Some(kind) = outcome else {
+                bail!(
+                    "Testcase {} does not produce a solution, nothing to minimize",
+                    self.path.display()
+                );
+            };
+
+            self.expected = Some(kind.clone());
+
+            return Ok(!self.next_candidate());
+        };
+
+        // NOTE: Details like faulting addresses and messages change with the input, so only
+        // the cause of the solution has to match
+        let reproduced =
+            outcome.is_some_and(|kind| kind.reproduction_key() == expected.reproduction_key());
+
+        if reproduced {
+            self.best = self.candidate.clone();
+        }
+
+        match &mut self.phase {
+            MinimizePhase::Trim {
+                block_size,
+                position,
+            } if !reproduced => *position += *block_size,
+            // The removed block shifted the remaining bytes down, so the next block starts
+            // at the same position
+            MinimizePhase::Trim { .. } => {}
+            MinimizePhase::Normalize { position } => *position += 1,
+        }
+
+        Ok(!self.next_candidate())
+    }
+
+    /// Select the next candidate, returning `false` if there is none
+    fn next_candidate(&mut self) -> bool {
+        loop {
+            match &mut self.phase {
+                MinimizePhase::Trim { block_size: 0, .. } => {
+                    self.phase = MinimizePhase::Normalize { position: 0 };
+                }
+                MinimizePhase::Trim {
+                    block_size,
+                    position,
+                } => {
+                    if *position >= self.best.len() {
+                        *block_size /= 2;
+                        *position = 0;
+                        continue;
+                    }
+
+                    let end = (*position + *block_size).min(self.best.len());
+
+                    // Never remove the entire testcase
+                    if end - *position >= self.best.len() {
+                        *position = end;
+                        continue;
+                    }
+
+                    self.candidate = self.best[..*position]
+                        .iter()
+                        .chain(self.best[end..].iter())
+                        .cloned()
+                        .collect();
+
+                    return true;
+                }
+                MinimizePhase::Normalize { position } => {
+                    while *position < self.best.len() && self.best[*position] == NORMALIZED_BYTE {
+                        *position += 1;
+                    }
+
+                    if *position >= self.best.len() {
+                        return false;
+                    }
+
+                    self.candidate = self.best.clone();
+                    self.candidate[*position] = NORMALIZED_BYTE;
+
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+impl Tsffs {
+    /// Called when an execution of a minimization candidate finishes with the solution it
+    /// produced, if any. Either starts the next candidate or writes out the minimized
+    /// testcase and leaves the simulation stopped.
+    pub fn on_minimize_iteration(&mut self, outcome: Option<SolutionKind>) -> Result<()> {
+        let minimizer = self
+            .minimizer
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not minimizing a testcase"))?;
+
+        let finished = match minimizer.record(outcome.as_ref()) {
+            Ok(finished) => finished,
+            Err(e) => {
+                self.minimizer = None;
+                error!(self.as_conf_object(), "Minimization failed: {e}");
+                return Ok(());
+            }
+        };
+
+        if finished {
+            let minimizer = self
+                .minimizer
+                .take()
+                .ok_or_else(|| anyhow!("Not minimizing a testcase"))?;
+            let output_path = minimizer.output_path();
+
+            write(&output_path, &minimizer.best)?;
+
+            // Set the log level so this message always prints
+            set_log_level(self.as_conf_object_mut(), LogLevel::Info)?;
+
+            info!(
+                self.as_conf_object(),
+                "Minimized {} from {} to {} bytes in {} executions. Saved minimized testcase to {}",
+                minimizer.path.display(),
+                minimizer.original_size,
+                minimizer.best.len(),
+                minimizer.executions,
+                output_path.display()
+            );
+
+            return Ok(());
+        }
+
+        debug!(
+            self.as_conf_object(),
+            "Minimizing: trying candidate of {} bytes",
+            minimizer.candidate().len()
+        );
+
+        self.restore_initial_snapshot()?;
+        self.coverage_prev_loc = 0;
+        self.get_and_write_testcase()?;
+        self.post_timeout_event()?;
+
+        run_alone(|| {
+            continue_simulation(0)?;
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitizer::SanitizerViolation;
+
+    #[test]
+    fn test_first_execution_must_produce_solution() {
+        let mut minimizer = TestcaseMinimizer::new(PathBuf::from("solution"), b"AAAA".to_vec());
+
+        assert!(minimizer.record(None).is_err());
+    }
+
+    #[test]
+    fn test_output_path_is_next_to_original() {
+        let minimizer = TestcaseMinimizer::new(PathBuf::from("solutions/abcd"), b"A".to_vec());
+
+        assert_eq!(minimizer.output_path(), PathBuf::from("solutions/abcd.min"));
+    }
+
+    #[test]
+    fn test_trim_removes_blocks() -> Result<()> {
+        let kind = SolutionKind::Exception { number: 14 };
+        let mut minimizer = TestcaseMinimizer::new(PathBuf::from("solution"), b"AAAA".to_vec());
+
+        assert!(!minimizer.record(Some(&kind))?);
+        // The first candidate removes the first half of the testcase
+        assert_eq!(minimizer.candidate(), b"AA");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimize_to_required_byte() -> Result<()> {
+        let kind = SolutionKind::Exception { number: 14 };
+        let mut minimizer = TestcaseMinimizer::new(PathBuf::from("solution"), b"AAXA".to_vec());
+
+        loop {
+            let outcome = minimizer.candidate().contains(&b'X').then_some(&kind);
+
+            if minimizer.record(outcome)? {
+                break;
+            }
+        }
+
+        assert_eq!(minimizer.best, b"X");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_replaces_unneeded_bytes() -> Result<()> {
+        let kind = SolutionKind::Exception { number: 14 };
+        let mut minimizer = TestcaseMinimizer::new(PathBuf::from("solution"), b"XY".to_vec());
+
+        // Only the length of the testcase matters, so no block can be removed but every
+        // byte can be normalized
+        loop {
+            let outcome = (minimizer.candidate().len() == 2).then_some(&kind);
+
+            if minimizer.record(outcome)? {
+                break;
+            }
+        }
+
+        assert_eq!(minimizer.best, b"00");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_details_of_solution_are_ignored() -> Result<()> {
+        let mut minimizer = TestcaseMinimizer::new(PathBuf::from("solution"), b"AAAA".to_vec());
+
+        minimizer.record(Some(&SolutionKind::Sanitizer {
+            violation: SanitizerViolation::UseAfterFree,
+            address: 0x1000,
+            allocation_site: 0x4000,
+        }))?;
+        minimizer.record(Some(&SolutionKind::Sanitizer {
+            violation: SanitizerViolation::UseAfterFree,
+            address: 0x2000,
+            allocation_site: 0x4000,
+        }))?;
+
+        assert_eq!(minimizer.best, b"AA");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_different_cause_is_not_reproduced() -> Result<()> {
+        let mut minimizer = TestcaseMinimizer::new(PathBuf::from("solution"), b"AAAA".to_vec());
+
+        minimizer.record(Some(&SolutionKind::Exception { number: 14 }))?;
+        minimizer.record(Some(&SolutionKind::Exception { number: 13 }))?;
+
+        assert_eq!(minimizer.best, b"AAAA");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Named memory regions which the target may not access in some ways
+//!
+//! Regions like the null page, MMIO holes, code segments, or SMRAM are declared with the
+//! config interface along with the kinds of access which are not allowed, for example writes
+//! to code or execution of data. A breakpoint is set for each kind of access on each region
+//! once the start processor is known, and a hit of one of these breakpoints is a solution
+//! naming the region and the access.
+
+use crate::{arch::ArchitectureOperations, state::SolutionKind, Tsffs};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use simics::{
+    api::{
+        breakpoint, delete_breakpoint, get_attribute, sys::breakpoint_kind_t, Access,
+        BreakpointFlag, BreakpointId, GenericTransaction,
+    },
+    debug, AsConfObject,
+};
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// A kind of memory access
+pub(crate) enum RegionAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+impl RegionAccess {
+    /// The breakpoint access type for this kind of access
+    fn access(&self) -> Access {
+        match self {
+            Self::Read => Access::Sim_Access_Read,
+            Self::Write => Access::Sim_Access_Write,
+            Self::Execute => Access::Sim_Access_Execute,
+        }
+    }
+
+    /// Parse an access mask made of the characters 'r', 'w', and 'x', for example "wx"
+    pub fn parse_mask(mask: &str) -> Result<Vec<Self>> {
+        let mut accesses = Vec::new();
+
+        for c in mask.chars() {
+            let access = match c {
+                'r' => Self::Read,
+                'w' => Self::Write,
+                'x' => Self::Execute,
+                _ => bail!("Invalid access {c} in access mask {mask}. Expected r, w, or x"),
+            };
+
+            if !accesses.contains(&access) {
+                accesses.push(access);
+            }
+        }
+
+        if accesses.is_empty() {
+            bail!("Empty access mask");
+        }
+
+        Ok(accesses)
+    }
+}
+
+impl Display for RegionAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read => write!(f, "read"),
+            Self::Write => write!(f, "write"),
+            Self::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A named region of memory in which some kinds of access are solutions
+pub(crate) struct MemoryRegion {
+    pub name: String,
+    /// The first address of the region
+    pub start: u64,
+    /// The address after the last address of the region
+    pub end: u64,
+    /// Whether the addresses are physical. Otherwise, they are virtual addresses in the
+    /// context of the start processor.
+    pub physical: bool,
+    /// The kinds of access which are solutions
+    pub accesses: Vec<RegionAccess>,
+    /// The breakpoint set for each kind of access, once the region is armed
+    breakpoints: Vec<(BreakpointId, RegionAccess)>,
+}
+
+impl MemoryRegion {
+    pub fn new(
+        name: String,
+        start: u64,
+        end: u64,
+        physical: bool,
+        accesses: Vec<RegionAccess>,
+    ) -> Result<Self> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            bail!(
+                "Invalid memory region name '{name}'. Names may only contain letters, digits, '-', '_', and '.'"
+            );
+        }
+
+        if end <= start {
+            bail!("Memory region {name} is empty: {start:#x} to {end:#x}");
+        }
+
+        Ok(Self {
+            name,
+            start,
+            end,
+            physical,
+            accesses,
+            breakpoints: Vec::new(),
+        })
+    }
+}
+
+impl Tsffs {
+    /// Add a memory region, replacing any region with the same name. The region is armed
+    /// immediately if the start processor is known.
+    pub fn insert_memory_region(&mut self, region: MemoryRegion) -> Result<()> {
+        debug!(
+            self.as_conf_object(),
+            "Adding memory region {} from {:#x} to {:#x} ({}) with solution accesses {:?}",
+            region.name,
+            region.start,
+            region.end,
+            if region.physical {
+                "physical"
+            } else {
+                "virtual"
+            },
+            region.accesses
+        );
+
+        self.delete_memory_region(&region.name)?;
+        self.memory_regions.push(region);
+
+        if self.start_processor_number.get().is_some() {
+            self.arm_memory_regions()?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the memory region named `name` and delete its breakpoints. Returns whether
+    /// there was a region with the name.
+    pub fn delete_memory_region(&mut self, name: &str) -> Result<bool> {
+        let Some(index) = self.memory_regions.iter().position(|r| r.name == name) else {
+            return Ok(false);
+        };
+
+        for (id, _) in self.memory_regions.remove(index).breakpoints {
+            delete_breakpoint(id)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Set the breakpoints of each memory region which has not been armed yet on the start
+    /// processor's physical memory or current context
+    pub fn arm_memory_regions(&mut self) -> Result<()> {
+        if self
+            .memory_regions
+            .iter()
+            .all(|r| !r.breakpoints.is_empty())
+        {
+            return Ok(());
+        }
+
+        let start_processor = self
+            .start_processor()
+            .ok_or_else(|| anyhow!("No start processor"))?;
+        let physical_memory = start_processor.processor_info_v2().get_physical_memory()?;
+        let context = get_attribute(start_processor.cpu(), "current_context")?
+            .as_object()
+            .ok_or_else(|| anyhow!("Start processor has no current context"))?;
+
+        for region in self
+            .memory_regions
+            .iter_mut()
+            .filter(|r| r.breakpoints.is_empty())
+        {
+            let (object, kind) = if region.physical {
+                (physical_memory, breakpoint_kind_t::Sim_Break_Physical)
+            } else {
+                (context, breakpoint_kind_t::Sim_Break_Virtual)
+            };
+
+            for access in &region.accesses {
+                let id = breakpoint(
+                    object,
+                    kind,
+                    access.access(),
+                    region.start,
+                    region.end - region.start,
+                    BreakpointFlag::Sim_Breakpoint_Simulation
+                        | BreakpointFlag::Sim_Breakpoint_Private,
+                )?;
+
+                region.breakpoints.push((id, *access));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the solution a hit of the breakpoint `breakpoint` is, if it was set for a
+    /// memory region
+    pub fn memory_region_violation(
+        &self,
+        breakpoint: i64,
+        transaction: *mut GenericTransaction,
+    ) -> Option<SolutionKind> {
+        self.memory_regions.iter().find_map(|region| {
+            region
+                .breakpoints
+                .iter()
+                .find(|(id, _)| *id as i64 == breakpoint)
+                .map(|(_, access)| SolutionKind::MemoryRegion {
+                    name: region.name.clone(),
+                    access: *access,
+                    address: if region.physical {
+                        unsafe { (*transaction).physical_address }
+                    } else {
+                        unsafe { (*transaction).logical_address }
+                    },
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mask() -> Result<()> {
+        assert_eq!(RegionAccess::parse_mask("r")?, vec![RegionAccess::Read]);
+        assert_eq!(
+            RegionAccess::parse_mask("wx")?,
+            vec![RegionAccess::Write, RegionAccess::Execute]
+        );
+        assert_eq!(
+            RegionAccess::parse_mask("xrw")?,
+            vec![
+                RegionAccess::Execute,
+                RegionAccess::Read,
+                RegionAccess::Write
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mask_ignores_repeated_accesses() -> Result<()> {
+        assert_eq!(
+            RegionAccess::parse_mask("wwrw")?,
+            vec![RegionAccess::Write, RegionAccess::Read]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid_mask() {
+        assert!(RegionAccess::parse_mask("").is_err());
+        assert!(RegionAccess::parse_mask("rz").is_err());
+        assert!(RegionAccess::parse_mask("RW").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Comparison logging of routines which compare buffers, like `memcmp` and `strcmp`
+//!
+//! Instruction-level comparison logging cannot see comparisons of buffers made by a loop in a
+//! routine, so magic strings checked through these routines are never solved. Routines are
+//! declared by address, or by symbol in an ELF or PE file, along with the registers holding
+//! the two buffer pointers and the length. When a routine is entered, both buffers are read
+//! from guest memory and logged as an AFL++ routine comparison.
+
+use crate::{arch::ArchitectureOperations, Tsffs};
+use anyhow::{anyhow, bail, Result};
+use goblin::Object;
+use raw_cstr::AsRawCstr;
+use simics::{debug, trace, AsConfObject};
+use std::{fs::read, path::Path};
+
+/// The largest number of bytes of each buffer logged for a routine comparison, which is the
+/// size of an AFL++ routine comparison operand
+pub(crate) const CMP_ROUTINE_MAX_BYTES: usize = 31;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A routine which compares two buffers
+pub(crate) struct CmpRoutine {
+    /// The name of the routine, if any. This is only used to describe the routine.
+    pub name: Option<String>,
+    /// The register holding a pointer to the first buffer when the routine is entered
+    pub left_register: String,
+    /// The register holding a pointer to the second buffer when the routine is entered
+    pub right_register: String,
+    /// The register holding the number of bytes compared when the routine is entered. If
+    /// there is none, the buffers are NUL-terminated strings.
+    pub length_register: Option<String>,
+}
+
+impl CmpRoutine {
+    /// Check that each register of the routine exists on `processor`
+    fn validate_registers<A>(&self, processor: &mut A) -> Result<()>
+    where
+        A: ArchitectureOperations,
+    {
+        for name in [&self.left_register, &self.right_register]
+            .into_iter()
+            .chain(self.length_register.as_ref())
+        {
+            processor
+                .int_register()
+                .get_number(name.as_raw_cstr()?)
+                .map_err(|e| {
+                    anyhow!(
+                        "Invalid register {name} for comparison routine {}: {e}",
+                        self.name.as_deref().unwrap_or("<unnamed>")
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the address of a symbol in an ELF or PE file loaded at `base`. ELF symbols are
+/// looked up in the symbol table and then the dynamic symbol table, and `base` is added to
+/// their value. PE symbols are looked up in the exports, and `base` is added to their
+/// relative virtual address.
+pub(crate) fn symbol_address<P>(executable: P, symbol: &str, base: u64) -> Result<u64>
+where
+    P: AsRef<Path>,
+{
+    let executable = executable.as_ref();
+    let contents = read(executable)?;
+
+    let address = match Object::parse(&contents)? {
+        Object::Elf(elf) => elf
+            .syms
+            .iter()
+            .find(|s| elf.strtab.get_at(s.st_name) == Some(symbol) && s.st_value != 0)
+            .or_else(|| {
+                elf.dynsyms
+                    .iter()
+                    .find(|s| elf.dynstrtab.get_at(s.st_name) == Some(symbol) && s.st_value != 0)
+            })
+            .map(|s| s.st_value),
+        Object::PE(pe) => pe
+            .exports
+            .iter()
+            .find(|e| e.name == Some(symbol))
+            .map(|e| e.rva as u64),
+        _ => bail!(
+            "Unsupported executable format for {}, expected ELF or PE",
+            executable.display()
+        ),
+    };
+
+    address
+        .map(|a| base.wrapping_add(a))
+        .ok_or_else(|| anyhow!("Symbol {symbol} not found in {}", executable.display()))
+}
+
+/// Read the value of a register by name
+fn read_register<A>(processor: &mut A, name: &str) -> Result<u64>
+where
+    A: ArchitectureOperations,
+{
+    let number = processor.int_register().get_number(name.as_raw_cstr()?)?;
+    Ok(processor.int_register().read(number)?)
+}
+
+/// Read a NUL-terminated string of up to `CMP_ROUTINE_MAX_BYTES` bytes, excluding the NUL.
+/// Reading stops early at memory which cannot be read, because the string may end right
+/// before it.
+fn read_c_string<A>(processor: &mut A, address: u64) -> Result<Vec<u8>>
+where
+    A: ArchitectureOperations,
+{
+    let mut string = Vec::new();
+
+    while string.len() < CMP_ROUTINE_MAX_BYTES {
+        let len = (CMP_ROUTINE_MAX_BYTES - string.len()).min(8);
+
+        let Ok(chunk) =
+            processor.read_logical_bytes(address.wrapping_add(string.len() as u64), len)
+        else {
+            break;
+        };
+
+        if let Some(end) = chunk.iter().position(|b| *b == 0) {
+            string.extend_from_slice(&chunk[..end]);
+            break;
+        }
+
+        string.extend_from_slice(&chunk);
+    }
+
+    Ok(string)
+}
+
+/// Read the buffers compared by `routine` when it is entered
+fn read_cmp_routine_buffers<A>(
+    processor: &mut A,
+    routine: &CmpRoutine,
+) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    A: ArchitectureOperations,
+{
+    let left = read_register(processor, &routine.left_register)?;
+    let right = read_register(processor, &routine.right_register)?;
+
+    if let Some(length_register) = &routine.length_register {
+        let len = (read_register(processor, length_register)? as usize).min(CMP_ROUTINE_MAX_BYTES);
+
+        Ok((
+            processor.read_logical_bytes(left, len)?,
+            processor.read_logical_bytes(right, len)?,
+        ))
+    } else {
+        Ok((
+            read_c_string(processor, left)?,
+            read_c_string(processor, right)?,
+        ))
+    }
+}
+
+impl Tsffs {
+    /// Add a comparison routine entered at `address`. Its registers are checked against each
+    /// processor which has been added, and processors added later are checked when they are
+    /// added.
+    pub fn insert_cmp_routine(&mut self, address: u64, routine: CmpRoutine) -> Result<()> {
+        for processor in self.processors.values_mut() {
+            routine.validate_registers(processor)?;
+        }
+
+        debug!(
+            self.as_conf_object(),
+            "Adding comparison routine {routine:?} at {address:#x}"
+        );
+        self.cmp_routines.insert(address, routine);
+
+        Ok(())
+    }
+
+    /// Check the registers of each comparison routine against the processor with number
+    /// `processor_number`
+    pub fn validate_cmp_routines(&mut self, processor_number: i32) -> Result<()> {
+        let processor = self
+            .processors
+            .get_mut(&processor_number)
+            .ok_or_else(|| anyhow!("No processor {processor_number}"))?;
+
+        self.cmp_routines
+            .values()
+            .try_for_each(|routine| routine.validate_registers(processor))
+    }
+
+    /// If the processor with number `processor_number` is entering a comparison routine, read
+    /// the buffers it compares and log them
+    pub fn log_cmp_routine_if_entered(&mut self, processor_number: i32) -> Result<()> {
+        let processor = self
+            .processors
+            .get_mut(&processor_number)
+            .ok_or_else(|| anyhow!("No processor {processor_number}"))?;
+
+        let pc = processor.processor_info_v2().get_program_counter()?;
+
+        let Some(routine) = self.cmp_routines.get(&pc) else {
+            return Ok(());
+        };
+
+        // The buffers may not be mapped yet, for example when the target passes a bad
+        // pointer, which is not an error in the fuzzer
+        let (left, right) = match read_cmp_routine_buffers(processor, routine) {
+            Ok(buffers) => buffers,
+            Err(e) => {
+                trace!(
+                    self.as_conf_object(),
+                    "Failed to read buffers of comparison routine at {pc:#x}: {e}"
+                );
+                return Ok(());
+            }
+        };
+
+        self.log_cmp_routine(pc, &left, &right)
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Heap sanitizer for allocators in the target software
+//!
+//! Heap corruption rarely raises an exception, so it usually goes unnoticed by the fuzzer.
+//! The sanitizer is configured with the entry and exit addresses of the target's allocation
+//! and free routines, like `AllocatePool`/`FreePool` in UEFI or `kmalloc`/`kfree` in Linux,
+//! and which arguments hold the size and pointer. It tracks live allocations by reading the
+//! arguments on entry and the returned pointer on exit, and sets breakpoints on the redzones
+//! around each live allocation and on freed allocations. An access to either outside of the
+//! allocator, or freeing an allocation twice, is a solution naming the allocation site.
+
+use crate::{
+    arch::ArchitectureOperations,
+    state::{SolutionKind, StopReason},
+    Tsffs,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use simics::{
+    api::{
+        breakpoint, delete_breakpoint, get_attribute, get_processor_number, sys::breakpoint_kind_t,
+        Access, BreakpointFlag, BreakpointId, ConfObject, GenericTransaction,
+    },
+    debug, AsConfObject,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::Display,
+};
+
+/// The number of freed allocations kept poisoned. When it is exceeded, the oldest freed
+/// allocation is released and accesses to it are no longer detected.
+const QUARANTINE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// An invalid use of an allocation detected by the sanitizer
+pub(crate) enum SanitizerViolation {
+    /// Memory of a freed allocation was accessed
+    UseAfterFree,
+    /// An allocation was freed again after it was freed
+    DoubleFree,
+    /// Memory just outside of a live allocation was accessed
+    HeapBufferOverflow,
+}
+
+impl Display for SanitizerViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UseAfterFree => write!(f, "use-after-free"),
+            Self::DoubleFree => write!(f, "double-free"),
+            Self::HeapBufferOverflow => write!(f, "heap-buffer-overflow"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A routine of the target's allocator, identified by its entry address
+pub(crate) enum AllocatorRoutine {
+    /// A routine which allocates memory and returns a pointer to it
+    Allocate {
+        /// The index of the argument holding the size of the allocation
+        size_argument: usize,
+    },
+    /// A routine which frees memory
+    Free {
+        /// The index of the argument holding the pointer to the memory to free
+        pointer_argument: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+/// A call to an allocator routine which has been entered but has not exited yet
+enum PendingCall {
+    Allocate {
+        size: u64,
+        site: u64,
+    },
+    Free {
+        address: u64,
+    },
+    /// A call whose arguments could not be read, kept so entries and exits stay balanced
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+/// A live allocation
+struct Allocation {
+    size: u64,
+    /// The address the allocation routine returned to
+    site: u64,
+    /// The breakpoints on the redzones before and after the allocation
+    redzone_breakpoints: Vec<BreakpointId>,
+}
+
+#[derive(Debug, Clone)]
+/// A freed allocation, which may not be accessed or freed again
+struct FreedAllocation {
+    address: u64,
+    size: u64,
+    site: u64,
+    breakpoint: BreakpointId,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// The memory a sanitizer breakpoint guards
+enum Guard {
+    /// A redzone of the allocation made at `site`
+    Redzone { site: u64 },
+    /// The allocation made at `site`, which has been freed
+    Freed { site: u64 },
+}
+
+#[derive(Debug, Default)]
+/// The allocator routines and the allocations tracked by the sanitizer
+pub(crate) struct Sanitizer {
+    /// Allocator routines by entry address
+    entries: HashMap<u64, AllocatorRoutine>,
+    /// The exit addresses of allocator routines
+    exits: HashSet<u64>,
+    /// The allocator routine calls in progress on each processor, innermost last
+    pending: HashMap<i32, Vec<PendingCall>>,
+    /// Live allocations by address
+    live: BTreeMap<u64, Allocation>,
+    /// Freed allocations, oldest first
+    quarantine: VecDeque<FreedAllocation>,
+    /// The memory guarded by each breakpoint set by the sanitizer
+    guards: HashMap<BreakpointId, Guard>,
+}
+
+impl Sanitizer {
+    /// Whether any allocator routines are configured
+    pub fn enabled(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    /// Whether `address` is the entry or exit address of an allocator routine
+    pub fn is_routine_address(&self, address: u64) -> bool {
+        self.entries.contains_key(&address) || self.exits.contains(&address)
+    }
+
+    /// The entry and exit addresses of every allocator routine
+    pub fn routine_addresses(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries.keys().chain(self.exits.iter()).cloned()
+    }
+
+    /// Whether the breakpoint `breakpoint` was set by the sanitizer
+    pub fn owns(&self, breakpoint: i64) -> bool {
+        self.guards.contains_key(&(breakpoint as BreakpointId))
+    }
+
+    /// Whether the processor with number `processor_number` is executing an allocator
+    /// routine, which accesses the metadata in redzones and freed allocations legitimately.
+    /// If the processor is not known, whether any processor is.
+    fn in_allocator(&self, processor_number: Option<i32>) -> bool {
+        match processor_number {
+            Some(processor_number) => self
+                .pending
+                .get(&processor_number)
+                .is_some_and(|calls| !calls.is_empty()),
+            None => self.pending.values().any(|calls| !calls.is_empty()),
+        }
+    }
+
+    /// The live allocation containing `address`, if any
+    fn live_allocation(&self, address: u64) -> Option<(&u64, &Allocation)> {
+        self.live
+            .range(..=address)
+            .next_back()
+            .filter(|(start, allocation)| address < start.wrapping_add(allocation.size))
+    }
+}
+
+impl Tsffs {
+    /// Add an allocator routine entered at `entry_address` and exited at `exit_address`.
+    /// Routines with multiple exits are added once for each exit.
+    pub fn insert_allocator_routine(
+        &mut self,
+        entry_address: u64,
+        exit_address: u64,
+        routine: AllocatorRoutine,
+    ) {
+        debug!(
+            self.as_conf_object(),
+            "Adding allocator routine {routine:?} entered at {entry_address:#x} and exited at {exit_address:#x}"
+        );
+        self.sanitizer.entries.insert(entry_address, routine);
+        self.sanitizer.exits.insert(exit_address);
+    }
+
+    /// Set a sanitizer breakpoint on reads and writes of `length` bytes at the logical
+    /// address `address` of `cpu`
+    fn set_sanitizer_breakpoint(
+        &mut self,
+        cpu: *mut ConfObject,
+        address: u64,
+        length: u64,
+        guard: Guard,
+    ) -> Result<BreakpointId> {
+        let context = get_attribute(cpu, "current_context")?
+            .as_object()
+            .ok_or_else(|| anyhow!("Processor has no current context"))?;
+
+        let id = breakpoint(
+            context,
+            breakpoint_kind_t::Sim_Break_Virtual,
+            Access::Sim_Access_Read | Access::Sim_Access_Write,
+            address,
+            length,
+            BreakpointFlag::Sim_Breakpoint_Simulation | BreakpointFlag::Sim_Breakpoint_Private,
+        )?;
+
+        self.sanitizer.guards.insert(id, guard);
+
+        Ok(id)
+    }
+
+    /// Delete a sanitizer breakpoint
+    fn delete_sanitizer_breakpoint(&mut self, id: BreakpointId) -> Result<()> {
+        self.sanitizer.guards.remove(&id);
+        delete_breakpoint(id)?;
+        Ok(())
+    }
+
+    /// Start tracking an allocation of `size` bytes at `address` made at `site`
+    fn track_allocation(
+        &mut self,
+        cpu: *mut ConfObject,
+        address: u64,
+        size: u64,
+        site: u64,
+    ) -> Result<()> {
+        let end = address.wrapping_add(size);
+
+        // Memory which was freed earlier may be reused by this allocation
+        let (reused, quarantine) = self
+            .sanitizer
+            .quarantine
+            .drain(..)
+            .partition::<VecDeque<_>, _>(|f| {
+                f.address < end && address < f.address.wrapping_add(f.size)
+            });
+        self.sanitizer.quarantine = quarantine;
+
+        for freed in reused {
+            self.delete_sanitizer_breakpoint(freed.breakpoint)?;
+        }
+
+        if let Some(stale) = self.sanitizer.live.remove(&address) {
+            for id in stale.redzone_breakpoints {
+                self.delete_sanitizer_breakpoint(id)?;
+            }
+        }
+
+        let mut redzone_breakpoints = Vec::new();
+
+        if self.sanitizer_redzone_size > 0 {
+            redzone_breakpoints.push(self.set_sanitizer_breakpoint(
+                cpu,
+                address.wrapping_sub(self.sanitizer_redzone_size),
+                self.sanitizer_redzone_size,
+                Guard::Redzone { site },
+            )?);
+            redzone_breakpoints.push(self.set_sanitizer_breakpoint(
+                cpu,
+                end,
+                self.sanitizer_redzone_size,
+                Guard::Redzone { site },
+            )?);
+        }
+
+        self.sanitizer.live.insert(
+            address,
+            Allocation {
+                size,
+                site,
+                redzone_breakpoints,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Stop tracking the live allocation at `address` and poison it
+    fn poison_freed_allocation(&mut self, cpu: *mut ConfObject, address: u64) -> Result<()> {
+        // Pointers which were not allocated while tracking, for example before the harness
+        // started, are not poisoned because their size is unknown
+        let Some(allocation) = self.sanitizer.live.remove(&address) else {
+            return Ok(());
+        };
+
+        for id in allocation.redzone_breakpoints {
+            self.delete_sanitizer_breakpoint(id)?;
+        }
+
+        if allocation.size > 0 {
+            let breakpoint = self.set_sanitizer_breakpoint(
+                cpu,
+                address,
+                allocation.size,
+                Guard::Freed {
+                    site: allocation.site,
+                },
+            )?;
+
+            self.sanitizer.quarantine.push_back(FreedAllocation {
+                address,
+                size: allocation.size,
+                site: allocation.site,
+                breakpoint,
+            });
+        }
+
+        if self.sanitizer.quarantine.len() > QUARANTINE_SIZE {
+            if let Some(oldest) = self.sanitizer.quarantine.pop_front() {
+                self.delete_sanitizer_breakpoint(oldest.breakpoint)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// If the processor with number `processor_number` is entering or exiting an allocator
+    /// routine, update the tracked allocations. Freeing an allocation which is already freed
+    /// stops the simulation with a solution.
+    pub fn track_allocator_routines(&mut self, processor_number: i32) -> Result<()> {
+        let processor = self
+            .processors
+            .get_mut(&processor_number)
+            .ok_or_else(|| anyhow!("No processor {processor_number}"))?;
+
+        let pc = processor.processor_info_v2().get_program_counter()?;
+
+        if !self.sanitizer.is_routine_address(pc) {
+            return Ok(());
+        }
+
+        let cpu = processor.cpu();
+
+        if let Some(routine) = self.sanitizer.entries.get(&pc).copied() {
+            // NOTE: Arguments on the stack may not be readable, for example when the stack
+            // is paged out, in which case the call is not tracked
+            let call = match routine {
+                AllocatorRoutine::Allocate { size_argument } => match processor
+                    .get_call_argument(size_argument, self.sanitizer_calling_convention)
+                    .and_then(|size| Ok((size, processor.get_call_return_address()?)))
+                {
+                    Ok((size, site)) => PendingCall::Allocate { size, site },
+                    Err(e) => {
+                        debug!(
+                            self.as_conf_object(),
+                            "Failed to read arguments of allocation routine at {pc:#x}: {e}"
+                        );
+                        PendingCall::Unknown
+                    }
+                },
+                AllocatorRoutine::Free { pointer_argument } => match processor
+                    .get_call_argument(pointer_argument, self.sanitizer_calling_convention)
+                {
+                    Ok(address) => {
+                        if let Some(site) = self
+                            .sanitizer
+                            .quarantine
+                            .iter()
+                            .find(|f| f.address == address)
+                            .map(|f| f.site)
+                        {
+                            self.stop_simulation(StopReason::Solution {
+                                kind: SolutionKind::Sanitizer {
+                                    violation: SanitizerViolation::DoubleFree,
+                                    address,
+                                    allocation_site: site,
+                                },
+                                processor: cpu,
+                            })?;
+                        }
+
+                        PendingCall::Free { address }
+                    }
+                    Err(e) => {
+                        debug!(
+                            self.as_conf_object(),
+                            "Failed to read arguments of free routine at {pc:#x}: {e}"
+                        );
+                        PendingCall::Unknown
+                    }
+                },
+            };
+
+            self.sanitizer
+                .pending
+                .entry(processor_number)
+                .or_default()
+                .push(call);
+        } else if let Some(call) = self
+            .sanitizer
+            .pending
+            .get_mut(&processor_number)
+            .and_then(|calls| calls.pop())
+        {
+            match call {
+                PendingCall::Allocate { size, site } => {
+                    match self
+                        .processors
+                        .get_mut(&processor_number)
+                        .ok_or_else(|| anyhow!("No processor {processor_number}"))?
+                        .get_call_return_value()
+                    {
+                        Ok(0) => {}
+                        Ok(address) => self.track_allocation(cpu, address, size, site)?,
+                        Err(e) => debug!(
+                            self.as_conf_object(),
+                            "Failed to read return value of allocation routine at {pc:#x}: {e}"
+                        ),
+                    }
+                }
+                PendingCall::Free { address } => self.poison_freed_allocation(cpu, address)?,
+                PendingCall::Unknown => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the violation a hit of the breakpoint `breakpoint` is, if it is a sanitizer
+    /// breakpoint and the access is invalid. Accesses made by a processor while it executes
+    /// an allocator routine are valid.
+    pub fn sanitizer_violation(
+        &self,
+        breakpoint: i64,
+        transaction: *mut GenericTransaction,
+    ) -> Option<SolutionKind> {
+        let guard = self.sanitizer.guards.get(&(breakpoint as BreakpointId))?;
+
+        let initiator = unsafe { (*transaction).ini_ptr };
+        let processor_number = (!initiator.is_null())
+            .then(|| get_processor_number(initiator).ok())
+            .flatten();
+
+        if self.sanitizer.in_allocator(processor_number) {
+            return None;
+        }
+
+        let address = unsafe { (*transaction).logical_address };
+
+        match *guard {
+            Guard::Freed { site } => Some(SolutionKind::Sanitizer {
+                violation: SanitizerViolation::UseAfterFree,
+                address,
+                allocation_site: site,
+            }),
+            // Redzones may overlap allocations placed right next to each other
+            Guard::Redzone { site } => self.sanitizer.live_allocation(address).is_none().then_some(
+                SolutionKind::Sanitizer {
+                    violation: SanitizerViolation::HeapBufferOverflow,
+                    address,
+                    allocation_site: site,
+                },
+            ),
+        }
+    }
+
+    /// Forget all tracked allocations and delete their breakpoints. Allocations are
+    /// tracked for a single execution, because restoring the snapshot discards them.
+    pub fn reset_sanitizer(&mut self) -> Result<()> {
+        for id in self.sanitizer.guards.keys().copied().collect::<Vec<_>>() {
+            delete_breakpoint(id)?;
+        }
+
+        self.sanitizer.guards.clear();
+        self.sanitizer.pending.clear();
+        self.sanitizer.live.clear();
+        self.sanitizer.quarantine.clear();
+
+        Ok(())
+    }
+}
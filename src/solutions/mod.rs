@@ -0,0 +1,280 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deduplication of solutions into buckets by crash signature
+//!
+//! The signature of a solution is made up of the kind of solution (including the exception
+//! number, breakpoint ID, or manual solution ID), the program counter when the simulation
+//! stopped (except for timeouts, which stop wherever the target happens to be), and a short
+//! hash of the last edges executed before the stop. Each bucket keeps only the smallest
+//! solution with its signature, and counts how many times it was hit.
+
+use crate::{
+    state::{SolutionKind, SolutionMetadata},
+    Tsffs,
+};
+use anyhow::{anyhow, Result};
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use simics::{debug, info, AsConfObject};
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read_to_string, write},
+    path::PathBuf,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A bucket of solutions sharing the same signature
+pub(crate) struct SolutionBucket {
+    /// The metadata of the smallest solution in the bucket
+    pub metadata: SolutionMetadata,
+    /// The hash of the last edges executed before the solution
+    pub edges_hash: u32,
+    /// The size of the smallest solution in the bucket
+    pub size: usize,
+    /// The number of solutions with this signature which have been found
+    pub count: usize,
+}
+
+impl SolutionBucket {
+    /// The signature of a solution, which is also the name of its bucket's directory
+    fn signature(metadata: &SolutionMetadata, edges_hash: u32) -> String {
+        let kind = match &metadata.kind {
+            SolutionKind::Timeout => "timeout".to_string(),
+            SolutionKind::Exception { number } => format!("exception-{number}"),
+            SolutionKind::Breakpoint { id } => format!("breakpoint-{id}"),
+            SolutionKind::Manual { id, .. } => format!("manual-{id}"),
+            SolutionKind::Sanitizer {
+                violation,
+                allocation_site,
+                ..
+            } => format!("sanitizer-{violation}-{allocation_site:x}"),
+            SolutionKind::ControlFlowIntegrity { expected, .. } => {
+                format!("control-flow-integrity-{expected:x}")
+            }
+            SolutionKind::OutOfBounds { .. } => "out-of-bounds".to_string(),
+            SolutionKind::MemoryRegion { name, access, .. } => {
+                format!("memory-region-{name}-{access}")
+            }
+            SolutionKind::Console { pattern, .. } => {
+                format!("console-{:08x}", crc32fast::hash(pattern.as_bytes()))
+            }
+        };
+
+        // Timeouts stop wherever the target happens to be running, so the program counter
+        // would split one hang into many buckets
+        if matches!(metadata.kind, SolutionKind::Timeout) {
+            format!("{kind}-{edges_hash:08x}")
+        } else {
+            format!("{kind}-{:x}-{edges_hash:08x}", metadata.pc)
+        }
+    }
+}
+
+/// Hash a sequence of edges into a short hash which is stable across runs
+fn hash_edges<'a, I>(edges: I) -> u32
+where
+    I: IntoIterator<Item = &'a (u64, u64)>,
+{
+    let mut hasher = Hasher::new();
+    edges.into_iter().for_each(|(from, to)| {
+        hasher.update(&from.to_le_bytes());
+        hasher.update(&to.to_le_bytes());
+    });
+    hasher.finalize()
+}
+
+impl Tsffs {
+    /// The name of the directory in the solutions directory containing the buckets
+    const SOLUTION_BUCKETS_DIRECTORY_NAME: &'static str = "buckets";
+    /// The name of the summary index file in the buckets directory
+    const SOLUTION_BUCKETS_INDEX_FILE_NAME: &'static str = "index.json";
+    /// The name of the file in each bucket containing its smallest solution
+    const SOLUTION_BUCKET_TESTCASE_FILE_NAME: &'static str = "solution";
+
+    fn solution_buckets_directory(&self) -> PathBuf {
+        self.solutions_directory
+            .join(Self::SOLUTION_BUCKETS_DIRECTORY_NAME)
+    }
+
+    /// Record the edge from the previously recorded edge target to `pc` executed in the
+    /// current iteration, keeping only the most recent edges used in solution signatures
+    pub fn record_recent_edge(&mut self, pc: u64) {
+        if self.bucket_edge_history == 0 {
+            return;
+        }
+
+        let from = self.recent_edges.back().map_or(0, |(_, to)| *to);
+
+        while self.recent_edges.len() >= self.bucket_edge_history {
+            self.recent_edges.pop_front();
+        }
+
+        self.recent_edges.push_back((from, pc));
+    }
+
+    /// Add a solution with the given metadata, whose input is the current testcase, to its
+    /// bucket. The input is only saved if it is the first or the smallest in its bucket, and
+    /// returns whether it was saved.
+    pub fn bucket_solution(&mut self, metadata: &SolutionMetadata) -> Result<bool> {
+        let buckets_directory = self.solution_buckets_directory();
+        let index_path = buckets_directory.join(Self::SOLUTION_BUCKETS_INDEX_FILE_NAME);
+
+        if self.solution_buckets.is_none() {
+            // Continue counting into buckets from previous runs
+            self.solution_buckets = Some(if index_path.is_file() {
+                serde_json::from_str(&read_to_string(&index_path)?)?
+            } else {
+                BTreeMap::new()
+            });
+        }
+
+        let testcase = self
+            .current_testcase
+            .clone()
+            .ok_or_else(|| anyhow!("No current testcase"))?;
+        let edges_hash = hash_edges(self.recent_edges.iter());
+        let signature = SolutionBucket::signature(metadata, edges_hash);
+
+        let buckets = self
+            .solution_buckets
+            .as_mut()
+            .ok_or_else(|| anyhow!("Solution buckets not loaded"))?;
+
+        let store = match buckets.get_mut(&signature) {
+            Some(bucket) => {
+                bucket.count += 1;
+
+                if testcase.len() < bucket.size {
+                    bucket.metadata = metadata.clone();
+                    bucket.size = testcase.len();
+                    true
+                } else {
+                    false
+                }
+            }
+            None => {
+                buckets.insert(
+                    signature.clone(),
+                    SolutionBucket {
+                        metadata: metadata.clone(),
+                        edges_hash,
+                        size: testcase.len(),
+                        count: 1,
+                    },
+                );
+                true
+            }
+        };
+
+        let count = buckets.get(&signature).map(|b| b.count).unwrap_or_default();
+
+        if store {
+            let bucket_directory = buckets_directory.join(&signature);
+            create_dir_all(&bucket_directory)?;
+            write(
+                bucket_directory.join(Self::SOLUTION_BUCKET_TESTCASE_FILE_NAME),
+                &testcase,
+            )?;
+        }
+
+        write(&index_path, serde_json::to_string_pretty(buckets)?)?;
+
+        if count == 1 {
+            info!(
+                self.as_conf_object(),
+                "New solution bucket {signature} ({} buckets total)",
+                self.solution_buckets.as_ref().map_or(0, |b| b.len())
+            );
+        } else {
+            debug!(
+                self.as_conf_object(),
+                "Solution in existing bucket {signature} (hit {count} times{})",
+                if store {
+                    ", saved smaller solution"
+                } else {
+                    ""
+                }
+            );
+        }
+
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regions::RegionAccess;
+
+    fn metadata(kind: SolutionKind, pc: u64) -> SolutionMetadata {
+        SolutionMetadata {
+            kind,
+            processor_number: 0,
+            pc,
+            virtual_time: 0.0,
+            duplicate: false,
+        }
+    }
+
+    #[test]
+    fn test_signature_includes_cause_and_pc() {
+        assert_eq!(
+            SolutionBucket::signature(
+                &metadata(SolutionKind::Exception { number: 14 }, 0x1234),
+                0xabc
+            ),
+            "exception-14-1234-00000abc"
+        );
+        assert_eq!(
+            SolutionBucket::signature(
+                &metadata(
+                    SolutionKind::MemoryRegion {
+                        name: "null".to_string(),
+                        access: RegionAccess::Write,
+                        address: 0x10,
+                    },
+                    0x1234
+                ),
+                0
+            ),
+            "memory-region-null-write-1234-00000000"
+        );
+    }
+
+    #[test]
+    fn test_timeout_signature_ignores_pc() {
+        assert_eq!(
+            SolutionBucket::signature(&metadata(SolutionKind::Timeout, 0x1234), 0xdeadbeef),
+            SolutionBucket::signature(&metadata(SolutionKind::Timeout, 0x5678), 0xdeadbeef),
+        );
+    }
+
+    #[test]
+    fn test_signature_ignores_input_dependent_details() {
+        let manual = |message: &str| {
+            metadata(
+                SolutionKind::Manual {
+                    id: 1,
+                    message: message.to_string(),
+                },
+                0x1234,
+            )
+        };
+
+        assert_eq!(
+            SolutionBucket::signature(&manual("first"), 0),
+            SolutionBucket::signature(&manual("second"), 0),
+        );
+    }
+
+    #[test]
+    fn test_signature_depends_on_edges() {
+        let metadata = metadata(SolutionKind::Breakpoint { id: 3 }, 0x1234);
+
+        assert_ne!(
+            SolutionBucket::signature(&metadata, hash_edges(&[(0x10, 0x20)])),
+            SolutionBucket::signature(&metadata, hash_edges(&[(0x20, 0x10)])),
+        );
+    }
+}
@@ -4,6 +4,7 @@
 //! Definitions for tracking the state of the fuzzer
 
 use anyhow::{anyhow, Error, Result};
+use libafl_bolts::impl_serdeany;
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string};
 use simics::api::ConfObject;
@@ -13,16 +14,122 @@ use std::{
     str::FromStr,
 };
 
-use crate::{magic::MagicNumber, ManualStartInfo};
+use crate::{
+    magic::MagicNumber, regions::RegionAccess, sanitizer::SanitizerViolation, ManualStartInfo,
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// The kind of a solution, along with the details of what triggered it
 pub(crate) enum SolutionKind {
+    /// The testcase did not finish within the configured timeout
     Timeout,
-    Exception,
-    Breakpoint,
-    Manual,
+    /// A configured exception (or any exception, if all exceptions are solutions) occurred
+    Exception { number: i64 },
+    /// A configured breakpoint (or any breakpoint, if all breakpoints are solutions) was hit
+    Breakpoint { id: i64 },
+    /// A solution was signaled manually, either with the `solution` interface method or with
+    /// a harness assertion
+    Manual { id: u64, message: String },
+    /// The heap sanitizer detected an invalid use of the allocation made at
+    /// `allocation_site`
+    Sanitizer {
+        violation: SanitizerViolation,
+        address: u64,
+        allocation_site: u64,
+    },
+    /// A return went to `actual` instead of the address `expected` pushed by its call
+    ControlFlowIntegrity { expected: u64, actual: u64 },
+    /// Memory guarded around the testcase buffer was accessed at the physical address
+    /// `address`, `offset` bytes from the start of the buffer
+    OutOfBounds { address: u64, offset: i64 },
+    /// The memory region named `name` was accessed at `address` in a way which is not allowed
+    MemoryRegion {
+        name: String,
+        access: RegionAccess,
+        address: u64,
+    },
+    /// The console named `console` output `line`, which matched the solution pattern
+    /// `pattern`
+    Console {
+        console: String,
+        pattern: String,
+        line: String,
+    },
 }
 
+impl SolutionKind {
+    /// The name of every kind of solution
+    pub const NAMES: &'static [&'static str] = &[
+        "timeout",
+        "exception",
+        "breakpoint",
+        "manual",
+        "sanitizer",
+        "control-flow-integrity",
+        "out-of-bounds",
+        "memory-region",
+        "console",
+    ];
+
+    /// A short name for the kind of solution, without its details. This is one of
+    /// [`SolutionKind::NAMES`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::Exception { .. } => "exception",
+            Self::Breakpoint { .. } => "breakpoint",
+            Self::Manual { .. } => "manual",
+            Self::Sanitizer { .. } => "sanitizer",
+            Self::ControlFlowIntegrity { .. } => "control-flow-integrity",
+            Self::OutOfBounds { .. } => "out-of-bounds",
+            Self::MemoryRegion { .. } => "memory-region",
+            Self::Console { .. } => "console",
+        }
+    }
+
+    /// A key identifying the cause of the solution, without the details which depend on the
+    /// input, like faulting addresses, messages, or console output. Two solutions with the
+    /// same key are considered to reproduce each other.
+    pub fn reproduction_key(&self) -> String {
+        match self {
+            Self::Exception { number } => format!("{}-{number}", self.name()),
+            Self::Breakpoint { id } => format!("{}-{id}", self.name()),
+            Self::Manual { id, .. } => format!("{}-{id}", self.name()),
+            Self::Sanitizer {
+                violation,
+                allocation_site,
+                ..
+            } => format!("{}-{violation}-{allocation_site:x}", self.name()),
+            Self::MemoryRegion { name, access, .. } => {
+                format!("{}-{name}-{access}", self.name())
+            }
+            Self::Console { pattern, .. } => format!("{}-{pattern}", self.name()),
+            Self::Timeout | Self::ControlFlowIntegrity { .. } | Self::OutOfBounds { .. } => {
+                self.name().to_string()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Metadata describing why and where a solution occurred, saved alongside each solution in
+/// the solutions directory
+pub(crate) struct SolutionMetadata {
+    pub kind: SolutionKind,
+    /// The number of the processor the solution occurred on
+    pub processor_number: i32,
+    /// The program counter of the processor when the simulation stopped
+    pub pc: u64,
+    /// The virtual time in seconds of the processor when the simulation stopped
+    pub virtual_time: f64,
+    #[serde(skip)]
+    /// Whether the solution duplicates a smaller solution in its bucket, in which case it is
+    /// not saved
+    pub duplicate: bool,
+}
+
+impl_serdeany!(SolutionMetadata);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// Definition of all the reasons the simulator could be stopped by the fuzzer. In general,
 /// callbacks in the fuzzer, for example [`Driver::on_magic_instruction`] may be called
@@ -43,6 +150,10 @@ pub(crate) enum StopReason {
     ManualStop,
     Solution {
         kind: SolutionKind,
+        /// The processor the solution occurred on, if known. The start processor is used
+        /// otherwise.
+        #[serde(skip, default = "null_mut")]
+        processor: *mut ConfObject,
     },
 }
 
@@ -0,0 +1,212 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! AFL++-compatible campaign statistics
+//!
+//! Statistics are written in the same layout AFL++ uses for a fuzzer instance's output
+//! directory: a `fuzzer_stats` file of `key : value` lines, rewritten on each update, and an
+//! append-only `plot_data` CSV. This lets tools like `afl-whatsup` and `afl-plot` read TSFFS
+//! campaigns directly.
+
+use crate::{state::SolutionKind, Tsffs};
+use anyhow::{anyhow, Result};
+use simics::{debug, AsConfObject};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{create_dir_all, rename, write, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    process::id,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the instance directory when not fuzzing in parallel, matching AFL++
+const DEFAULT_INSTANCE_NAME: &str = "default";
+/// The header of the `plot_data` file, in the column order AFL++ writes
+const PLOT_DATA_HEADER: &str = "# relative_time, cycles_done, cur_item, corpus_count, pending_total, pending_favs, map_size, saved_crashes, saved_hangs, max_depth, execs_per_sec, total_execs, edges_found";
+
+#[derive(Debug, Clone, Default)]
+/// Statistics collected on the simulator thread over the course of a campaign
+pub(crate) struct FuzzerStats {
+    /// The number of entries in the corpus, as last reported by the fuzzer
+    pub corpus_count: usize,
+    /// The number of solutions which were not timeouts
+    pub crashes: usize,
+    /// The number of timeouts
+    pub hangs: usize,
+    /// The number of solutions of each kind, by the name of the kind
+    pub solutions: BTreeMap<&'static str, usize>,
+    /// Every coverage map index found to be interesting
+    pub map_indices: HashSet<usize>,
+    /// When a new interesting input was last found
+    pub last_find: Option<SystemTime>,
+    /// When a solution which was not a timeout was last found
+    pub last_crash: Option<SystemTime>,
+    /// When a timeout was last found
+    pub last_hang: Option<SystemTime>,
+    /// When statistics were last written
+    pub last_written: Option<Instant>,
+}
+
+impl FuzzerStats {
+    /// Count a solution of the given kind
+    pub fn record_solution(&mut self, kind: &SolutionKind) {
+        *self.solutions.entry(kind.name()).or_default() += 1;
+
+        if matches!(kind, SolutionKind::Timeout) {
+            self.hangs += 1;
+            self.last_hang = Some(SystemTime::now());
+        } else {
+            self.crashes += 1;
+            self.last_crash = Some(SystemTime::now());
+        }
+    }
+
+    /// Count a new interesting input which covered `indices`
+    pub fn record_find(&mut self, indices: &[usize]) {
+        self.map_indices.extend(indices.iter().cloned());
+        self.last_find = Some(SystemTime::now());
+    }
+}
+
+/// Seconds since the epoch of a time, or 0 if it has not happened, as AFL++ reports times
+fn unix_seconds(time: Option<SystemTime>) -> u64 {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Tsffs {
+    /// The directory statistics for this instance are written to. As in AFL++, each instance
+    /// has its own directory so multiple parallel clients can share a statistics directory.
+    fn stats_instance_directory(&self) -> PathBuf {
+        if self.parallel {
+            self.stats_directory
+                .join(format!("client-{}", self.client_id))
+        } else {
+            self.stats_directory.join(DEFAULT_INSTANCE_NAME)
+        }
+    }
+
+    /// Write the statistics files if they are enabled and were last written more than
+    /// `stats_interval` seconds ago
+    pub fn write_stats_if_needed(&mut self) -> Result<()> {
+        if !self.stats_to_file {
+            return Ok(());
+        }
+
+        let Some(start_time) = self.start_time.get().cloned() else {
+            // Fuzzing has not started yet
+            return Ok(());
+        };
+
+        if self
+            .stats
+            .last_written
+            .is_some_and(|t| t.elapsed() < Duration::from_secs(self.stats_interval))
+        {
+            return Ok(());
+        }
+
+        self.write_stats(start_time)?;
+        self.stats.last_written = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Rewrite `fuzzer_stats` and append a line to `plot_data`
+    fn write_stats(&self, start_time: SystemTime) -> Result<()> {
+        let directory = self.stats_instance_directory();
+
+        create_dir_all(&directory).map_err(|e| {
+            anyhow!(
+                "Failed to create statistics directory {}: {e}",
+                directory.display()
+            )
+        })?;
+
+        let run_time = SystemTime::now().duration_since(start_time)?;
+        let execs_per_sec = if run_time.as_secs_f64() > 0.0 {
+            self.iterations as f64 / run_time.as_secs_f64()
+        } else {
+            0.0
+        };
+        let map_density =
+            self.stats.map_indices.len() as f64 * 100.0 / Self::COVERAGE_MAP_SIZE as f64;
+
+        let fuzzer_stats = [
+            ("start_time", unix_seconds(Some(start_time)).to_string()),
+            (
+                "last_update",
+                unix_seconds(Some(SystemTime::now())).to_string(),
+            ),
+            ("run_time", run_time.as_secs().to_string()),
+            ("fuzzer_pid", id().to_string()),
+            // Queue cycles and pending entries are not tracked by the scheduler in use, so
+            // they are reported as zero for tools which expect them
+            ("cycles_done", "0".to_string()),
+            ("cycles_wo_finds", "0".to_string()),
+            ("execs_done", self.iterations.to_string()),
+            ("execs_per_sec", format!("{execs_per_sec:.2}")),
+            ("corpus_count", self.stats.corpus_count.to_string()),
+            ("cur_item", "0".to_string()),
+            ("pending_favs", "0".to_string()),
+            ("pending_total", "0".to_string()),
+            ("bitmap_cvg", format!("{map_density:.2}%")),
+            ("saved_crashes", self.stats.crashes.to_string()),
+            ("saved_hangs", self.stats.hangs.to_string()),
+            ("last_find", unix_seconds(self.stats.last_find).to_string()),
+            (
+                "last_crash",
+                unix_seconds(self.stats.last_crash).to_string(),
+            ),
+            ("last_hang", unix_seconds(self.stats.last_hang).to_string()),
+            ("edges_found", self.edges_seen.len().to_string()),
+            ("total_edges", Self::COVERAGE_MAP_SIZE.to_string()),
+            ("afl_banner", "tsffs".to_string()),
+            ("afl_version", env!("CARGO_PKG_VERSION").to_string()),
+            ("target_mode", "simics".to_string()),
+        ]
+        .iter()
+        .map(|(k, v)| format!("{k:<18}: {v}\n"))
+        .collect::<String>();
+
+        let fuzzer_stats_path = directory.join("fuzzer_stats");
+        let temporary_path = directory.join(".fuzzer_stats_tmp");
+        write(&temporary_path, fuzzer_stats)?;
+        rename(&temporary_path, &fuzzer_stats_path)?;
+
+        let plot_data_path = directory.join("plot_data");
+        let write_header = !plot_data_path.is_file();
+
+        let mut plot_data = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&plot_data_path)?;
+
+        if write_header {
+            writeln!(plot_data, "{PLOT_DATA_HEADER}")?;
+        }
+
+        // Queue cycles, pending entries and depth are not tracked by the scheduler in use,
+        // so they are reported as zero
+        writeln!(
+            plot_data,
+            "{}, 0, 0, {}, 0, 0, {map_density:.2}%, {}, {}, 0, {execs_per_sec:.2}, {}, {}",
+            run_time.as_secs(),
+            self.stats.corpus_count,
+            self.stats.crashes,
+            self.stats.hangs,
+            self.iterations,
+            self.edges_seen.len(),
+        )?;
+
+        debug!(
+            self.as_conf_object(),
+            "Wrote statistics to {}",
+            directory.display()
+        );
+
+        Ok(())
+    }
+}
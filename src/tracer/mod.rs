@@ -5,22 +5,40 @@ use anyhow::{anyhow, bail, Error, Result};
 use ffi::ffi;
 use libafl::prelude::CmpValues;
 use libafl_bolts::{AsMutSlice, AsSlice};
-use libafl_targets::{AFLppCmpLogOperands, AFL_CMP_TYPE_INS, CMPLOG_MAP_H};
+use libafl_targets::{
+    AFLppCmpLogFnOperands, AFLppCmpLogOperands, AFL_CMP_TYPE_INS, AFL_CMP_TYPE_RTN, CMPLOG_MAP_H,
+    CMPLOG_MAP_RTN_EXTENDED_H,
+};
 use serde::{Deserialize, Serialize};
 use simics::{
     api::{
-        get_processor_number, sys::instruction_handle_t, AsConfObject, AttrValue, AttrValueType,
-        ConfObject,
+        flush_all_caches, get_interface, get_processor_number,
+        sys::{cached_instruction_handle_t, instruction_handle_t, memory_handle_t},
+        AsConfObject, AttrValue, AttrValueType, ConfObject, CpuCachedInstructionInterface,
     },
-    trace,
+    debug, trace,
 };
 use std::{
-    collections::HashMap, ffi::c_void, fmt::Display, hash::Hash, num::Wrapping,
-    slice::from_raw_parts, str::FromStr,
+    collections::HashMap,
+    ffi::c_void,
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::Write,
+    num::Wrapping,
+    slice::from_raw_parts,
+    str::FromStr,
+    time::SystemTime,
 };
 use typed_builder::TypedBuilder;
 
-use crate::{arch::ArchitectureOperations, Tsffs};
+use crate::{
+    arch::ArchitectureOperations,
+    routines::CMP_ROUTINE_MAX_BYTES,
+    state::{SolutionKind, StopReason},
+    Tsffs,
+};
+
+pub(crate) mod tenet;
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub(crate) struct ExecutionTrace(pub HashMap<i32, Vec<ExecutionTraceEntry>>);
@@ -70,6 +88,10 @@ pub(crate) enum CmpExpr {
     U64(u64),
     I64(i64),
     Addr(u64),
+    /// A vector register by index, and the number of bytes of it compared
+    Vector((u8, u8)),
+    /// A floating point stack register by index
+    Fpu(u8),
 }
 
 #[allow(unused)]
@@ -96,6 +118,7 @@ pub(crate) enum CmpValue {
     I32(i32),
     U64(u64),
     I64(i64),
+    Bytes(Vec<u8>),
     Expr(Box<CmpExpr>),
 }
 
@@ -116,13 +139,37 @@ impl TryFrom<&CmpExpr> for CmpValue {
     }
 }
 
-fn cmp_shape(cmp: &CmpValues) -> Result<u32> {
+/// The width of the operands of a comparison entry in the AFL++ cmp map, which holds up to
+/// 16 bytes per operand
+const AFLPP_CMP_OPERAND_MAX_BYTES: usize = 16;
+
+/// The AFL++ shape (the operand size minus one) and operands of a comparison of integers or
+/// of byte arrays of up to 16 bytes
+fn cmp_operands(cmp: &CmpValues) -> Result<(u32, AFLppCmpLogOperands)> {
     match cmp {
-        CmpValues::U8(_) => Ok(0),
-        CmpValues::U16(_) => Ok(1),
-        CmpValues::U32(_) => Ok(3),
-        CmpValues::U64(_) => Ok(7),
-        _ => bail!("Shape not implemented for non-integral types"),
+        CmpValues::U8((l, r)) => Ok((0, AFLppCmpLogOperands::new(*l as u64, *r as u64))),
+        CmpValues::U16((l, r)) => Ok((1, AFLppCmpLogOperands::new(*l as u64, *r as u64))),
+        CmpValues::U32((l, r)) => Ok((3, AFLppCmpLogOperands::new(*l as u64, *r as u64))),
+        CmpValues::U64((l, r)) => Ok((7, AFLppCmpLogOperands::new(*l, *r))),
+        CmpValues::Bytes((l, r)) => {
+            if l.is_empty() || l.len() != r.len() || l.len() > AFLPP_CMP_OPERAND_MAX_BYTES {
+                bail!(
+                    "Unsupported byte comparison operand sizes {} and {}",
+                    l.len(),
+                    r.len()
+                );
+            }
+
+            let mut lb = [0u8; AFLPP_CMP_OPERAND_MAX_BYTES];
+            let mut rb = [0u8; AFLPP_CMP_OPERAND_MAX_BYTES];
+            lb[..l.len()].copy_from_slice(l);
+            rb[..r.len()].copy_from_slice(r);
+
+            Ok((
+                l.len() as u32 - 1,
+                AFLppCmpLogOperands::new_128bit(u128::from_le_bytes(lb), u128::from_le_bytes(rb)),
+            ))
+        }
     }
 }
 
@@ -161,11 +208,153 @@ fn hash_index(value: u64, len: u64) -> u64 {
     u64::from_le_bytes(buffer)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// The kind of branch instruction an edge was taken by
+pub(crate) enum BranchKind {
+    #[default]
+    /// A jump, conditional or unconditional
+    Jump,
+    /// A call to a function
+    Call,
+    /// A return from a function
+    Return,
+}
+
+#[derive(Debug, Clone, Default)]
+/// A shadow of a processor's call stack, tracking the context edges are executed in for
+/// context-sensitive coverage
+pub(crate) struct ShadowCallStack {
+    /// The hash of the call sites on the stack
+    context: u64,
+    /// The context of each caller on the stack
+    frames: Vec<u64>,
+    /// The number of calls deeper than the maximum depth which have not returned
+    untracked: usize,
+}
+
+impl ShadowCallStack {
+    /// The maximum depth tracked. Deeper calls (for example, unbounded recursion) keep
+    /// the context of the deepest tracked frame.
+    const MAX_DEPTH: usize = 1024;
+
+    /// Enter a function called from the basic block at `call_site`
+    fn call(&mut self, call_site: u64) {
+        if self.frames.len() < Self::MAX_DEPTH {
+            self.frames.push(self.context);
+            self.context = self.context.rotate_left(1) ^ call_site;
+        } else {
+            self.untracked += 1;
+        }
+    }
+
+    /// Return to the caller. Returns without a matching call (for example, from a function
+    /// called before tracing started) reset the context.
+    fn ret(&mut self) {
+        if self.untracked > 0 {
+            self.untracked -= 1;
+        } else {
+            self.context = self.frames.pop().unwrap_or_default();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A frame of a return address stack
+enum ReturnFrame {
+    /// A call which should return to the address
+    Call(u64),
+    /// An exception or interrupt, whose handler returns to the interrupted instruction
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Default)]
+/// A shadow stack of the return addresses pushed by a processor's calls, used to check that
+/// each return goes back to its caller
+pub(crate) struct ReturnAddressStack {
+    frames: Vec<ReturnFrame>,
+    /// The number of calls deeper than the maximum depth which have not returned
+    untracked: usize,
+    /// The page table root of the address space the frames were pushed in
+    page_table_root: Option<u64>,
+}
+
+impl ReturnAddressStack {
+    /// The maximum depth tracked. Returns from deeper calls are not checked.
+    const MAX_DEPTH: usize = 1024;
+
+    fn push(&mut self, frame: ReturnFrame) {
+        if self.frames.len() < Self::MAX_DEPTH {
+            self.frames.push(frame);
+        } else {
+            self.untracked += 1;
+        }
+    }
+
+    /// Switch to the address space with page table root `root`. The frames pushed in
+    /// another address space belong to another thread of execution, so the stack is reset
+    /// when the address space changes.
+    fn switch_address_space(&mut self, root: u64) {
+        if self.page_table_root != Some(root) {
+            self.frames.clear();
+            self.untracked = 0;
+            self.page_table_root = Some(root);
+        }
+    }
+
+    /// Enter a function which should return to `return_address`
+    fn call(&mut self, return_address: u64) {
+        self.push(ReturnFrame::Call(return_address));
+    }
+
+    /// Enter an exception or interrupt handler
+    pub fn interrupt(&mut self) {
+        self.push(ReturnFrame::Interrupt);
+    }
+
+    /// Return to `target`, returning the address the innermost call should have returned to
+    /// if the return is invalid.
+    ///
+    /// A return to the caller of an outer call unwinds the stack to it, which is how
+    /// `longjmp` and exception unwinding appear. The stack is never unwound past an
+    /// interrupt, and returns from an interrupt handler are not checked, and neither are
+    /// returns without a matching call (for example, from a function called before tracing
+    /// started).
+    fn ret(&mut self, target: u64) -> Option<u64> {
+        if self.untracked > 0 {
+            self.untracked -= 1;
+            return None;
+        }
+
+        let interrupted = self
+            .frames
+            .iter()
+            .rposition(|f| *f == ReturnFrame::Interrupt)
+            .map(|depth| depth + 1)
+            .unwrap_or_default();
+
+        if let Some(depth) = self.frames[interrupted..]
+            .iter()
+            .rposition(|f| *f == ReturnFrame::Call(target))
+        {
+            self.frames.truncate(interrupted + depth);
+            return None;
+        }
+
+        match self.frames.pop() {
+            Some(ReturnFrame::Call(expected)) => Some(expected),
+            Some(ReturnFrame::Interrupt) | None => None,
+        }
+    }
+}
+
 #[derive(TypedBuilder, Debug, Clone, PartialEq, Eq)]
 pub(crate) struct TraceEntry {
     #[builder(default, setter(into, strip_option))]
     /// The target of an edge in the trace
     edge: Option<u64>,
+    #[builder(default)]
+    /// The kind of branch which took the edge
+    branch: BranchKind,
     #[builder(default, setter(into, strip_option))]
     cmp: Option<(u64, Vec<CmpType>, CmpValues)>,
 }
@@ -177,14 +366,25 @@ impl Default for TraceEntry {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd, Hash)]
+/// The scheme used to record coverage into the coverage map
 pub(crate) enum CoverageMode {
+    /// AFL-style edge coverage, counting the hits of each edge
     HitCount,
+    /// AFL-style edge coverage, recording only whether each edge was hit
     Once,
+    /// Basic block coverage, counting the hits of each branch target
+    Block,
+    /// Edge coverage over the last `coverage_ngram_size` branch targets, counting hits
+    NGram,
 }
 
 impl CoverageMode {
-    const AS_STRING: &'static [(&'static str, Self)] =
-        &[("hit-count", Self::HitCount), ("once", Self::Once)];
+    const AS_STRING: &'static [(&'static str, Self)] = &[
+        ("hit-count", Self::HitCount),
+        ("once", Self::Once),
+        ("block", Self::Block),
+        ("ngram", Self::NGram),
+    ];
 }
 
 impl Default for CoverageMode {
@@ -241,29 +441,230 @@ impl From<CoverageMode> for AttrValueType {
     }
 }
 
+impl From<CoverageMode> for AttrValue {
+    fn from(value: CoverageMode) -> Self {
+        value.to_string().into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd, Hash)]
+/// The format execution traces are saved in
+pub(crate) enum ExecutionTraceFormat {
+    /// One JSON object per trace, accumulated in memory while the testcase executes
+    Json,
+    /// The compact binary format of the `tsffs-trace` crate, streamed to disk while the
+    /// testcase executes
+    Binary,
+    /// The text format of the Tenet trace explorer, with register and memory access state,
+    /// streamed to disk while the testcase executes
+    Tenet,
+}
+
+impl ExecutionTraceFormat {
+    const AS_STRING: &'static [(&'static str, Self)] = &[
+        ("json", Self::Json),
+        ("binary", Self::Binary),
+        ("tenet", Self::Tenet),
+    ];
+
+    /// The extension of saved trace files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Binary => "trace",
+            Self::Tenet => "tenet",
+        }
+    }
+}
+
+impl Default for ExecutionTraceFormat {
+    fn default() -> Self {
+        Self::Json
+    }
+}
+
+impl FromStr for ExecutionTraceFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let as_string = Self::AS_STRING.iter().cloned().collect::<HashMap<_, _>>();
+
+        as_string.get(s).cloned().ok_or_else(|| {
+            anyhow!(
+                "Invalid execution trace format {}. Expected one of {}",
+                s,
+                Self::AS_STRING
+                    .iter()
+                    .map(|i| i.0)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+}
+
+impl Display for ExecutionTraceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let to_string = Self::AS_STRING
+            .iter()
+            .map(|(k, v)| (v, k))
+            .collect::<HashMap<_, _>>();
+        if let Some(name) = to_string.get(self) {
+            write!(f, "{}", name)
+        } else {
+            panic!("Invalid state for enum");
+        }
+    }
+}
+
+impl TryFrom<AttrValue> for ExecutionTraceFormat {
+    type Error = Error;
+
+    fn try_from(value: AttrValue) -> Result<Self> {
+        String::try_from(value)?.parse()
+    }
+}
+
+impl From<ExecutionTraceFormat> for AttrValueType {
+    fn from(value: ExecutionTraceFormat) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl From<ExecutionTraceFormat> for AttrValue {
+    fn from(value: ExecutionTraceFormat) -> Self {
+        value.to_string().into()
+    }
+}
+
+/// A writer which hashes everything written through it, so a binary execution trace can be
+/// named by its contents once it is complete without reading it back
+pub(crate) struct HashWriter<W>
+where
+    W: Write,
+{
+    writer: W,
+    hasher: DefaultHasher,
+}
+
+impl<W> HashWriter<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            hasher: DefaultHasher::new(),
+        }
+    }
+
+    /// Flush the underlying writer and return the hash of everything written
+    pub fn finish(mut self) -> Result<u64> {
+        self.writer.flush()?;
+        Ok(self.hasher.finish())
+    }
+}
+
+impl<W> Write for HashWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.write(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
 impl Tsffs {
-    fn log_pc(&mut self, pc: u64) -> Result<()> {
+    /// The index in a coverage map of length `len` of a branch to `pc`, given the branches
+    /// taken before it and the calling context it was taken in (zero if coverage is not
+    /// context-sensitive)
+    fn coverage_index(&self, pc: u64, context: u64, len: u64) -> u64 {
+        match self.coverage_mode {
+            CoverageMode::HitCount | CoverageMode::Once => {
+                (pc ^ self.coverage_prev_loc ^ context) % len
+            }
+            CoverageMode::Block => hash_index(pc ^ context, len),
+            CoverageMode::NGram => {
+                // Rotate each previous location by its age so the order of the branches
+                // contributes to the index
+                let history = self
+                    .coverage_ngram_history
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .fold(0, |acc, (age, loc)| acc ^ loc.rotate_left(age as u32));
+                (pc ^ history ^ context) % len
+            }
+        }
+    }
+
+    fn log_pc(&mut self, pc: u64, context: u64) -> Result<()> {
+        let len = self
+            .coverage_map
+            .get()
+            .ok_or_else(|| {
+                anyhow!("Coverage map not initialized. This is a bug in the fuzzer or the target")
+            })?
+            .as_slice()
+            .len() as u64;
+        let afl_idx = self.coverage_index(pc, context, len);
         let coverage_map = self.coverage_map.get_mut().ok_or_else(|| {
             anyhow!("Coverage map not initialized. This is a bug in the fuzzer or the target")
         })?;
-        let afl_idx = (pc ^ self.coverage_prev_loc) % coverage_map.as_slice().len() as u64;
-        let mut cur_byte: Wrapping<u8> = Wrapping(coverage_map.as_slice()[afl_idx as usize]);
-        cur_byte += 1;
-        coverage_map.as_mut_slice()[afl_idx as usize] = cur_byte.0;
-        self.coverage_prev_loc = (pc >> 1) % coverage_map.as_slice().len() as u64;
+
+        if self.coverage_mode == CoverageMode::Once {
+            coverage_map.as_mut_slice()[afl_idx as usize] = 1;
+        } else {
+            let mut cur_byte: Wrapping<u8> = Wrapping(coverage_map.as_slice()[afl_idx as usize]);
+            cur_byte += 1;
+            coverage_map.as_mut_slice()[afl_idx as usize] = cur_byte.0;
+        }
+
+        self.coverage_prev_loc = (pc >> 1) % len;
+
+        if self.coverage_mode == CoverageMode::NGram {
+            // The index covers the current branch and the previous n - 1 branches
+            if self.coverage_ngram_history.len() + 1 >= self.coverage_ngram_size {
+                self.coverage_ngram_history.pop_front();
+            }
+
+            self.coverage_ngram_history
+                .push_back(self.coverage_prev_loc);
+        }
 
         Ok(())
     }
 
     fn log_cmp(&mut self, pc: u64, types: Vec<CmpType>, cmp: CmpValues) -> Result<()> {
+        // Byte comparisons wider than an AFL++ operand (for example of 32-byte vector
+        // registers) are logged as one comparison per 16 bytes, each at its own index
+        if let CmpValues::Bytes((l, r)) = &cmp {
+            if l.len() > AFLPP_CMP_OPERAND_MAX_BYTES && l.len() == r.len() {
+                return l
+                    .chunks(AFLPP_CMP_OPERAND_MAX_BYTES)
+                    .zip(r.chunks(AFLPP_CMP_OPERAND_MAX_BYTES))
+                    .enumerate()
+                    .try_for_each(|(i, (l, r))| {
+                        self.log_cmp(
+                            pc.wrapping_add(i as u64),
+                            types.clone(),
+                            CmpValues::Bytes((l.to_vec(), r.to_vec())),
+                        )
+                    });
+            }
+        }
+
         // Consistently hash pc to the same header index
         let aflpp_cmp_map = self.aflpp_cmp_map.get_mut().ok_or_else(|| {
             anyhow!("AFL++ cmp map not initialized. This is a bug in the fuzzer or the target")
         })?;
-        let shape = cmp_shape(&cmp)?;
-        let operands = cmp
-            .to_u64_tuple()
-            .ok_or_else(|| anyhow!("Conversion to tuple of non-integral operands not supported"))?;
+        let (shape, operands) = cmp_operands(&cmp)?;
         let pc_index = hash_index(pc, aflpp_cmp_map.headers().len() as u64);
 
         let hits = aflpp_cmp_map.headers_mut()[pc_index as usize].hits();
@@ -281,7 +682,7 @@ impl Tsffs {
         }
 
         aflpp_cmp_map.values_mut().operands_mut()[pc_index as usize]
-            [hits as usize % CMPLOG_MAP_H] = AFLppCmpLogOperands::new(operands.0, operands.1);
+            [hits as usize % CMPLOG_MAP_H] = operands;
 
         if hits == 0 {
             trace!(
@@ -292,6 +693,147 @@ impl Tsffs {
 
         Ok(())
     }
+
+    /// Log a comparison of two buffers by a routine like `memcmp` to the AFL++ cmp map. Only
+    /// the bytes both buffers have, up to the size of a routine operand, are logged.
+    pub(crate) fn log_cmp_routine(&mut self, pc: u64, left: &[u8], right: &[u8]) -> Result<()> {
+        let len = left.len().min(right.len()).min(CMP_ROUTINE_MAX_BYTES);
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        let aflpp_cmp_map = self.aflpp_cmp_map.get_mut().ok_or_else(|| {
+            anyhow!("AFL++ cmp map not initialized. This is a bug in the fuzzer or the target")
+        })?;
+        let pc_index = hash_index(pc, aflpp_cmp_map.headers().len() as u64);
+
+        let hits = aflpp_cmp_map.headers_mut()[pc_index as usize].hits();
+
+        aflpp_cmp_map.headers_mut()[pc_index as usize].set_hits(hits + 1);
+        aflpp_cmp_map.headers_mut()[pc_index as usize].set_shape(len as u32 - 1);
+        aflpp_cmp_map.headers_mut()[pc_index as usize].set__type(AFL_CMP_TYPE_RTN);
+
+        aflpp_cmp_map.values_mut().fn_operands_mut()[pc_index as usize]
+            [hits as usize % CMPLOG_MAP_RTN_EXTENDED_H] =
+            AFLppCmpLogFnOperands::new(&left[..len], &right[..len]);
+
+        if hits == 0 {
+            trace!(
+                self.as_conf_object(),
+                "Logged first hit of routine comparison at {pc:#x} with values {:?} and {:?}",
+                &left[..len],
+                &right[..len]
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Push the return address of a call, or check the target of a return against the
+    /// return address stack of the processor with number `processor_number`, after a branch
+    /// of kind `branch` to `target`. A return to anywhere other than its caller stops the
+    /// simulation with a solution, if the target is in the traced address space and allowed
+    /// by the address filter.
+    fn check_return_address(
+        &mut self,
+        processor_number: i32,
+        handle: *mut instruction_handle_t,
+        branch: BranchKind,
+        target: u64,
+    ) -> Result<()> {
+        if branch == BranchKind::Jump {
+            return Ok(());
+        }
+
+        let arch = self
+            .processors
+            .get_mut(&processor_number)
+            .ok_or_else(|| anyhow!("No processor {processor_number}"))?;
+        let root = arch.get_page_table_root()?;
+
+        match branch {
+            BranchKind::Call => {
+                // The call returns to the instruction following it
+                let address = arch.cpu_instruction_query().logical_address(handle)?;
+                let length = arch
+                    .cpu_instruction_query()
+                    .get_instruction_bytes(handle)?
+                    .size;
+                let stack = self
+                    .return_address_stacks
+                    .entry(processor_number)
+                    .or_default();
+                stack.switch_address_space(root);
+                stack.call(address.wrapping_add(length as u64));
+            }
+            BranchKind::Return => {
+                let processor = arch.cpu();
+                let stack = self
+                    .return_address_stacks
+                    .entry(processor_number)
+                    .or_default();
+                stack.switch_address_space(root);
+
+                // NOTE: Returns into code which is not traced still unwind the stack, so
+                // calls and returns stay balanced
+                if let Some(expected) = stack.ret(target) {
+                    if self.address_allowed(processor_number, target)? {
+                        self.stop_simulation(StopReason::Solution {
+                            kind: SolutionKind::ControlFlowIntegrity {
+                                expected,
+                                actual: target,
+                            },
+                            processor,
+                        })?;
+                    }
+                }
+            }
+            BranchKind::Jump => {}
+        }
+
+        Ok(())
+    }
+
+    /// A hash of the configuration which decides the callbacks registered on each cached
+    /// instruction
+    fn cached_instrumentation_key(&self) -> u64 {
+        let mut cmp_routines = self.cmp_routines.keys().cloned().collect::<Vec<_>>();
+        cmp_routines.sort();
+        let mut allocator_routines = self.sanitizer.routine_addresses().collect::<Vec<_>>();
+        allocator_routines.sort();
+
+        let mut hasher = DefaultHasher::new();
+        self.cmplog.hash(&mut hasher);
+        self.save_all_execution_traces.hash(&mut hasher);
+        self.save_interesting_execution_traces.hash(&mut hasher);
+        self.save_solution_execution_traces.hash(&mut hasher);
+        cmp_routines.hash(&mut hasher);
+        allocator_routines.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Flush the instructions cached by every processor if `cached_instrumentation` is enabled
+    /// and the configuration which decides the callbacks registered on them has changed since
+    /// they were cached, so they are cached again with the right callbacks
+    pub fn flush_cached_instrumentation_if_changed(&mut self) -> Result<()> {
+        if !self.cached_instrumentation {
+            return Ok(());
+        }
+
+        let key = self.cached_instrumentation_key();
+
+        if self.cached_instrumentation_key != Some(key) {
+            debug!(
+                self.as_conf_object(),
+                "Instrumentation configuration changed, flushing cached instructions"
+            );
+            flush_all_caches()?;
+            self.cached_instrumentation_key = Some(key);
+        }
+
+        Ok(())
+    }
 }
 
 #[ffi(from_ptr, expect, self_ty = "*mut c_void")]
@@ -317,15 +859,49 @@ impl Tsffs {
                 match arch.trace_pc(handle) {
                     Ok(r) => {
                         if let Some(pc) = r.edge {
-                            if self.coverage_reporting && self.edges_seen.insert(pc) {
-                                let coverage_map = self.coverage_map.get_mut().ok_or_else(|| {
-                                    anyhow!("Coverage map not initialized. This is a bug in the fuzzer or the target")
-                                })?;
-                                let afl_idx = (pc ^ self.coverage_prev_loc)
-                                    % coverage_map.as_slice().len() as u64;
-                                self.edges_seen_since_last.insert(pc, afl_idx);
+                            let context = if self.context_sensitive_coverage {
+                                self.shadow_call_stacks
+                                    .get(&processor_number)
+                                    .map(|s| s.context)
+                                    .unwrap_or_default()
+                            } else {
+                                0
+                            };
+                            // The call site is identified by the basic block containing it,
+                            // which is the previous location before this edge is logged
+                            let call_site = self.coverage_prev_loc;
+                            if self.address_allowed(processor_number, pc)? {
+                                if self.coverage_reporting && self.edges_seen.insert(pc) {
+                                    self.last_new_edge = Some(SystemTime::now());
+                                    let coverage_map = self.coverage_map.get().ok_or_else(|| {
+                                        anyhow!("Coverage map not initialized. This is a bug in the fuzzer or the target")
+                                    })?;
+                                    let afl_idx = self.coverage_index(
+                                        pc,
+                                        context,
+                                        coverage_map.as_slice().len() as u64,
+                                    );
+                                    self.edges_seen_since_last.insert(pc, afl_idx);
+                                }
+                                if self.bucket_solutions {
+                                    self.record_recent_edge(pc);
+                                }
+                                self.log_pc(pc, context)?;
+                            }
+                            // The shadow call stack is kept for filtered code as well, so
+                            // calls and returns stay balanced
+                            if self.context_sensitive_coverage {
+                                let stack =
+                                    self.shadow_call_stacks.entry(processor_number).or_default();
+                                match r.branch {
+                                    BranchKind::Call => stack.call(call_site),
+                                    BranchKind::Return => stack.ret(),
+                                    BranchKind::Jump => {}
+                                }
+                            }
+                            if self.return_address_solutions {
+                                self.check_return_address(processor_number, handle, r.branch, pc)?;
                             }
-                            self.log_pc(pc)?;
                         }
                     }
                     Err(_) => {
@@ -340,6 +916,74 @@ impl Tsffs {
         Ok(())
     }
 
+    #[ffi(arg(rest), arg(self))]
+    /// Callback when an instruction is cached by the processor, used instead of the callbacks
+    /// on every instruction when `cached_instrumentation` is enabled. Registers the callback
+    /// after the instruction if it is a branch, and the callback before the instruction if it
+    /// is a comparison, the entry of a comparison routine, or if execution traces are being
+    /// saved.
+    ///
+    /// # Arguments
+    ///
+    /// * `obj`
+    /// * `cpu` - The processor the instruction is being cached by
+    /// * `cached_instruction` - An opaque handle to the cached instruction
+    /// * `handle` - An opaque handle to query the instruction being cached
+    pub fn on_cached_instruction(
+        &mut self,
+        _obj: *mut ConfObject,
+        cpu: *mut ConfObject,
+        cached_instruction: *mut cached_instruction_handle_t,
+        handle: *mut instruction_handle_t,
+    ) -> Result<()> {
+        let processor_number = get_processor_number(cpu)?;
+
+        let Some(arch) = self.processors.get_mut(&processor_number) else {
+            return Ok(());
+        };
+
+        // Instructions which cannot be decoded are never traced by either callback, so they
+        // need neither
+        let (control_flow, cmp) = arch
+            .decode(handle)
+            .map(|d| (d.control_flow, d.cmp || !d.cmp_exprs.is_empty()))
+            .unwrap_or_default();
+
+        let address = arch.cpu_instruction_query().logical_address(handle)?;
+
+        // Comparison routines are identified by the address they are entered at
+        let cmp_routine = self.cmp_routines.contains_key(&address);
+
+        // Allocator routines are tracked when they are entered and exited
+        let allocator_routine = self.sanitizer.is_routine_address(address);
+
+        let execution_traces = self.save_all_execution_traces
+            || self.save_interesting_execution_traces
+            || self.save_solution_execution_traces;
+
+        let mut cached_instruction_interface: CpuCachedInstructionInterface = get_interface(cpu)?;
+
+        if control_flow {
+            cached_instruction_interface.register_instruction_after_cb(
+                cached_instruction,
+                Some(tsffs::on_instruction_after),
+                self as *mut Self as *mut _,
+                None,
+            )?;
+        }
+
+        if (self.cmplog && (cmp || cmp_routine)) || allocator_routine || execution_traces {
+            cached_instruction_interface.register_instruction_before_cb(
+                cached_instruction,
+                Some(tsffs::on_instruction_before),
+                self as *mut Self as *mut _,
+                None,
+            )?;
+        }
+
+        Ok(())
+    }
+
     #[ffi(arg(rest), arg(self))]
     /// Callback after each instruction executed
     ///
@@ -356,12 +1000,22 @@ impl Tsffs {
     ) -> Result<()> {
         let processor_number = get_processor_number(cpu)?;
 
+        if self.coverage_enabled && self.sanitizer.enabled() {
+            self.track_allocator_routines(processor_number)?;
+        }
+
         if self.cmplog && self.cmplog_enabled {
+            if !self.cmp_routines.is_empty() {
+                self.log_cmp_routine_if_entered(processor_number)?;
+            }
+
             if let Some(arch) = self.processors.get_mut(&processor_number) {
                 match arch.trace_cmp(handle) {
                     Ok(r) => {
                         if let Some((pc, types, cmp)) = r.cmp {
-                            self.log_cmp(pc, types.clone(), cmp.clone())?;
+                            if self.address_allowed(processor_number, pc)? {
+                                self.log_cmp(pc, types.clone(), cmp.clone())?;
+                            }
                         }
                     }
                     Err(_) => {
@@ -378,7 +1032,47 @@ impl Tsffs {
                 || self.save_interesting_execution_traces
                 || self.save_solution_execution_traces)
         {
-            if let Some(arch) = self.processors.get_mut(&processor_number) {
+            if self.execution_trace_format == ExecutionTraceFormat::Binary {
+                if self.execution_trace_writer.is_none() {
+                    self.open_execution_trace()?;
+                }
+
+                if let (Some(arch), Some(writer)) = (
+                    self.processors.get_mut(&processor_number),
+                    self.execution_trace_writer.as_mut(),
+                ) {
+                    let instruction = if self.execution_trace_pc_only {
+                        None
+                    } else {
+                        let instruction_bytes =
+                            arch.cpu_instruction_query().get_instruction_bytes(handle)?;
+                        let instruction_bytes = unsafe {
+                            from_raw_parts(instruction_bytes.data, instruction_bytes.size)
+                        };
+
+                        // Instructions are only disassembled the first time they are
+                        // executed in each trace
+                        Some(match writer.instruction(instruction_bytes) {
+                            Some(id) => id,
+                            None => {
+                                let disassembly_string = arch
+                                    .disassembler()
+                                    .disassemble_to_string(instruction_bytes)
+                                    .unwrap_or_else(|_| "(unknown)".to_string());
+                                writer.define_instruction(instruction_bytes, &disassembly_string)?
+                            }
+                        })
+                    };
+
+                    writer.write_entry(
+                        processor_number,
+                        arch.processor_info_v2().get_program_counter()?,
+                        instruction,
+                    )?;
+                }
+            } else if self.execution_trace_format == ExecutionTraceFormat::Tenet {
+                self.trace_tenet_instruction(processor_number)?;
+            } else if let Some(arch) = self.processors.get_mut(&processor_number) {
                 self.execution_trace
                     .0
                     .entry(processor_number)
@@ -415,4 +1109,234 @@ impl Tsffs {
 
         Ok(())
     }
+
+    #[ffi(arg(rest), arg(self))]
+    /// Callback after each memory read, registered when saving Tenet execution traces. The
+    /// bytes read are only known after the read.
+    ///
+    /// # Arguments
+    ///
+    /// * `obj`
+    /// * `cpu` - The processor the read was made by
+    /// * `handle` - An opaque handle to the memory access
+    pub fn on_read_after(
+        &mut self,
+        _obj: *mut ConfObject,
+        cpu: *mut ConfObject,
+        handle: *mut memory_handle_t,
+    ) -> Result<()> {
+        if self.coverage_enabled {
+            if let Err(e) = self.record_tenet_memory_access(cpu, handle, false) {
+                debug!(
+                    self.as_conf_object(),
+                    "Failed to record read in Tenet trace: {e}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[ffi(arg(rest), arg(self))]
+    /// Callback before each memory write, registered when saving Tenet execution traces
+    ///
+    /// # Arguments
+    ///
+    /// * `obj`
+    /// * `cpu` - The processor the write is being made by
+    /// * `handle` - An opaque handle to the memory access
+    pub fn on_write_before(
+        &mut self,
+        _obj: *mut ConfObject,
+        cpu: *mut ConfObject,
+        handle: *mut memory_handle_t,
+    ) -> Result<()> {
+        if self.coverage_enabled {
+            if let Err(e) = self.record_tenet_memory_access(cpu, handle, true) {
+                debug!(
+                    self.as_conf_object(),
+                    "Failed to record write in Tenet trace: {e}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shadow_call_stack_restores_caller_context() {
+        let mut stack = ShadowCallStack::default();
+
+        stack.call(0x1000);
+        let callee = stack.context;
+        stack.call(0x2000);
+
+        assert_ne!(stack.context, callee);
+
+        stack.ret();
+        assert_eq!(stack.context, callee);
+
+        stack.ret();
+        assert_eq!(stack.context, 0);
+    }
+
+    #[test]
+    fn test_shadow_call_stack_unmatched_return_resets_context() {
+        let mut stack = ShadowCallStack::default();
+
+        stack.ret();
+
+        assert_eq!(stack.context, 0);
+    }
+
+    #[test]
+    fn test_shadow_call_stack_keeps_deepest_context() {
+        let mut stack = ShadowCallStack::default();
+
+        (0..ShadowCallStack::MAX_DEPTH).for_each(|i| stack.call(i as u64));
+        let deepest = stack.context;
+
+        stack.call(0x1000);
+        assert_eq!(stack.context, deepest);
+
+        stack.ret();
+        assert_eq!(stack.context, deepest);
+    }
+
+    #[test]
+    fn test_return_to_caller() {
+        let mut stack = ReturnAddressStack::default();
+
+        stack.call(0x1000);
+
+        assert_eq!(stack.ret(0x1000), None);
+        assert!(stack.frames.is_empty());
+    }
+
+    #[test]
+    fn test_return_to_wrong_address() {
+        let mut stack = ReturnAddressStack::default();
+
+        stack.call(0x1000);
+
+        assert_eq!(stack.ret(0x2000), Some(0x1000));
+        assert!(stack.frames.is_empty());
+    }
+
+    #[test]
+    fn test_return_to_outer_caller_unwinds() {
+        let mut stack = ReturnAddressStack::default();
+
+        stack.call(0x1000);
+        stack.call(0x2000);
+        stack.call(0x3000);
+
+        assert_eq!(stack.ret(0x1000), None);
+        assert!(stack.frames.is_empty());
+    }
+
+    #[test]
+    fn test_return_without_call_is_not_checked() {
+        let mut stack = ReturnAddressStack::default();
+
+        assert_eq!(stack.ret(0x1000), None);
+    }
+
+    #[test]
+    fn test_return_from_interrupt_is_not_checked() {
+        let mut stack = ReturnAddressStack::default();
+
+        stack.call(0x1000);
+        stack.interrupt();
+
+        assert_eq!(stack.ret(0x4321), None);
+        assert_eq!(stack.ret(0x1000), None);
+        assert!(stack.frames.is_empty());
+    }
+
+    #[test]
+    fn test_return_never_unwinds_past_interrupt() {
+        let mut stack = ReturnAddressStack::default();
+
+        stack.call(0x1000);
+        stack.interrupt();
+        stack.call(0x2000);
+
+        assert_eq!(stack.ret(0x1000), Some(0x2000));
+        assert_eq!(
+            stack.frames,
+            vec![ReturnFrame::Call(0x1000), ReturnFrame::Interrupt]
+        );
+    }
+
+    #[test]
+    fn test_address_space_switch_resets_stack() {
+        let mut stack = ReturnAddressStack::default();
+
+        stack.switch_address_space(0x1000);
+        stack.call(0x1000);
+        stack.switch_address_space(0x1000);
+        assert_eq!(stack.frames, vec![ReturnFrame::Call(0x1000)]);
+
+        stack.switch_address_space(0x2000);
+        assert_eq!(stack.ret(0x3000), None);
+    }
+
+    #[test]
+    fn test_returns_deeper_than_maximum_depth_are_not_checked() {
+        let mut stack = ReturnAddressStack::default();
+
+        (0..=ReturnAddressStack::MAX_DEPTH).for_each(|_| stack.call(0x1000));
+
+        assert_eq!(stack.ret(0x2000), None);
+        assert_eq!(stack.ret(0x2000), Some(0x1000));
+    }
+
+    #[test]
+    fn test_cmp_operands_of_integers() -> Result<()> {
+        let (shape, operands) = cmp_operands(&CmpValues::U8((1, 2)))?;
+        assert_eq!(shape, 0);
+        assert_eq!((operands.v0(), operands.v1()), (1, 2));
+
+        let (shape, operands) = cmp_operands(&CmpValues::U16((0x1234, 0x5678)))?;
+        assert_eq!(shape, 1);
+        assert_eq!((operands.v0(), operands.v1()), (0x1234, 0x5678));
+
+        let (shape, _) = cmp_operands(&CmpValues::U32((1, 2)))?;
+        assert_eq!(shape, 3);
+
+        let (shape, operands) = cmp_operands(&CmpValues::U64((u64::MAX, 0)))?;
+        assert_eq!(shape, 7);
+        assert_eq!((operands.v0(), operands.v1()), (u64::MAX, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cmp_operands_of_bytes() -> Result<()> {
+        let (shape, operands) = cmp_operands(&CmpValues::Bytes((
+            b"fuzzing!".to_vec(),
+            b"AAAAAAAA".to_vec(),
+        )))?;
+        assert_eq!(shape, 7);
+        assert_eq!(operands.v0(), u64::from_le_bytes(*b"fuzzing!"));
+        assert_eq!(operands.v1(), u64::from_le_bytes(*b"AAAAAAAA"));
+
+        let (shape, _) = cmp_operands(&CmpValues::Bytes((vec![0; 16], vec![1; 16])))?;
+        assert_eq!(shape, 15);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cmp_operands_of_unsupported_bytes() {
+        assert!(cmp_operands(&CmpValues::Bytes((Vec::new(), Vec::new()))).is_err());
+        assert!(cmp_operands(&CmpValues::Bytes((vec![0; 4], vec![0; 5]))).is_err());
+        assert!(cmp_operands(&CmpValues::Bytes((vec![0; 17], vec![0; 17]))).is_err());
+    }
 }
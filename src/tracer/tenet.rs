@@ -0,0 +1,159 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Execution traces in the text format of the Tenet trace explorer
+//!
+//! Each line of a Tenet trace is a comma separated list of `name=value` fields. The first line
+//! has the value of every traced register. Each line after it has the registers whose values
+//! were changed by the previous instruction, the program counter of the next instruction
+//! (even if it did not change), and the memory read (`mr`) and written (`mw`) by the previous
+//! instruction as an address and the hex encoded bytes accessed.
+
+use crate::{arch::ArchitectureOperations, tracer::HashWriter, Tsffs};
+use anyhow::{anyhow, Result};
+use simics::api::{
+    get_interface, get_processor_number, sys::memory_handle_t, ConfObject, CpuMemoryQueryInterface,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    slice::from_raw_parts,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A memory access made by an instruction
+struct MemoryAccess {
+    /// Whether the access is a write
+    write: bool,
+    /// The logical address of the access
+    address: u64,
+    /// The bytes read or written
+    bytes: Vec<u8>,
+}
+
+/// A Tenet trace of the start processor being written to a file
+pub(crate) struct TenetTrace {
+    writer: HashWriter<BufWriter<File>>,
+    /// The value of each register as of the last line written
+    registers: HashMap<&'static str, u64>,
+    /// The memory accesses made since the last line written
+    accesses: Vec<MemoryAccess>,
+}
+
+impl TenetTrace {
+    pub fn new(file: File) -> Self {
+        Self {
+            writer: HashWriter::new(BufWriter::new(file)),
+            registers: HashMap::new(),
+            accesses: Vec::new(),
+        }
+    }
+
+    /// Record a memory access made by the instruction currently executing
+    fn record_access(&mut self, write: bool, address: u64, bytes: Vec<u8>) {
+        self.accesses.push(MemoryAccess {
+            write,
+            address,
+            bytes,
+        });
+    }
+
+    /// Write the line for an instruction about to execute, given the registers returned by
+    /// `get_tenet_registers`. The program counter is always written, the other registers only
+    /// if they changed.
+    fn write_instruction(&mut self, registers: &[(&'static str, u64)]) -> Result<()> {
+        let ((pc_name, pc), registers) = registers
+            .split_last()
+            .ok_or_else(|| anyhow!("No program counter"))?;
+
+        let mut fields = registers
+            .iter()
+            .filter(|(name, value)| self.registers.insert(name, *value) != Some(*value))
+            .map(|(name, value)| format!("{name}={value:#x}"))
+            .collect::<Vec<_>>();
+
+        fields.push(format!("{pc_name}={pc:#x}"));
+
+        fields.extend(self.accesses.drain(..).map(|access| {
+            format!(
+                "{}={:#x}:{}",
+                if access.write { "mw" } else { "mr" },
+                access.address,
+                access
+                    .bytes
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>()
+            )
+        }));
+
+        writeln!(self.writer, "{}", fields.join(","))?;
+
+        Ok(())
+    }
+
+    /// Flush the trace and return the hash of its contents. Accesses made by the last
+    /// instruction are not written, because there is no line after it to write them on.
+    pub fn finish(self) -> Result<u64> {
+        self.writer.finish()
+    }
+}
+
+impl Tsffs {
+    /// Whether the processor with number `processor_number` is traced in Tenet traces, which
+    /// only support a single processor
+    fn tenet_traced(&self, processor_number: i32) -> bool {
+        self.start_processor_number.get() == Some(&processor_number)
+    }
+
+    /// Write the line of the Tenet trace for the instruction the processor with number
+    /// `processor_number` is about to execute
+    pub fn trace_tenet_instruction(&mut self, processor_number: i32) -> Result<()> {
+        if !self.tenet_traced(processor_number) {
+            return Ok(());
+        }
+
+        if self.tenet_trace.is_none() {
+            self.open_execution_trace()?;
+        }
+
+        let registers = self
+            .processors
+            .get_mut(&processor_number)
+            .ok_or_else(|| anyhow!("No processor {processor_number}"))?
+            .get_tenet_registers()?;
+
+        self.tenet_trace
+            .as_mut()
+            .ok_or_else(|| anyhow!("No Tenet trace open"))?
+            .write_instruction(&registers)
+    }
+
+    /// Record a memory access made by the instruction `cpu` is executing in the Tenet trace.
+    /// The bytes of writes are known before the access, and the bytes of reads are known
+    /// after the access.
+    pub fn record_tenet_memory_access(
+        &mut self,
+        cpu: *mut ConfObject,
+        handle: *mut memory_handle_t,
+        write: bool,
+    ) -> Result<()> {
+        let processor_number = get_processor_number(cpu)?;
+
+        if !self.tenet_traced(processor_number) || self.tenet_trace.is_none() {
+            return Ok(());
+        }
+
+        let mut memory_query: CpuMemoryQueryInterface = get_interface(cpu)?;
+        let address = memory_query.logical_address(handle)?;
+        let access_bytes = memory_query.get_bytes(handle)?;
+        let bytes = unsafe { from_raw_parts(access_bytes.data, access_bytes.size) }.to_vec();
+
+        if let Some(tenet_trace) = self.tenet_trace.as_mut() {
+            tenet_trace.record_access(write, address, bytes);
+        }
+
+        Ok(())
+    }
+}
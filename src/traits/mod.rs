@@ -1,7 +1,7 @@
 // Copyright (C) 2024 Intel Corporation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::tracer::{CmpExpr, CmpType};
+use crate::tracer::{BranchKind, CmpExpr, CmpType};
 use anyhow::Result;
 
 /// Trait for disassemblers of various architectures to implement to permit branch
@@ -15,4 +15,15 @@ pub trait TracerDisassembler {
     fn last_was_cmp(&self) -> bool;
     fn cmp(&self) -> Vec<CmpExpr>;
     fn cmp_type(&self) -> Vec<CmpType>;
+
+    /// The kind of branch the last disassembled instruction was, if it was a branch
+    fn last_branch_kind(&self) -> BranchKind {
+        if self.last_was_call() {
+            BranchKind::Call
+        } else if self.last_was_ret() {
+            BranchKind::Return
+        } else {
+            BranchKind::Jump
+        }
+    }
 }
@@ -0,0 +1,640 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compact binary execution traces
+//!
+//! TSFFS writes execution traces in this format when its `execution_trace_format` attribute
+//! is set to "binary". A trace is streamed to its file while the target executes, so it is
+//! never held in memory, and it is a small fraction of the size of the equivalent JSON trace.
+//!
+//! # Format
+//!
+//! A trace starts with the 8 byte magic `TSFFSTRC`, a one byte version (currently 1), and a
+//! one byte set of flags, of which bit 0 is set if the trace records only program counters.
+//! The rest of the trace is a sequence of records, each starting with a one byte tag.
+//! Integers are unsigned LEB128, and signed integers are zigzag encoded before being written.
+//!
+//! | Tag | Record      | Contents                                                          |
+//! |-----|-------------|-------------------------------------------------------------------|
+//! | 1   | Processor   | The signed number of the processor which executed the entries after it |
+//! | 2   | Instruction | The length and bytes of an instruction, then the length and bytes of its UTF-8 disassembly |
+//! | 3   | Entry       | The signed difference from the previous program counter of the same processor, then the instruction id plus one, or zero for no instruction |
+//!
+//! Each processor has its own stream of entries, with a previous program counter starting at
+//! zero, so interleaving processors does not disturb the delta encoding. Instruction records
+//! make up a dictionary: the first has id 0, the second id 1, and so on. Each distinct
+//! instruction is defined once, before the first entry which refers to it.
+//!
+//! # Reading Traces
+//!
+//! ```no_run
+//! use std::{fs::File, io::BufReader};
+//! use tsffs_trace::TraceReader;
+//!
+//! let reader = TraceReader::new(BufReader::new(File::open("trace.trace")?))?;
+//!
+//! for entry in reader {
+//!     let entry = entry?;
+//!     println!("{}: {:#x}", entry.processor, entry.pc);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind, Read, Write},
+    sync::Arc,
+};
+
+/// The magic bytes every trace starts with
+pub const MAGIC: &[u8; 8] = b"TSFFSTRC";
+/// The version of the format written by this crate
+pub const VERSION: u8 = 1;
+
+/// Set in the header flags if the trace records only program counters
+const FLAG_PC_ONLY: u8 = 1 << 0;
+
+const TAG_PROCESSOR: u8 = 1;
+const TAG_INSTRUCTION: u8 = 2;
+const TAG_ENTRY: u8 = 3;
+
+/// The longest instruction or disassembly a reader accepts, which keeps a corrupt length
+/// from causing a huge allocation
+const MAX_FIELD_LEN: u64 = 1 << 16;
+
+fn invalid_data<S>(message: S) -> io::Error
+where
+    S: AsRef<str>,
+{
+    io::Error::new(ErrorKind::InvalidData, message.as_ref())
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn write_varint<W>(writer: &mut W, mut value: u64) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+
+    writer.write_all(&buf[..len])
+}
+
+fn read_u8<R>(reader: &mut R) -> io::Result<u8>
+where
+    R: Read,
+{
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_varint<R>(reader: &mut R) -> io::Result<u64>
+where
+    R: Read,
+{
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(reader)?;
+        value |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(invalid_data("Integer is longer than 64 bits"))
+}
+
+fn read_field<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: Read,
+{
+    let len = read_varint(reader)?;
+
+    if len > MAX_FIELD_LEN {
+        return Err(invalid_data(format!(
+            "Field length {len} is longer than the maximum of {MAX_FIELD_LEN}"
+        )));
+    }
+
+    let mut field = vec![0u8; len as usize];
+    reader.read_exact(&mut field)?;
+    Ok(field)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// An instruction in the dictionary of a trace
+pub struct Instruction {
+    /// The bytes of the instruction
+    pub bytes: Vec<u8>,
+    /// The disassembly of the instruction, or "(unknown)" if it could not be disassembled
+    pub disassembly: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// One executed instruction in a trace
+pub struct TraceEntry {
+    /// The number of the processor which executed the instruction
+    pub processor: i32,
+    /// The program counter of the instruction
+    pub pc: u64,
+    /// The instruction, unless the trace records only program counters
+    pub instruction: Option<Arc<Instruction>>,
+}
+
+/// Entries serialize in the shape of an entry of a JSON execution trace, which does not
+/// include the processor because entries are grouped by processor
+impl Serialize for TraceEntry {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut entry = serializer.serialize_struct("ExecutionTraceEntry", 3)?;
+        entry.serialize_field("pc", &self.pc)?;
+        entry.serialize_field(
+            "insn",
+            &self.instruction.as_ref().map(|i| i.disassembly.as_str()),
+        )?;
+        entry.serialize_field(
+            "insn_bytes",
+            &self.instruction.as_ref().map(|i| i.bytes.as_slice()),
+        )?;
+        entry.end()
+    }
+}
+
+/// Writes a trace to a stream one entry at a time
+pub struct TraceWriter<W>
+where
+    W: Write,
+{
+    writer: W,
+    /// The processor of the most recent entry
+    processor: Option<i32>,
+    /// The program counter of the most recent entry of each processor
+    previous_pcs: HashMap<i32, u64>,
+    /// The id of each instruction defined so far, by its bytes
+    instructions: HashMap<Vec<u8>, u64>,
+}
+
+impl<W> TraceWriter<W>
+where
+    W: Write,
+{
+    /// Start a trace by writing its header to `writer`
+    pub fn new(mut writer: W, pc_only: bool) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION, if pc_only { FLAG_PC_ONLY } else { 0 }])?;
+
+        Ok(Self {
+            writer,
+            processor: None,
+            previous_pcs: HashMap::new(),
+            instructions: HashMap::new(),
+        })
+    }
+
+    /// The id of the instruction with `bytes`, if it has been defined. Checking this first
+    /// lets the caller skip disassembling instructions which are already defined.
+    pub fn instruction(&self, bytes: &[u8]) -> Option<u64> {
+        self.instructions.get(bytes).copied()
+    }
+
+    /// Define the instruction with `bytes` and `disassembly` if it is not defined yet, and
+    /// return its id
+    pub fn define_instruction(&mut self, bytes: &[u8], disassembly: &str) -> io::Result<u64> {
+        if let Some(id) = self.instruction(bytes) {
+            return Ok(id);
+        }
+
+        let id = self.instructions.len() as u64;
+
+        self.writer.write_all(&[TAG_INSTRUCTION])?;
+        write_varint(&mut self.writer, bytes.len() as u64)?;
+        self.writer.write_all(bytes)?;
+        write_varint(&mut self.writer, disassembly.len() as u64)?;
+        self.writer.write_all(disassembly.as_bytes())?;
+
+        self.instructions.insert(bytes.to_vec(), id);
+
+        Ok(id)
+    }
+
+    /// Write an entry for the instruction with id `instruction` executed at `pc` by
+    /// `processor`
+    pub fn write_entry(
+        &mut self,
+        processor: i32,
+        pc: u64,
+        instruction: Option<u64>,
+    ) -> io::Result<()> {
+        if let Some(id) = instruction {
+            if id >= self.instructions.len() as u64 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Instruction {id} is not defined"),
+                ));
+            }
+        }
+
+        if self.processor != Some(processor) {
+            self.writer.write_all(&[TAG_PROCESSOR])?;
+            write_varint(&mut self.writer, zigzag(processor as i64))?;
+            self.processor = Some(processor);
+        }
+
+        let previous_pc = self.previous_pcs.insert(processor, pc).unwrap_or(0);
+
+        self.writer.write_all(&[TAG_ENTRY])?;
+        write_varint(
+            &mut self.writer,
+            zigzag(pc.wrapping_sub(previous_pc) as i64),
+        )?;
+        write_varint(&mut self.writer, instruction.map_or(0, |id| id + 1))
+    }
+
+    /// Flush the trace and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the entries of a trace from a stream
+pub struct TraceReader<R>
+where
+    R: Read,
+{
+    reader: R,
+    pc_only: bool,
+    /// The processor of the entries being read
+    processor: Option<i32>,
+    /// The program counter of the most recent entry of each processor
+    previous_pcs: HashMap<i32, u64>,
+    /// The instructions defined so far, by id
+    instructions: Vec<Arc<Instruction>>,
+    /// Set after an error, after which no more entries are read
+    failed: bool,
+}
+
+impl<R> TraceReader<R>
+where
+    R: Read,
+{
+    /// Start reading a trace by reading and checking its header from `reader`
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a TSFFS binary execution trace"));
+        }
+
+        let version = read_u8(&mut reader)?;
+
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported trace version {version}, expected {VERSION}"
+            )));
+        }
+
+        let flags = read_u8(&mut reader)?;
+
+        Ok(Self {
+            reader,
+            pc_only: flags & FLAG_PC_ONLY != 0,
+            processor: None,
+            previous_pcs: HashMap::new(),
+            instructions: Vec::new(),
+            failed: false,
+        })
+    }
+
+    /// Whether the trace records only program counters
+    pub fn pc_only(&self) -> bool {
+        self.pc_only
+    }
+
+    /// Read the tag of the next record, or `None` at the end of the trace
+    fn read_tag(&mut self) -> io::Result<Option<u8>> {
+        let mut tag = [0u8];
+
+        loop {
+            match self.reader.read(&mut tag) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(tag[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read the next entry, or `None` at the end of the trace
+    pub fn read_entry(&mut self) -> io::Result<Option<TraceEntry>> {
+        while let Some(tag) = self.read_tag()? {
+            match tag {
+                TAG_PROCESSOR => {
+                    let processor = unzigzag(read_varint(&mut self.reader)?);
+                    self.processor = Some(
+                        i32::try_from(processor)
+                            .map_err(|_| invalid_data(format!("Invalid processor {processor}")))?,
+                    );
+                }
+                TAG_INSTRUCTION => {
+                    let bytes = read_field(&mut self.reader)?;
+                    let disassembly = String::from_utf8(read_field(&mut self.reader)?)
+                        .map_err(|e| invalid_data(format!("Invalid disassembly: {e}")))?;
+                    self.instructions
+                        .push(Arc::new(Instruction { bytes, disassembly }));
+                }
+                TAG_ENTRY => {
+                    let processor = self
+                        .processor
+                        .ok_or_else(|| invalid_data("Entry before any processor"))?;
+                    let delta = unzigzag(read_varint(&mut self.reader)?);
+                    let instruction = match read_varint(&mut self.reader)? {
+                        0 => None,
+                        id => Some(
+                            self.instructions
+                                .get((id - 1) as usize)
+                                .cloned()
+                                .ok_or_else(|| {
+                                    invalid_data(format!("Instruction {} is not defined", id - 1))
+                                })?,
+                        ),
+                    };
+
+                    let previous_pc = self.previous_pcs.entry(processor).or_default();
+                    *previous_pc = previous_pc.wrapping_add(delta as u64);
+
+                    return Ok(Some(TraceEntry {
+                        processor,
+                        pc: *previous_pc,
+                        instruction,
+                    }));
+                }
+                _ => return Err(invalid_data(format!("Unknown record tag {tag}"))),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<R> Iterator for TraceReader<R>
+where
+    R: Read,
+{
+    type Item = io::Result<TraceEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let entry = self.read_entry().transpose();
+
+        if let Some(Err(_)) = entry {
+            self.failed = true;
+        }
+
+        entry
+    }
+}
+
+/// Convert the binary trace read from `reader` to the JSON trace TSFFS writes when its
+/// `execution_trace_format` attribute is "json": an object mapping each processor number to
+/// the list of its entries. The whole trace is read into memory, because entries are grouped
+/// by processor.
+pub fn convert_to_json<R, W>(reader: R, writer: W) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut trace = BTreeMap::<i32, Vec<TraceEntry>>::new();
+
+    for entry in TraceReader::new(reader)? {
+        let entry = entry?;
+        trace.entry(entry.processor).or_default().push(entry);
+    }
+
+    serde_json::to_writer(writer, &trace).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// An entry to write: the processor, program counter, and instruction bytes and
+    /// disassembly
+    type Entry<'a> = (i32, u64, Option<(&'a [u8], &'a str)>);
+
+    /// Write a trace with the given entries, defining each instruction the first time it is
+    /// used, and return the trace's bytes
+    fn write_trace(pc_only: bool, entries: &[Entry]) -> Vec<u8> {
+        let mut writer = TraceWriter::new(Vec::new(), pc_only).unwrap();
+
+        for (processor, pc, instruction) in entries {
+            let id = instruction
+                .map(|(bytes, disassembly)| writer.define_instruction(bytes, disassembly))
+                .transpose()
+                .unwrap();
+            writer.write_entry(*processor, *pc, id).unwrap();
+        }
+
+        writer.finish().unwrap()
+    }
+
+    fn read_trace(trace: &[u8]) -> io::Result<Vec<TraceEntry>> {
+        TraceReader::new(trace)?.collect()
+    }
+
+    #[test]
+    fn test_round_trip_interleaved_processors() {
+        let nop: &[u8] = &[0x90];
+        let ret: &[u8] = &[0xc3];
+        let entries = [
+            (0, 0x1000, Some((nop, "nop"))),
+            (1, 0xffff_ffff_8000_0000, Some((ret, "ret"))),
+            (0, 0x1001, Some((nop, "nop"))),
+            // Negative deltas on both processors
+            (1, 0x10, Some((nop, "nop"))),
+            (0, 0x0, Some((ret, "ret"))),
+            (-1, u64::MAX, None),
+            (-1, 0, Some((nop, "nop"))),
+        ];
+
+        let trace = write_trace(false, &entries);
+        let reader = TraceReader::new(trace.as_slice()).unwrap();
+        assert!(!reader.pc_only());
+
+        let read = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(read.len(), entries.len());
+
+        for (entry, (processor, pc, instruction)) in read.iter().zip(entries.iter()) {
+            assert_eq!(entry.processor, *processor);
+            assert_eq!(entry.pc, *pc);
+            assert_eq!(
+                entry
+                    .instruction
+                    .as_ref()
+                    .map(|i| (i.bytes.as_slice(), i.disassembly.as_str())),
+                *instruction
+            );
+        }
+
+        // Repeated instructions are defined once and shared by every entry using them
+        assert!(Arc::ptr_eq(
+            read[0].instruction.as_ref().unwrap(),
+            read[2].instruction.as_ref().unwrap()
+        ));
+        assert_eq!(
+            trace
+                .windows(ret.len() + 2)
+                .filter(|w| w == &[TAG_INSTRUCTION, 1, 0xc3])
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_round_trip_pc_only() {
+        let entries = [(0, 0x4000, None), (0, 0x3ff0, None), (2, 0x4000, None)];
+
+        let trace = write_trace(true, &entries);
+        let reader = TraceReader::new(trace.as_slice()).unwrap();
+        assert!(reader.pc_only());
+
+        let read = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            read.iter()
+                .map(|e| (e.processor, e.pc, e.instruction.is_none()))
+                .collect::<Vec<_>>(),
+            vec![(0, 0x4000, true), (0, 0x3ff0, true), (2, 0x4000, true)]
+        );
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 0x7f, 0x80, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value).unwrap();
+            assert_eq!(read_varint(&mut buf.as_slice()).unwrap(), value);
+        }
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0]);
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, u64::MAX).unwrap();
+        assert_eq!(buf.len(), 10);
+
+        for value in [0, 1, -1, i64::MAX, i64::MIN] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, zigzag(value)).unwrap();
+            assert_eq!(unzigzag(read_varint(&mut buf.as_slice()).unwrap()), value);
+        }
+
+        assert_eq!(zigzag(i64::MIN), u64::MAX);
+        assert!(read_varint(&mut [0xffu8; 10].as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_reject_bad_magic() {
+        let mut trace = write_trace(false, &[]);
+        trace[0] = b'X';
+
+        assert_eq!(
+            TraceReader::new(trace.as_slice()).err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_reject_unknown_tag() {
+        let mut trace = write_trace(false, &[(0, 0x1000, None)]);
+        trace.push(0xff);
+
+        let mut reader = TraceReader::new(trace.as_slice()).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().pc, 0x1000);
+        assert_eq!(
+            reader.next().unwrap().err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidData)
+        );
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_reject_undefined_instruction() {
+        let mut trace = write_trace(false, &[]);
+        trace.extend_from_slice(&[TAG_PROCESSOR, 0, TAG_ENTRY, 0, 1]);
+
+        assert_eq!(
+            read_trace(&trace).err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidData)
+        );
+
+        let mut writer = TraceWriter::new(Vec::new(), false).unwrap();
+        assert_eq!(
+            writer.write_entry(0, 0, Some(0)).err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn test_convert_to_json() {
+        let nop: &[u8] = &[0x90];
+        let trace = write_trace(
+            false,
+            &[
+                (1, 0x2000, Some((nop, "nop"))),
+                (0, 0x1000, None),
+                (1, 0x2001, Some((nop, "nop"))),
+            ],
+        );
+
+        let mut json = Vec::new();
+        convert_to_json(trace.as_slice(), &mut json).unwrap();
+
+        // The JSON execution trace maps each processor number to its entries, each with the
+        // program counter, disassembly, and bytes of the instruction
+        assert_eq!(
+            serde_json::from_slice::<Value>(&json).unwrap(),
+            json!({
+                "0": [
+                    { "pc": 0x1000, "insn": null, "insn_bytes": null },
+                ],
+                "1": [
+                    { "pc": 0x2000, "insn": "nop", "insn_bytes": [0x90] },
+                    { "pc": 0x2001, "insn": "nop", "insn_bytes": [0x90] },
+                ],
+            })
+        );
+    }
+}
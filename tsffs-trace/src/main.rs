@@ -0,0 +1,49 @@
+// Copyright (C) 2024 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Convert TSFFS binary execution traces to JSON execution traces
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::{
+    fs::File,
+    io::{stdout, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+use tsffs_trace::convert_to_json;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+/// Convert a TSFFS binary execution trace to the JSON execution trace format
+struct Args {
+    /// The binary execution trace to convert
+    input: PathBuf,
+    #[arg(short, long)]
+    /// The file to write the JSON execution trace to. If not provided, it is written to
+    /// standard output.
+    output: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let input = BufReader::new(
+        File::open(&args.input)
+            .with_context(|| format!("Failed to open {}", args.input.display()))?,
+    );
+
+    let mut output: Box<dyn Write> = if let Some(output) = &args.output {
+        Box::new(BufWriter::new(File::create(output).with_context(|| {
+            format!("Failed to create {}", output.display())
+        })?))
+    } else {
+        Box::new(BufWriter::new(stdout().lock()))
+    };
+
+    convert_to_json(input, &mut output)
+        .with_context(|| format!("Failed to convert {}", args.input.display()))?;
+
+    output.flush()?;
+
+    Ok(())
+}